@@ -0,0 +1,271 @@
+//! The audit log of `backtor serve`: service, stream and session events
+//! written as one JSON object per line, each with its `event` name and a
+//! `time` in Unix seconds.
+//!
+//! Lines are appended to a file created with mode `0600`, or handed to the
+//! local syslog daemon with facility `authpriv` when the target is `syslog`.
+//! Until [`init`] is called, [`record`] does nothing.
+
+use log::error;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where audit events end up.
+enum AuditSink {
+    /// Append one JSON object per line to a file.
+    File(Mutex<File>),
+    /// Hand each JSON line to the local syslog daemon (facility `authpriv`).
+    #[cfg(unix)]
+    Syslog,
+}
+
+static AUDIT_SINK: OnceLock<AuditSink> = OnceLock::new();
+
+/// A single audit record.
+///
+/// Tor does not tell an onion service who is on the other end of a rendezvous
+/// circuit, so the per-process `circuit` number is the closest thing to a
+/// client identity we can record: every stream that shares a circuit number
+/// was opened by the same client. This holds with restricted discovery too:
+/// the client keys only decide who can read the service's descriptor, and
+/// nothing in an introduction (or in arti's `RendRequest`) names the key that
+/// got the client there.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum AuditEvent<'a> {
+    ServiceStart {
+        service: &'a str,
+        nickname: &'a str,
+    },
+    ServiceReachable {
+        service: &'a str,
+    },
+    ServiceStop {
+        service: &'a str,
+    },
+    /// A session was ended from the control socket.
+    SessionKilled {
+        session: u64,
+    },
+    CircuitRejected {
+        service: &'a str,
+        reason: &'a str,
+    },
+    StreamAccepted {
+        service: &'a str,
+        circuit: u64,
+        port: u16,
+    },
+    StreamRejected {
+        service: &'a str,
+        circuit: u64,
+        port: Option<u16>,
+        reason: &'a str,
+    },
+    SessionStart {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        port: u16,
+        command: &'a str,
+    },
+    ForwardStart {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        port: u16,
+        target: &'a str,
+    },
+    /// The server started listening for a client's remote forward (`-R`).
+    ListenStart {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        port: u16,
+        address: &'a str,
+    },
+    /// A connection arrived on a remote-forward listener and was offered to
+    /// its client.
+    ListenAccept {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        peer: &'a str,
+    },
+    Transfer {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        port: u16,
+        op: &'a str,
+        path: &'a str,
+        bytes: u64,
+        duration_secs: f64,
+        error: Option<&'a str>,
+    },
+    SessionEnd {
+        service: &'a str,
+        circuit: u64,
+        session: u64,
+        duration_secs: f64,
+        bytes_in: u64,
+        bytes_out: u64,
+        exit_code: Option<u32>,
+        signal: Option<&'a str>,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: &'a AuditEvent<'a>,
+}
+
+/// Enable audit logging.
+///
+/// `target` is either a file path (opened in append mode and created with
+/// mode `0600` if missing) or the literal string `syslog`.
+pub(crate) fn init(target: &str) -> anyhow::Result<()> {
+    let sink = if target == "syslog" {
+        #[cfg(unix)]
+        {
+            // SAFETY: the ident is a static C string that outlives the process.
+            unsafe {
+                libc::openlog(c"backtor".as_ptr(), libc::LOG_PID, libc::LOG_AUTHPRIV);
+            }
+            AuditSink::Syslog
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("syslog audit logging is only supported on Unix");
+    } else {
+        AuditSink::File(Mutex::new(open_log_file(Path::new(target))?))
+    };
+
+    AUDIT_SINK
+        .set(sink)
+        .map_err(|_| anyhow::anyhow!("audit log already initialised"))
+}
+
+fn open_log_file(path: &Path) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Cannot open audit log {}: {e}", path.display()))
+}
+
+/// Record an audit event. A no-op unless [`init`] has been called.
+pub(crate) fn record(event: AuditEvent<'_>) {
+    let Some(sink) = AUDIT_SINK.get() else {
+        return;
+    };
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let line = match serde_json::to_string(&AuditRecord {
+        time,
+        event: &event,
+    }) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Failed to serialise audit event: {e}");
+            return;
+        }
+    };
+
+    match sink {
+        AuditSink::File(file) => {
+            let mut file = file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{line}") {
+                error!("Failed to write audit event: {e}");
+            }
+        }
+        #[cfg(unix)]
+        AuditSink::Syslog => {
+            let Ok(msg) = std::ffi::CString::new(line) else {
+                return;
+            };
+            // SAFETY: both the format string and the message are valid,
+            // NUL-terminated C strings.
+            unsafe {
+                libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), msg.as_ptr());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn to_json(event: &AuditEvent<'_>) -> Value {
+        serde_json::to_value(AuditRecord {
+            time: 1_700_000_000.5,
+            event,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn session_end_is_one_flat_object() {
+        let event = AuditEvent::SessionEnd {
+            service: "ops",
+            circuit: 3,
+            session: 7,
+            duration_secs: 12.5,
+            bytes_in: 100,
+            bytes_out: 2000,
+            exit_code: Some(0),
+            signal: None,
+        };
+        assert_eq!(
+            to_json(&event),
+            json!({
+                "time": 1_700_000_000.5,
+                "event": "session_end",
+                "service": "ops",
+                "circuit": 3,
+                "session": 7,
+                "duration_secs": 12.5,
+                "bytes_in": 100,
+                "bytes_out": 2000,
+                "exit_code": 0,
+                "signal": null,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_values_are_null() {
+        let event = AuditEvent::StreamRejected {
+            service: "ops",
+            circuit: 3,
+            port: None,
+            reason: "not a stream request",
+        };
+        assert_eq!(
+            to_json(&event),
+            json!({
+                "time": 1_700_000_000.5,
+                "event": "stream_rejected",
+                "service": "ops",
+                "circuit": 3,
+                "port": null,
+                "reason": "not a stream request",
+            })
+        );
+    }
+}
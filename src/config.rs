@@ -0,0 +1,478 @@
+//! Server configuration: the onion services one `backtor serve` process runs,
+//! either described by command-line flags (one service) or by the
+//! configuration files (any number of services sharing one Tor client; see
+//! [`crate::settings`] for how the files are found and layered).
+//!
+//! ```toml
+//! audit_log = "syslog"
+//! control_socket = "/run/backtor/control.sock"
+//! shutdown_grace = 30
+//! pty = { rows = 50, cols = 132 }
+//!
+//! [[service]]
+//! name = "ops"
+//! key = "<64 hex chars>"
+//! ports = [23, "2222:/usr/bin/fish --private"]
+//! user = "ops"
+//!
+//! [[service]]
+//! name = "web"
+//! no_shell = true
+//! forwards = ["80:127.0.0.1:8080"]
+//! authorized_clients = { alice = "descriptor:x25519:<base32 key>" }
+//! ```
+
+use anyhow::{Context, Error, anyhow, bail};
+use log::debug;
+use portable_pty::PtySize;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use tor_hscrypto::pk::HsClientDescEncKey;
+use tor_hsservice::HsNickname;
+use tor_hsservice::config::restricted_discovery::HsClientNickname;
+
+use crate::dos::{
+    DEFAULT_MAX_SESSIONS, DEFAULT_MAX_SESSIONS_PER_CIRCUIT, DEFAULT_POW_QUEUE_DEPTH, DosSettings,
+    RateLimit, WhenLimited,
+};
+use crate::egress::EgressRule;
+use crate::forward::ForwardRule;
+use crate::onion_server::{PortAction, PortMap, ServiceSpec, ShellConfig, ShellPort};
+use crate::settings::parse_list;
+use crate::sftp::SFTP_PORT;
+use crate::transfer::{TRANSFER_PORT, TransferPolicy};
+use crate::tunnel::{TUNNEL_PORT, TunnelPolicy};
+use crate::utils::{DEFAULT_SHELL_PORT, get_onion_address, keypair_from_sk};
+
+/// The name of a service that was not given one.
+pub(crate) const DEFAULT_SERVICE_NAME: &str = "shell";
+
+/// Everything `backtor serve` runs. Part of [`crate::settings::Settings`],
+/// which checks for unknown keys.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ServerConfig {
+    /// Audit log target, see `--audit-log`.
+    #[serde(default)]
+    pub(crate) audit_log: Option<String>,
+
+    /// Control socket path, see `--control-socket`.
+    #[serde(default)]
+    pub(crate) control_socket: Option<PathBuf>,
+
+    /// Seconds sessions get to end on shutdown, see `--shutdown-grace`.
+    #[serde(default)]
+    pub(crate) shutdown_grace: Option<u64>,
+
+    /// The terminal size shells start with.
+    #[serde(default)]
+    pub(crate) pty: PtyConfig,
+
+    /// Keep service keys in memory only, see `--ephemeral`.
+    #[serde(default)]
+    pub(crate) ephemeral: bool,
+
+    #[serde(default, rename = "service")]
+    pub(crate) services: Vec<ServiceConfig>,
+}
+
+impl ServerConfig {
+    /// Validates every service and turns it into a launchable spec.
+    pub(crate) fn into_specs(self) -> Result<Vec<ServiceSpec>, Error> {
+        let mut names = HashSet::new();
+        let pty_size = self.pty.size()?;
+        self.services
+            .into_iter()
+            .map(|service| {
+                let name = service.name().to_owned();
+                if !names.insert(name.clone()) {
+                    bail!("Service name {name:?} is used more than once");
+                }
+                service
+                    .into_spec(pty_size)
+                    .with_context(|| format!("in service {name:?}"))
+            })
+            .collect()
+    }
+}
+
+/// `pty = { rows = ..., cols = ... }`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct PtyConfig {
+    pub(crate) rows: u16,
+    pub(crate) cols: u16,
+}
+
+impl Default for PtyConfig {
+    fn default() -> Self {
+        let size = PtySize::default();
+        PtyConfig {
+            rows: size.rows,
+            cols: size.cols,
+        }
+    }
+}
+
+impl PtyConfig {
+    fn size(self) -> Result<PtySize, Error> {
+        if self.rows == 0 || self.cols == 0 {
+            bail!("pty rows and cols must be positive");
+        }
+        Ok(PtySize {
+            rows: self.rows,
+            cols: self.cols,
+            ..PtySize::default()
+        })
+    }
+}
+
+/// One onion service. The fields mirror the `serve` flags of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct ServiceConfig {
+    /// Distinguishes services in logs and names the Tor keystore entry of
+    /// services without a `key`.
+    pub(crate) name: Option<String>,
+    pub(crate) key: Option<String>,
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) ports: Vec<ShellPort>,
+    /// Run every shell of this service as this user (requires root).
+    pub(crate) user: Option<String>,
+    pub(crate) no_shell: bool,
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) forwards: Vec<ForwardRule>,
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) allow_egress: Vec<EgressRule>,
+    pub(crate) allow_remote_forwarding: bool,
+    pub(crate) transfer_roots: Vec<PathBuf>,
+    pub(crate) sftp: bool,
+    /// In minutes.
+    pub(crate) idle_timeout: Option<u64>,
+    /// In minutes.
+    pub(crate) max_session_time: Option<u64>,
+    /// Restricted discovery: when non-empty, only these clients (nickname
+    /// to `descriptor:x25519:...` key) can find the service at all.
+    pub(crate) authorized_clients: BTreeMap<String, String>,
+    pub(crate) no_pow: bool,
+    pub(crate) pow_queue_depth: Option<usize>,
+    /// `{ rate = ..., burst = ... }`.
+    pub(crate) intro_rate_limit: Option<RateLimit>,
+    /// 0 for no limit.
+    pub(crate) max_sessions: Option<usize>,
+    /// 0 for no limit.
+    pub(crate) max_sessions_per_circuit: Option<usize>,
+    /// `{ rate = ..., burst = ... }`.
+    pub(crate) stream_rate_limit: Option<RateLimit>,
+    pub(crate) when_limited: WhenLimited,
+}
+
+impl ServiceConfig {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME)
+    }
+
+    /// Validates the service and builds its port map; shells start with a
+    /// terminal of `pty_size`.
+    pub(crate) fn into_spec(self, pty_size: PtySize) -> Result<ServiceSpec, Error> {
+        let name = self.name().to_owned();
+        let secret_key: Option<[u8; 32]> = match &self.key {
+            Some(hex) => {
+                let bytes = hex::decode(hex).map_err(|e| anyhow!("Invalid hex key: {e}"))?;
+                let arr: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Key must be exactly 32 bytes (64 hex chars)"))?;
+                Some(arr)
+            }
+            None => None,
+        };
+
+        // Keyed services are named after their address, so a changed key
+        // never picks up the old one from the Tor keystore.
+        let nickname = match secret_key {
+            Some(sk) => format!(
+                "backtor-shell-{}",
+                get_onion_address(keypair_from_sk(sk).public().as_bytes())
+            )
+            .parse()
+            .map_err(|_| anyhow!("invalid service name {name:?}"))?,
+            None => keystore_nickname(&name)?,
+        };
+
+        if let Some(user) = &self.user {
+            check_user(user)?;
+        }
+        if self.no_shell && !self.ports.is_empty() {
+            bail!("no_shell conflicts with ports");
+        }
+        if self.sftp && self.transfer_roots.is_empty() {
+            bail!("sftp requires transfer_roots");
+        }
+        let idle_timeout = minutes("idle_timeout", self.idle_timeout)?;
+        let max_session_time = minutes("max_session_time", self.max_session_time)?;
+        let dos = DosSettings {
+            pow: !self.no_pow,
+            pow_queue_depth: match self.pow_queue_depth {
+                Some(0) => bail!("pow_queue_depth must be positive"),
+                Some(depth) => depth,
+                None => DEFAULT_POW_QUEUE_DEPTH,
+            },
+            intro_rate_limit: self
+                .intro_rate_limit
+                .map(RateLimit::check)
+                .transpose()
+                .map_err(|e| anyhow!("invalid intro_rate_limit: {e}"))?,
+            max_sessions: self.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS),
+            max_sessions_per_circuit: self
+                .max_sessions_per_circuit
+                .unwrap_or(DEFAULT_MAX_SESSIONS_PER_CIRCUIT),
+            stream_rate_limit: self
+                .stream_rate_limit
+                .map(RateLimit::check)
+                .transpose()
+                .map_err(|e| anyhow!("invalid stream_rate_limit: {e}"))?,
+            when_limited: self.when_limited,
+        };
+
+        let mut ports = PortMap::new();
+        let shell_ports = if self.ports.is_empty() && !self.no_shell {
+            vec![ShellPort {
+                port: DEFAULT_SHELL_PORT,
+                shell: ShellConfig::default(),
+            }]
+        } else {
+            self.ports
+        };
+        for ShellPort { port, mut shell } in shell_ports {
+            if ports.contains_key(&port) {
+                bail!("Onion port {port} is already in use");
+            }
+            shell.user = self.user.clone();
+            shell.pty_size = pty_size;
+            shell.idle_timeout = idle_timeout;
+            shell.max_session_time = max_session_time;
+            ports.insert(port, PortAction::Shell(shell));
+        }
+        for rule in self.forwards {
+            if ports.contains_key(&rule.onion_port) {
+                bail!("Onion port {} is already in use", rule.onion_port);
+            }
+            ports.insert(rule.onion_port, PortAction::Forward(rule.target));
+        }
+        if ports.is_empty() {
+            bail!("no_shell requires at least one forward");
+        }
+
+        for rule in &self.allow_egress {
+            debug!("Allowing egress to {rule}");
+        }
+        let policy = TunnelPolicy {
+            egress: self.allow_egress.into(),
+            allow_listen: self.allow_remote_forwarding,
+        };
+        if policy != TunnelPolicy::default() {
+            if ports.contains_key(&TUNNEL_PORT) {
+                bail!("Onion port {TUNNEL_PORT} is reserved for port forwarding");
+            }
+            ports.insert(TUNNEL_PORT, PortAction::Tunnel(policy));
+        }
+        if !self.transfer_roots.is_empty() {
+            if ports.contains_key(&TRANSFER_PORT) {
+                bail!("Onion port {TRANSFER_PORT} is reserved for file transfers");
+            }
+            let policy = TransferPolicy::new(&self.transfer_roots)?;
+            if self.sftp {
+                if ports.contains_key(&SFTP_PORT) {
+                    bail!("Onion port {SFTP_PORT} is reserved for SFTP");
+                }
+                ports.insert(SFTP_PORT, PortAction::Sftp(policy.clone()));
+            }
+            ports.insert(TRANSFER_PORT, PortAction::Transfer(policy));
+        }
+
+        let authorized_clients = self
+            .authorized_clients
+            .into_iter()
+            .map(|(client, key)| {
+                let nickname = client
+                    .parse::<HsClientNickname>()
+                    .map_err(|e| anyhow!("invalid client nickname {client:?}: {e}"))?;
+                let key = key
+                    .parse::<HsClientDescEncKey>()
+                    .map_err(|e| anyhow!("invalid key for client {client:?}: {e}"))?;
+                Ok((nickname, key))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(ServiceSpec {
+            name,
+            nickname,
+            secret_key,
+            ports,
+            authorized_clients,
+            dos,
+        })
+    }
+}
+
+/// The Tor keystore entry holding the key of service `name` when it has no
+/// `key` of its own.
+pub(crate) fn keystore_nickname(name: &str) -> Result<HsNickname, Error> {
+    format!("backtor-{name}")
+        .parse()
+        .map_err(|_| anyhow!("invalid service name {name:?}"))
+}
+
+/// Checks that shells can be started as `user`.
+fn check_user(user: &str) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(user).map_err(|_| anyhow!("invalid user {user:?}"))?;
+        if unsafe { libc::getpwnam(name.as_ptr()) }.is_null() {
+            bail!("unknown user {user:?}");
+        }
+        if unsafe { libc::geteuid() } != 0 {
+            bail!("running shells as {user:?} requires backtor to run as root");
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    bail!("running shells as {user:?} is only supported on Unix")
+}
+
+/// Converts the `key` setting, in minutes, to a duration.
+fn minutes(key: &str, minutes: Option<u64>) -> Result<Option<Duration>, Error> {
+    // A year, which keeps deadlines far from overflowing.
+    const MAX_MINUTES: u64 = 366 * 24 * 60;
+    match minutes {
+        Some(minutes) if minutes == 0 || minutes > MAX_MINUTES => {
+            bail!("{key} must be between 1 and {MAX_MINUTES} minutes")
+        }
+        Some(minutes) => Ok(Some(Duration::from_secs(minutes * 60))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(text: &str) -> Result<ServiceSpec, Error> {
+        toml::from_str::<ServiceConfig>(text)?.into_spec(PtySize::default())
+    }
+
+    fn ports(spec: &ServiceSpec) -> Vec<u16> {
+        spec.ports.keys().copied().collect()
+    }
+
+    fn transfer_roots() -> String {
+        format!("transfer_roots = [{:?}]\n", std::env::temp_dir())
+    }
+
+    #[test]
+    fn serves_a_shell_by_default() {
+        let spec = spec("").unwrap();
+        assert_eq!(spec.name, DEFAULT_SERVICE_NAME);
+        assert_eq!(ports(&spec), [DEFAULT_SHELL_PORT]);
+        assert_eq!(
+            spec.nickname,
+            keystore_nickname(DEFAULT_SERVICE_NAME).unwrap()
+        );
+    }
+
+    #[test]
+    fn adds_transfer_ports_only_with_roots() {
+        let plain = spec("ports = [23]\n").unwrap();
+        assert_eq!(ports(&plain), [23]);
+
+        let transfers = spec(&format!("ports = [23]\n{}", transfer_roots())).unwrap();
+        assert_eq!(ports(&transfers), [23, TRANSFER_PORT]);
+        assert!(matches!(
+            transfers.ports[&TRANSFER_PORT],
+            PortAction::Transfer(_)
+        ));
+
+        let sftp = spec(&format!("sftp = true\n{}", transfer_roots())).unwrap();
+        assert_eq!(ports(&sftp), [DEFAULT_SHELL_PORT, TRANSFER_PORT, SFTP_PORT]);
+        assert!(spec("sftp = true\n").is_err());
+    }
+
+    #[test]
+    fn adds_the_tunnel_port_only_for_a_non_default_policy() {
+        assert!(!ports(&spec("").unwrap()).contains(&TUNNEL_PORT));
+
+        let egress = spec("allow_egress = [\"127.0.0.1:80\"]\n").unwrap();
+        let PortAction::Tunnel(policy) = &egress.ports[&TUNNEL_PORT] else {
+            panic!("no tunnel port");
+        };
+        assert_eq!(policy.egress.len(), 1);
+        assert!(!policy.allow_listen);
+
+        let listen = spec("allow_remote_forwarding = true\n").unwrap();
+        let PortAction::Tunnel(policy) = &listen.ports[&TUNNEL_PORT] else {
+            panic!("no tunnel port");
+        };
+        assert!(policy.egress.is_empty());
+        assert!(policy.allow_listen);
+    }
+
+    #[test]
+    fn refuses_ports_used_twice() {
+        for text in [
+            "ports = [23, \"23:/bin/sh\"]\n",
+            "ports = [80]\nforwards = [\"80:127.0.0.1:8080\"]\n",
+            "forwards = [\"23:127.0.0.1:8080\"]\n",
+            "ports = [24]\nallow_remote_forwarding = true\n",
+        ] {
+            let error = spec(text).unwrap_err();
+            assert!(
+                error.to_string().contains("Onion port"),
+                "{text:?}: {error}"
+            );
+        }
+        let transfers = format!("forwards = [\"26:127.0.0.1:80\"]\n{}", transfer_roots());
+        assert!(spec(&transfers).is_err());
+    }
+
+    #[test]
+    fn refuses_names_used_twice() {
+        let server: ServerConfig = toml::from_str(
+            "[[service]]\nname = \"ops\"\n[[service]]\nname = \"web\"\n\
+             [[service]]\nname = \"ops\"\nports = [2222]\n",
+        )
+        .unwrap();
+        let error = server.into_specs().unwrap_err();
+        assert!(error.to_string().contains("\"ops\" is used more than once"));
+
+        // Services without a name are all called `shell`.
+        let server: ServerConfig =
+            toml::from_str("[[service]]\nports = [23]\n[[service]]\nports = [24]\n").unwrap();
+        assert!(server.into_specs().is_err());
+
+        assert!(spec("name = \"not a nickname!\"\n").is_err());
+    }
+
+    #[test]
+    fn runs_shells_as_the_configured_user() {
+        assert!(spec("user = \"no-such-user-backtor\"\n").is_err());
+
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let root = unsafe { libc::geteuid() } == 0;
+        match spec("user = \"root\"\nports = [23, 2222]\n") {
+            Ok(spec) => {
+                assert!(root);
+                for action in spec.ports.values() {
+                    let PortAction::Shell(shell) = action else {
+                        panic!("not a shell port");
+                    };
+                    assert_eq!(shell.user.as_deref(), Some("root"));
+                }
+            }
+            Err(e) => {
+                assert!(!root);
+                assert!(e.to_string().contains("requires backtor to run as root"));
+            }
+        }
+    }
+}
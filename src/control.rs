@@ -0,0 +1,811 @@
+//! The local control socket of `backtor serve`, and the `backtor ctl`
+//! client that talks to it.
+//!
+//! The protocol is one JSON [`ControlRequest`] per line, each answered by
+//! one JSON [`ControlResponse`] line. The socket is only accessible to the
+//! user running the server.
+
+use anyhow::{Context, Error, anyhow, bail};
+use arti_client::{ErrorKind, HasKind, TorClient};
+use clap::Subcommand;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Mutex;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::audit::{self, AuditEvent};
+use crate::dos;
+use crate::onion_server::{
+    RUNNING_ONION_SERVICES, ServiceSpec, onion_service_from_sk, stop_service,
+};
+use crate::sessions::{SESSIONS, SessionInfo};
+use crate::settings::Settings;
+use crate::systemd;
+use crate::utils::{get_onion_address, keypair_from_sk};
+
+/// A command for a running server.
+#[derive(Debug, Clone, Subcommand, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub(crate) enum ControlRequest {
+    /// List the configured services and whether they are reachable.
+    Services,
+    /// List the sessions being served.
+    Sessions,
+    /// End a session.
+    Kill {
+        /// Session number, as listed by `sessions`.
+        session: u64,
+    },
+    /// Stop a service and end its sessions.
+    Stop {
+        /// Service name, as listed by `services`.
+        service: String,
+    },
+    /// Start a stopped service.
+    Start {
+        /// Service name, as listed by `services`.
+        service: String,
+    },
+    /// Re-read the configuration files: start added services, stop removed ones
+    /// and restart changed ones.
+    Reload,
+}
+
+/// The answer to a [`ControlRequest`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub(crate) enum ControlResponse {
+    Services { services: Vec<ServiceStatus> },
+    Sessions { sessions: Vec<SessionInfo> },
+    Done { message: String },
+    Error { message: String },
+}
+
+/// One configured service as reported by `backtor ctl services`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ServiceStatus {
+    pub(crate) name: String,
+    /// Onion address; unknown for stopped services without a fixed key.
+    pub(crate) address: Option<String>,
+    /// `Stopped`, or the state arti reports for the running service.
+    pub(crate) state: String,
+    pub(crate) reachable: bool,
+    pub(crate) ports: Vec<u16>,
+    /// The proof-of-work effort clients currently pay, if PoW is enabled.
+    #[serde(default)]
+    pub(crate) pow_effort: Option<u32>,
+}
+
+/// How often, and how far apart, [`Controller`] retries launching a service
+/// whose previous instance has not released its state yet.
+const LAUNCH_ATTEMPTS: usize = 20;
+const LAUNCH_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Where `serve` listens and `ctl` connects when `--control-socket` is not
+/// given: `$XDG_RUNTIME_DIR/backtor.sock`, or a socket in a per-user
+/// directory in the temporary directory.
+pub(crate) fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("backtor.sock"),
+        _ => {
+            // SAFETY: geteuid has no preconditions and cannot fail.
+            let euid = unsafe { libc::geteuid() };
+            std::env::temp_dir()
+                .join(format!("backtor-{euid}"))
+                .join("backtor.sock")
+        }
+    }
+}
+
+/// Whether a file owned by `uid` can be trusted by `euid`: its own files
+/// and root's.
+fn trusted_owner(uid: u32, euid: u32) -> bool {
+    uid == euid || uid == 0
+}
+
+/// Checks that nobody but us (and root) can put a socket of their own at
+/// `path`, or has put one there already.
+fn check_socket_path(path: &Path) -> Result<(), Error> {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    check_socket_path_for(path, unsafe { libc::geteuid() })
+}
+
+/// [`check_socket_path`] on behalf of the user `euid`.
+fn check_socket_path_for(path: &Path, euid: u32) -> Result<(), Error> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let metadata =
+            std::fs::metadata(dir).with_context(|| format!("cannot access {}", dir.display()))?;
+        // Others may write to a sticky directory such as /tmp, but not
+        // replace our files in it.
+        let replaceable = metadata.mode() & 0o022 != 0 && metadata.mode() & 0o1000 == 0;
+        if !trusted_owner(metadata.uid(), euid) || replaceable {
+            bail!(
+                "{} is not safe for the control socket: it must be owned by you or root and \
+                 not writable by anyone else",
+                dir.display()
+            );
+        }
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !trusted_owner(metadata.uid(), euid) => {
+            bail!("{} belongs to another user", path.display())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The services of one `backtor serve` process, running or not.
+pub(crate) struct Controller {
+    tor_client: TorClient<PreferredRuntime>,
+    /// Whether the services came from the configuration files, which
+    /// `reload` re-reads.
+    from_config: bool,
+    /// The `--config` file read after the system and user ones, if any.
+    config_path: Option<PathBuf>,
+    /// Every configured service by name.
+    services: Mutex<BTreeMap<String, ServiceSpec>>,
+}
+
+impl Controller {
+    pub(crate) fn new(
+        tor_client: TorClient<PreferredRuntime>,
+        from_config: bool,
+        config_path: Option<PathBuf>,
+        specs: &[ServiceSpec],
+    ) -> Self {
+        let services = specs
+            .iter()
+            .map(|spec| (spec.name.clone(), spec.clone()))
+            .collect();
+        Controller {
+            tor_client,
+            from_config,
+            config_path,
+            services: Mutex::new(services),
+        }
+    }
+
+    async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        let result = match request {
+            ControlRequest::Services => {
+                return ControlResponse::Services {
+                    services: self.service_status().await,
+                };
+            }
+            ControlRequest::Sessions => {
+                return ControlResponse::Sessions {
+                    sessions: Self::named_sessions(),
+                };
+            }
+            ControlRequest::Kill { session } => Self::kill(session),
+            ControlRequest::Stop { service } => self.stop(&service).await,
+            ControlRequest::Start { service } => self.start(&service).await,
+            ControlRequest::Reload => self.reload().await,
+        };
+        match result {
+            Ok(message) => ControlResponse::Done { message },
+            Err(e) => ControlResponse::Error {
+                message: format!("{e:#}"),
+            },
+        }
+    }
+
+    async fn service_status(&self) -> Vec<ServiceStatus> {
+        let services = self.services.lock().await;
+        let running = RUNNING_ONION_SERVICES.lock().unwrap();
+        services
+            .values()
+            .map(|spec| {
+                let ports = spec.ports.keys().copied().collect();
+                let pow_effort = spec.dos.pow.then(|| dos::current_effort(&spec.nickname));
+                match running.get(&spec.name) {
+                    Some(service) => {
+                        let state = service.service.status().state();
+                        ServiceStatus {
+                            name: spec.name.clone(),
+                            address: Some(service.address.to_string()),
+                            state: format!("{state:?}"),
+                            reachable: state.is_fully_reachable(),
+                            ports,
+                            pow_effort,
+                        }
+                    }
+                    None => ServiceStatus {
+                        name: spec.name.clone(),
+                        address: spec.secret_key.map(|sk| {
+                            let address =
+                                get_onion_address(keypair_from_sk(sk).public().as_bytes());
+                            format!("{address}.onion")
+                        }),
+                        state: "Stopped".to_owned(),
+                        reachable: false,
+                        ports,
+                        pow_effort: None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Launches `spec`, giving a just-stopped instance of the service a few
+    /// seconds to shut down and release its state directory.
+    async fn launch(&self, spec: ServiceSpec) -> Result<(), Error> {
+        for _ in 0..LAUNCH_ATTEMPTS {
+            match onion_service_from_sk(self.tor_client.clone(), spec.clone()).await {
+                Err(e) if is_still_locked(&e) => {
+                    debug!("State of {} still locked, retrying: {e}", spec.name);
+                    tokio::time::sleep(LAUNCH_RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
+        onion_service_from_sk(self.tor_client.clone(), spec).await
+    }
+
+    /// The live sessions, labelled with the name of their service rather
+    /// than its address.
+    fn named_sessions() -> Vec<SessionInfo> {
+        let running = RUNNING_ONION_SERVICES.lock().unwrap();
+        let mut sessions = SESSIONS.list();
+        for session in &mut sessions {
+            if let Some((name, _)) = running
+                .iter()
+                .find(|(_, service)| *service.address == session.service)
+            {
+                session.service = name.clone();
+            }
+        }
+        sessions
+    }
+
+    fn kill(session: u64) -> Result<String, Error> {
+        if !SESSIONS.kill(session) {
+            bail!("No session {session}");
+        }
+        audit::record(AuditEvent::SessionKilled { session });
+        info!("Killed session {session}");
+        Ok(format!("Killed session {session}"))
+    }
+
+    async fn stop(&self, name: &str) -> Result<String, Error> {
+        if !self.services.lock().await.contains_key(name) {
+            bail!("No service {name:?}");
+        }
+        stop_service(name).ok_or_else(|| anyhow!("Service {name:?} is not running"))?;
+        Ok(format!("Stopped {name}"))
+    }
+
+    async fn start(&self, name: &str) -> Result<String, Error> {
+        let spec = self
+            .services
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("No service {name:?}"))?;
+        self.launch(spec).await?;
+        info!("Started service {name}");
+        Ok(format!("Started {name}"))
+    }
+
+    async fn reload(&self) -> Result<String, Error> {
+        if !self.from_config {
+            bail!("The services were given on the command line, not in the configuration");
+        }
+        let settings = Settings::load(self.config_path.as_deref())?;
+        if settings.server.services.is_empty() {
+            bail!("The configuration no longer defines any [[service]]");
+        }
+        let sources = settings.sources;
+        let specs = settings.server.into_specs()?;
+        let mut new: BTreeMap<String, ServiceSpec> = specs
+            .into_iter()
+            .map(|spec| (spec.name.clone(), spec))
+            .collect();
+
+        // Unchanged services keep running; the others are stopped and
+        // (re)started from the new definitions.
+        let mut services = self.services.lock().await;
+        let mut changes = Vec::new();
+        let mut removed = Vec::new();
+        for (name, old) in services.iter() {
+            match new.get(name) {
+                Some(spec) if spec == old => {
+                    new.remove(name);
+                }
+                Some(_) => {
+                    stop_service(name);
+                    changes.push(format!("restarted {name}"));
+                }
+                None => {
+                    stop_service(name);
+                    changes.push(format!("stopped {name}"));
+                    removed.push(name.clone());
+                }
+            }
+        }
+        for name in &removed {
+            services.remove(name);
+        }
+        for name in new.keys() {
+            if !services.contains_key(name) {
+                changes.push(format!("started {name}"));
+            }
+        }
+        services.extend(new.clone());
+        drop(services);
+
+        for (name, spec) in new {
+            self.launch(spec)
+                .await
+                .with_context(|| format!("cannot start {name}"))?;
+        }
+
+        let sources: Vec<_> = sources.iter().map(|p| p.display().to_string()).collect();
+        info!("Reloaded {}", sources.join(", "));
+        if changes.is_empty() {
+            Ok("No changes".to_owned())
+        } else {
+            Ok(format!("Reloaded: {}", changes.join(", ")))
+        }
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, reloading the configuration on every
+/// SIGHUP in the meantime.
+pub(crate) async fn wait_for_shutdown_signal(controller: &Controller) -> Result<(), Error> {
+    handle_signals(|| controller.reload()).await
+}
+
+/// [`wait_for_shutdown_signal`], reloading with `reload`.
+async fn handle_signals<F>(mut reload: impl FnMut() -> F) -> Result<(), Error>
+where
+    F: Future<Output = Result<String, Error>>,
+{
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                systemd::notify_reloading();
+                match reload().await {
+                    Ok(message) => info!("SIGHUP: {message}"),
+                    Err(e) => error!("SIGHUP: reload failed: {e:#}"),
+                }
+                systemd::notify("READY=1");
+            }
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down");
+                return Ok(());
+            }
+            _ = interrupt.recv() => {
+                info!("Received SIGINT, shutting down");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Whether launching failed because a stopped instance of the same service
+/// still holds its state lock.
+fn is_still_locked(error: &Error) -> bool {
+    error
+        .downcast_ref::<arti_client::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::LocalResourceAlreadyInUse)
+}
+
+/// Binds the control socket at `path`, replacing a stale socket file.
+///
+/// Missing directories are created accessible only to us, and the socket is
+/// never accessible to anyone else, not even briefly.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if let Some(parent) = path.parent().filter(|dir| !dir.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)
+            .with_context(|| format!("cannot create {}", parent.display()))?;
+    }
+    check_socket_path(path)?;
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("another backtor already listens on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("cannot remove stale socket {}", path.display()))?;
+    }
+    // Create the socket in a directory only we can enter, restrict it, and
+    // only then move it into place. Changing the umask instead would affect
+    // every thread of the process.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    let staging = path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(format!(".backtor-{}-{nanos}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("cannot create {}", staging.display()))?;
+    let listener = bind_in(&staging, path);
+    let _ = std::fs::remove_dir_all(&staging);
+    listener
+}
+
+/// Binds a socket in the private directory `staging` and moves it to `path`.
+fn bind_in(staging: &Path, path: &Path) -> Result<UnixListener, Error> {
+    let socket = staging.join("s");
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("cannot listen on {}", path.display()))?;
+    std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&socket, path)
+        .with_context(|| format!("cannot move the socket to {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Answers control requests on `listener` until the process exits.
+pub(crate) async fn run_control_socket(listener: UnixListener, controller: Arc<Controller>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Control socket: {e}");
+                continue;
+            }
+        };
+        let controller = controller.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_control_client(stream, &controller).await {
+                debug!("Control client: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_control_client(stream: UnixStream, controller: &Controller) -> Result<(), Error> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                debug!("Control request: {request:?}");
+                controller.handle(request).await
+            }
+            Err(e) => ControlResponse::Error {
+                message: format!("bad request: {e}"),
+            },
+        };
+        let mut reply = serde_json::to_vec(&response)?;
+        reply.push(b'\n');
+        write.write_all(&reply).await?;
+    }
+    Ok(())
+}
+
+/// Sends `request` to the server listening on `socket` and prints the answer.
+pub(crate) async fn send(socket: &Path, request: &ControlRequest) -> Result<(), Error> {
+    // Do not hand our request to a server someone else put in our way.
+    if socket.exists() {
+        check_socket_path(socket)?;
+    }
+    let stream = UnixStream::connect(socket).await.with_context(|| {
+        format!(
+            "cannot connect to {} (is backtor serve running?)",
+            socket.display()
+        )
+    })?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+
+    let reply = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("the server closed the control connection"))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    print!("{}", render(serde_json::from_str(&reply)?, now)?);
+    Ok(())
+}
+
+/// Formats a response for the terminal, `now` being the current Unix time
+/// in seconds; an error response becomes an error.
+fn render(response: ControlResponse, now: u64) -> Result<String, Error> {
+    let mut out = String::new();
+    match response {
+        ControlResponse::Services { services } => {
+            writeln!(
+                out,
+                "{:<16} {:<20} {:<9} {:<14} {:<10} ADDRESS",
+                "NAME", "STATE", "REACHABLE", "PORTS", "POW EFFORT"
+            )?;
+            for service in services {
+                let ports = service
+                    .ports
+                    .iter()
+                    .map(u16::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let pow_effort = service
+                    .pow_effort
+                    .map_or_else(|| "-".to_owned(), |effort| effort.to_string());
+                writeln!(
+                    out,
+                    "{:<16} {:<20} {:<9} {:<14} {:<10} {}",
+                    service.name,
+                    service.state,
+                    if service.reachable { "yes" } else { "no" },
+                    ports,
+                    pow_effort,
+                    service.address.as_deref().unwrap_or("-"),
+                )?;
+            }
+        }
+        ControlResponse::Sessions { sessions } => {
+            writeln!(
+                out,
+                "{:<6} {:<16} {:<8} {:<5} {:<8} {:<8} DETAIL",
+                "ID", "SERVICE", "CIRCUIT", "PORT", "KIND", "AGE"
+            )?;
+            for session in sessions {
+                writeln!(
+                    out,
+                    "{:<6} {:<16} {:<8} {:<5} {:<8} {:<8} {}",
+                    session.id,
+                    session.service,
+                    session.circuit,
+                    session.port,
+                    session.kind,
+                    format_age(now.saturating_sub(session.started)),
+                    session.detail,
+                )?;
+            }
+        }
+        ControlResponse::Done { message } => writeln!(out, "{message}")?,
+        ControlResponse::Error { message } => bail!(message),
+    }
+    Ok(out)
+}
+
+/// Formats a duration in seconds like `2h05m`, `3m07s` or `12s`.
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    fn request(line: &str) -> Result<ControlRequest, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    #[test]
+    fn parses_requests() {
+        assert!(matches!(
+            request(r#"{"command":"services"}"#),
+            Ok(ControlRequest::Services)
+        ));
+        assert!(matches!(
+            request(r#"{"command":"sessions"}"#),
+            Ok(ControlRequest::Sessions)
+        ));
+        assert!(matches!(
+            request(r#"{"command":"kill","session":7}"#),
+            Ok(ControlRequest::Kill { session: 7 })
+        ));
+        assert!(matches!(
+            request(r#"{"command":"stop","service":"web"}"#),
+            Ok(ControlRequest::Stop { service }) if service == "web"
+        ));
+        assert!(matches!(
+            request(r#"{"command":"start","service":"web"}"#),
+            Ok(ControlRequest::Start { service }) if service == "web"
+        ));
+        assert!(matches!(
+            request(r#"{"command":"reload"}"#),
+            Ok(ControlRequest::Reload)
+        ));
+        let line = serde_json::to_string(&ControlRequest::Kill { session: 3 }).unwrap();
+        assert_eq!(line, r#"{"command":"kill","session":3}"#);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        for line in [
+            "",
+            "services",
+            r#"{"command":"restart"}"#,
+            r#"{"command":"kill"}"#,
+            r#"{"command":"kill","session":"all"}"#,
+            r#"{"command":"stop"}"#,
+        ] {
+            assert!(request(line).is_err(), "{line:?} parsed");
+        }
+    }
+
+    #[test]
+    fn renders_responses() {
+        let services = ControlResponse::Services {
+            services: vec![ServiceStatus {
+                name: "web".to_owned(),
+                address: None,
+                state: "Stopped".to_owned(),
+                reachable: false,
+                ports: vec![22, 80],
+                pow_effort: Some(12),
+            }],
+        };
+        let lines = render(services, 0).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert!(lines[0].starts_with("NAME "));
+        let fields: Vec<_> = lines[1].split_whitespace().collect();
+        assert_eq!(fields, ["web", "Stopped", "no", "22,80", "12", "-"]);
+
+        let sessions = ControlResponse::Sessions {
+            sessions: vec![SessionInfo {
+                id: 4,
+                service: "web".to_owned(),
+                circuit: 2,
+                port: 22,
+                kind: "shell".to_owned(),
+                detail: "/bin/sh -l".to_owned(),
+                started: 1000,
+            }],
+        };
+        let lines = render(sessions, 1000 + 3725).unwrap();
+        let fields: Vec<_> = lines.lines().nth(1).unwrap().split_whitespace().collect();
+        assert_eq!(
+            fields,
+            ["4", "web", "2", "22", "shell", "1h02m", "/bin/sh", "-l"]
+        );
+
+        let done = ControlResponse::Done {
+            message: "Stopped web".to_owned(),
+        };
+        assert_eq!(render(done, 0).unwrap(), "Stopped web\n");
+        let error = ControlResponse::Error {
+            message: "No service \"x\"".to_owned(),
+        };
+        assert_eq!(
+            render(error, 0).unwrap_err().to_string(),
+            "No service \"x\""
+        );
+    }
+
+    #[test]
+    fn formats_ages() {
+        assert_eq!(format_age(12), "12s");
+        assert_eq!(format_age(187), "3m07s");
+        assert_eq!(format_age(7500), "2h05m");
+    }
+
+    /// The user to check as, for whom `path` belongs to someone else.
+    fn foreign_euid(path: &Path) -> u32 {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        if euid == 0 {
+            std::os::unix::fs::chown(path, Some(4242), None).unwrap();
+            0
+        } else {
+            // Root's files are trusted, so pretend to be someone else.
+            euid + 1
+        }
+    }
+
+    #[test]
+    fn socket_path_must_be_private() {
+        let dir = scratch_dir("socket-mode");
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        let socket = dir.join("backtor.sock");
+        assert!(check_socket_path_for(&socket, euid).is_ok());
+
+        for (mode, safe) in [
+            (0o755, true),
+            (0o1777, true),
+            (0o770, false),
+            (0o777, false),
+        ] {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode)).unwrap();
+            let checked = check_socket_path_for(&socket, euid);
+            assert_eq!(checked.is_ok(), safe, "mode {mode:o}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn socket_path_refuses_directories_of_other_users() {
+        let dir = scratch_dir("socket-owner");
+        let socket = dir.join("backtor.sock");
+        let euid = foreign_euid(&dir);
+        assert!(check_socket_path_for(&socket, euid).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn socket_path_refuses_planted_sockets() {
+        let dir = scratch_dir("socket-planted");
+        let socket = dir.join("backtor.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        assert!(check_socket_path_for(&socket, euid).is_ok());
+
+        // Only root can hand the socket, and not its directory, to someone
+        // else.
+        if euid == 0 {
+            std::os::unix::fs::chown(&socket, Some(4242), None).unwrap();
+            let error = check_socket_path_for(&socket, euid).unwrap_err();
+            assert!(error.to_string().contains("belongs to another user"));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn binds_a_private_socket_over_a_stale_one() {
+        let dir = scratch_dir("socket-bind");
+        let socket = dir.join("backtor.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+        let _listener = bind(&socket).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixStream::connect(&socket).await.is_ok());
+        // Nothing is left of the directory the socket was created in.
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["backtor.sock"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_on_sighup_until_sigterm() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Catch the signals before raising them, or they end the tests.
+        let _hangup = signal(SignalKind::hangup()).unwrap();
+        let _terminate = signal(SignalKind::terminate()).unwrap();
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counted = reloads.clone();
+        let waiting = tokio::spawn(handle_signals(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async { Ok("reloaded".to_owned()) }
+        }));
+
+        // Signals raised before the task listens are lost, so repeat them.
+        while reloads.load(Ordering::SeqCst) == 0 {
+            // SAFETY: raise has no preconditions, and SIGHUP is handled.
+            unsafe { libc::raise(libc::SIGHUP) };
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!waiting.is_finished());
+        while !waiting.is_finished() {
+            // SAFETY: raise has no preconditions, and SIGTERM is handled.
+            unsafe { libc::raise(libc::SIGTERM) };
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        waiting.await.unwrap().unwrap();
+    }
+}
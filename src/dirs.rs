@@ -0,0 +1,236 @@
+//! Where the embedded Tor client keeps its state and cache.
+//!
+//! The state directory holds the keys of onion services started without
+//! `--key`, so it must stay in one place and be private to its owner.
+
+use anyhow::{Context, Error, anyhow};
+use log::warn;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// The directory older versions kept everything in, relative to the
+/// current directory.
+const LEGACY_DIR: &str = ".backtor";
+
+/// The state and cache directories of the Tor client.
+#[derive(Debug, Clone)]
+pub(crate) struct TorDirectories {
+    pub(crate) state: PathBuf,
+    pub(crate) cache: PathBuf,
+}
+
+impl TorDirectories {
+    /// Picks the directories: the given overrides, else the ones systemd
+    /// prepared (`$STATE_DIRECTORY` and `$CACHE_DIRECTORY`), else
+    /// `/var/lib/backtor` and `/var/cache/backtor` when running as root, else
+    /// the per-user XDG base directories.
+    pub(crate) fn resolve(state: Option<PathBuf>, cache: Option<PathBuf>) -> Result<Self, Error> {
+        Self::resolve_in(state, cache, &non_empty_var, is_root())
+    }
+
+    /// [`resolve`](Self::resolve) with the environment variables `var`
+    /// returns, running as root or not.
+    fn resolve_in(
+        state: Option<PathBuf>,
+        cache: Option<PathBuf>,
+        var: &dyn Fn(&str) -> Option<OsString>,
+        root: bool,
+    ) -> Result<Self, Error> {
+        let state = match state {
+            Some(state) => state,
+            None => default_dir(
+                var,
+                root,
+                "STATE_DIRECTORY",
+                "/var/lib/backtor",
+                "XDG_STATE_HOME",
+                ".local/state",
+            )
+            .context("cannot choose a state directory; pass --state-dir")?,
+        };
+        let cache = match cache {
+            Some(cache) => cache,
+            None => default_dir(
+                var,
+                root,
+                "CACHE_DIRECTORY",
+                "/var/cache/backtor",
+                "XDG_CACHE_HOME",
+                ".cache",
+            )
+            .context("cannot choose a cache directory; pass --cache-dir")?,
+        };
+        Ok(TorDirectories { state, cache })
+    }
+
+    /// Creates whichever directories are missing, accessible only to us.
+    ///
+    /// Existing directories are left alone: arti checks their permissions
+    /// when the Tor client starts.
+    pub(crate) fn create(&self) -> Result<(), Error> {
+        for dir in [&self.state, &self.cache] {
+            if dir.exists() {
+                continue;
+            }
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder
+                .create(dir)
+                .with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Points out Tor state left in the current directory by older versions,
+    /// which is no longer used: services without `--key` would silently get
+    /// new addresses.
+    pub(crate) fn warn_about_legacy_state(&self) {
+        let legacy = Path::new(LEGACY_DIR).join("config");
+        if legacy.join("keystore").is_dir() && !self.state.join("keystore").exists() {
+            warn!(
+                "Ignoring Tor state in {} left by an older backtor. To keep the addresses \
+                 of services without --key, stop backtor and run: mv {} {}",
+                legacy.display(),
+                legacy.join("*").display(),
+                self.state.display(),
+            );
+        }
+    }
+
+    /// A fix-it hint for arti's complaints about directory permissions.
+    pub(crate) fn permission_hint(&self) -> String {
+        format!(
+            "The Tor state and cache directories ({state}, {cache}) and every directory \
+             above them must be owned by you or root and not writable by anyone else; \
+             for example, run: chmod -R go-rwx {state} {cache}\n\
+             To skip this check (not recommended), set ARTI_FS_DISABLE_PERMISSION_CHECKS=1.",
+            state = self.state.display(),
+            cache = self.cache.display(),
+        )
+    }
+}
+
+/// Whether we run as root, and so should use the system directories.
+fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// A default directory: `$SYSTEMD_VAR` (the first of a `:`-separated list),
+/// `system` as root, or `$XDG_VAR/backtor` falling back to
+/// `$HOME/HOME_RELATIVE/backtor`, looking variables up with `var`.
+fn default_dir(
+    var: &dyn Fn(&str) -> Option<OsString>,
+    root: bool,
+    systemd_var: &str,
+    system: &str,
+    xdg_var: &str,
+    home_relative: &str,
+) -> Result<PathBuf, Error> {
+    if let Some(dirs) = var(systemd_var)
+        && let Some(dir) = dirs.to_str().and_then(|dirs| dirs.split(':').next())
+    {
+        return Ok(PathBuf::from(dir));
+    }
+    if root {
+        return Ok(PathBuf::from(system));
+    }
+    if let Some(base) = var(xdg_var) {
+        return Ok(PathBuf::from(base).join("backtor"));
+    }
+    #[cfg(windows)]
+    if let Some(base) = var("LOCALAPPDATA") {
+        let leaf = if xdg_var == "XDG_CACHE_HOME" {
+            "cache"
+        } else {
+            "state"
+        };
+        return Ok(PathBuf::from(base).join("backtor").join(leaf));
+    }
+    let home = var("HOME").ok_or_else(|| anyhow!("$HOME is not set"))?;
+    Ok(PathBuf::from(home).join(home_relative).join("backtor"))
+}
+
+pub(crate) fn non_empty_var(name: &str) -> Option<OsString> {
+    std::env::var_os(name).filter(|value| !value.is_empty())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn resolve(vars: &[(&str, &str)], root: bool) -> Result<TorDirectories, Error> {
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(key, value)| *key == name && !value.is_empty())
+                .map(|(_, value)| OsString::from(value))
+        };
+        TorDirectories::resolve_in(None, None, &var, root)
+    }
+
+    const SYSTEMD: [(&str, &str); 2] = [
+        ("STATE_DIRECTORY", "/var/lib/backtor-a:/var/lib/backtor-b"),
+        ("CACHE_DIRECTORY", "/var/cache/unit"),
+    ];
+    const XDG: [(&str, &str); 2] = [
+        ("XDG_STATE_HOME", "/xdg/state"),
+        ("XDG_CACHE_HOME", "/xdg/cache"),
+    ];
+    const HOME: (&str, &str) = ("HOME", "/home/user");
+
+    #[test]
+    fn overrides_win() {
+        let var = |_: &str| Some(OsString::from("/ignored"));
+        let dirs = TorDirectories::resolve_in(
+            Some("/my/state".into()),
+            Some("/my/cache".into()),
+            &var,
+            true,
+        )
+        .unwrap();
+        assert_eq!(dirs.state, Path::new("/my/state"));
+        assert_eq!(dirs.cache, Path::new("/my/cache"));
+    }
+
+    #[test]
+    fn prefers_systemd_directories() {
+        let vars = [SYSTEMD[0], SYSTEMD[1], XDG[0], XDG[1], HOME];
+        let dirs = resolve(&vars, true).unwrap();
+        assert_eq!(dirs.state, Path::new("/var/lib/backtor-a"));
+        assert_eq!(dirs.cache, Path::new("/var/cache/unit"));
+    }
+
+    #[test]
+    fn uses_system_directories_as_root() {
+        let dirs = resolve(&[XDG[0], XDG[1], HOME, ("STATE_DIRECTORY", "")], true).unwrap();
+        assert_eq!(dirs.state, Path::new("/var/lib/backtor"));
+        assert_eq!(dirs.cache, Path::new("/var/cache/backtor"));
+    }
+
+    #[test]
+    fn uses_xdg_directories_then_home() {
+        let dirs = resolve(&[XDG[0], XDG[1], HOME], false).unwrap();
+        assert_eq!(dirs.state, Path::new("/xdg/state/backtor"));
+        assert_eq!(dirs.cache, Path::new("/xdg/cache/backtor"));
+
+        let dirs = resolve(&[XDG[1], HOME], false).unwrap();
+        assert_eq!(dirs.state, Path::new("/home/user/.local/state/backtor"));
+        assert_eq!(dirs.cache, Path::new("/xdg/cache/backtor"));
+    }
+
+    #[test]
+    fn needs_home_as_a_last_resort() {
+        let error = resolve(&[], false).unwrap_err();
+        assert!(format!("{error:#}").contains("--state-dir"));
+        assert!(resolve(&[], true).is_ok());
+    }
+}
@@ -0,0 +1,690 @@
+//! Protection of onion services against floods: arti's proof-of-work
+//! defense, the rate limit introduction points apply, limits on the streams
+//! a service serves at once or accepts per second, and the proof-of-work
+//! statistics `backtor ctl services` and the logs report.
+//!
+//! With proof of work enabled, the service publishes a suggested effort that
+//! rises while it is flooded; clients solve a puzzle of that effort before
+//! they may introduce themselves, and the service answers the hardest-working
+//! ones first. Without load the suggested effort is 0 and costs nothing.
+//!
+//! arti keeps the suggested effort to itself, but reports the effort of every
+//! introduction through the `metrics` facade, which [`init`] taps into.
+
+use clap::ValueEnum;
+use log::{info, warn};
+use metrics::{
+    Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tor_hsservice::HsNickname;
+use tor_hsservice::config::{OnionServiceConfigBuilder, TokenBucketConfig};
+
+/// How many introductions wait for an answer while proof of work is
+/// required (arti's default; each takes a few KB).
+pub(crate) const DEFAULT_POW_QUEUE_DEPTH: usize = 8192;
+
+/// How many streams a service serves at once by default.
+pub(crate) const DEFAULT_MAX_SESSIONS: usize = 64;
+
+/// How many streams one circuit, that is one client, may have open at once
+/// by default. Generous enough for a browser behind `connect -D`.
+pub(crate) const DEFAULT_MAX_SESSIONS_PER_CIRCUIT: usize = 32;
+
+/// How long a queued stream waits for a free slot before it is rejected.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many streams may wait for a slot at once; later ones are rejected.
+const MAX_QUEUED_STREAMS: usize = 64;
+
+/// Rejected streams are logged at most this often, so a flood does not
+/// flood the logs too.
+const REJECTION_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often [`log_pow_activity`] summarizes the introductions that came
+/// with proof of work. arti reconsiders the suggested effort as often.
+const POW_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+/// `RATE/BURST`: at most `rate` events per second on average, and `burst`
+/// at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimit {
+    pub(crate) rate: u32,
+    pub(crate) burst: u32,
+}
+
+impl RateLimit {
+    /// Checks the limit is one Tor can pass on to introduction points.
+    pub(crate) fn check(self) -> Result<Self, String> {
+        let max = i32::MAX as u32;
+        if self.rate == 0 || self.rate > max || self.burst > max {
+            return Err(format!("the rate must be between 1 and {max}"));
+        }
+        if self.burst < self.rate {
+            return Err("the burst must be at least the rate".into());
+        }
+        Ok(self)
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected RATE/BURST, got {s:?}"))?;
+        let parse = |n: &str| {
+            n.parse::<u32>()
+                .map_err(|_| format!("invalid number {n:?} in {s:?}"))
+        };
+        RateLimit {
+            rate: parse(rate)?,
+            burst: parse(burst)?,
+        }
+        .check()
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.rate, self.burst)
+    }
+}
+
+/// What happens to a stream that arrives while a limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WhenLimited {
+    /// Refuse the stream right away.
+    #[default]
+    Reject,
+    /// Let the stream wait for a free slot, for up to 30 seconds.
+    Queue,
+}
+
+/// The flood protection of one onion service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DosSettings {
+    /// Ask clients for proof of work when flooded.
+    pub(crate) pow: bool,
+    /// Introductions waiting for an answer beyond this many are dropped,
+    /// the lowest effort first.
+    pub(crate) pow_queue_depth: usize,
+    /// `None` lets the introduction points pick a limit from the consensus.
+    pub(crate) intro_rate_limit: Option<RateLimit>,
+    /// Streams served at once; 0 for no limit.
+    pub(crate) max_sessions: usize,
+    /// Streams served at once per circuit; 0 for no limit.
+    pub(crate) max_sessions_per_circuit: usize,
+    /// New streams accepted per second.
+    pub(crate) stream_rate_limit: Option<RateLimit>,
+    pub(crate) when_limited: WhenLimited,
+}
+
+impl Default for DosSettings {
+    fn default() -> Self {
+        DosSettings {
+            pow: true,
+            pow_queue_depth: DEFAULT_POW_QUEUE_DEPTH,
+            intro_rate_limit: None,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            max_sessions_per_circuit: DEFAULT_MAX_SESSIONS_PER_CIRCUIT,
+            stream_rate_limit: None,
+            when_limited: WhenLimited::Reject,
+        }
+    }
+}
+
+impl DosSettings {
+    pub(crate) fn apply(&self, builder: &mut OnionServiceConfigBuilder) {
+        builder
+            .enable_pow(self.pow)
+            .pow_rend_queue_depth(self.pow_queue_depth)
+            .rate_limit_at_intro(
+                self.intro_rate_limit
+                    .map(|limit| TokenBucketConfig::new(limit.rate, limit.burst)),
+            );
+    }
+}
+
+/// The limit that kept a stream out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limited {
+    Sessions,
+    SessionsPerCircuit,
+    Rate,
+    QueueFull,
+    QueueTimeout,
+    /// The service was stopped while the stream waited.
+    Stopped,
+}
+
+impl Limited {
+    /// The reason recorded in the audit log.
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            Limited::Sessions => "session limit reached",
+            Limited::SessionsPerCircuit => "per-circuit session limit reached",
+            Limited::Rate => "stream rate limit exceeded",
+            Limited::QueueFull => "too many streams queued",
+            Limited::QueueTimeout => "no free session slot in time",
+            Limited::Stopped => "service stopped",
+        }
+    }
+}
+
+/// Enforces the stream limits of one service.
+pub(crate) struct SessionLimiter {
+    max_sessions: usize,
+    max_sessions_per_circuit: usize,
+    when_limited: WhenLimited,
+    state: Mutex<LimiterState>,
+    /// Woken whenever a stream ends.
+    freed: Notify,
+}
+
+struct LimiterState {
+    sessions: usize,
+    per_circuit: HashMap<u64, usize>,
+    bucket: Option<TokenBucket>,
+    queued: usize,
+    /// When a rejection was last logged, and how many were not since.
+    warned: Option<Instant>,
+    suppressed: usize,
+}
+
+/// A slot taken by one stream, given back when dropped.
+pub(crate) struct SessionPermit {
+    limiter: Arc<SessionLimiter>,
+    circuit: u64,
+}
+
+impl SessionLimiter {
+    pub(crate) fn new(settings: &DosSettings) -> Arc<Self> {
+        Arc::new(SessionLimiter {
+            max_sessions: settings.max_sessions,
+            max_sessions_per_circuit: settings.max_sessions_per_circuit,
+            when_limited: settings.when_limited,
+            state: Mutex::new(LimiterState {
+                sessions: 0,
+                per_circuit: HashMap::new(),
+                bucket: settings.stream_rate_limit.map(TokenBucket::new),
+                queued: 0,
+                warned: None,
+                suppressed: 0,
+            }),
+            freed: Notify::new(),
+        })
+    }
+
+    pub(crate) fn when_limited(&self) -> WhenLimited {
+        self.when_limited
+    }
+
+    /// Logs that a stream to `service` was rejected, unless one was logged
+    /// less than [`REJECTION_LOG_INTERVAL`] ago.
+    pub(crate) fn warn_rejected(&self, service: &str, limited: Limited) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state
+            .warned
+            .is_some_and(|warned| now.duration_since(warned) < REJECTION_LOG_INTERVAL)
+        {
+            state.suppressed += 1;
+            return;
+        }
+        let suppressed = std::mem::take(&mut state.suppressed);
+        state.warned = Some(now);
+        drop(state);
+        if suppressed == 0 {
+            warn!("Rejected a stream to {service}: {}", limited.reason());
+        } else {
+            warn!(
+                "Rejected a stream to {service}: {} ({suppressed} more rejections not logged)",
+                limited.reason()
+            );
+        }
+    }
+
+    /// Takes a slot for a new stream on `circuit` if every limit allows it.
+    /// On a rate limit, also says how long until the next stream may come.
+    fn try_take(
+        self: &Arc<Self>,
+        circuit: u64,
+    ) -> Result<SessionPermit, (Limited, Option<Duration>)> {
+        let mut state = self.state.lock().unwrap();
+        if self.max_sessions != 0 && state.sessions >= self.max_sessions {
+            return Err((Limited::Sessions, None));
+        }
+        let on_circuit = state.per_circuit.get(&circuit).copied().unwrap_or(0);
+        if self.max_sessions_per_circuit != 0 && on_circuit >= self.max_sessions_per_circuit {
+            return Err((Limited::SessionsPerCircuit, None));
+        }
+        if let Some(bucket) = &mut state.bucket {
+            bucket
+                .take(Instant::now())
+                .map_err(|wait| (Limited::Rate, Some(wait)))?;
+        }
+        state.sessions += 1;
+        *state.per_circuit.entry(circuit).or_default() += 1;
+        Ok(SessionPermit {
+            limiter: self.clone(),
+            circuit,
+        })
+    }
+
+    /// Admits a new stream on `circuit` right away, or says which limit
+    /// stands in the way.
+    pub(crate) fn try_admit(self: &Arc<Self>, circuit: u64) -> Result<SessionPermit, Limited> {
+        self.try_take(circuit).map_err(|(limited, _)| limited)
+    }
+
+    /// Waits until a new stream on `circuit` may be served, for up to
+    /// [`QUEUE_TIMEOUT`].
+    pub(crate) async fn admit(self: &Arc<Self>, circuit: u64) -> Result<SessionPermit, Limited> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.queued >= MAX_QUEUED_STREAMS {
+                return Err(Limited::QueueFull);
+            }
+            state.queued += 1;
+        }
+        let admitted = tokio::time::timeout(QUEUE_TIMEOUT, async {
+            loop {
+                let freed = self.freed.notified();
+                tokio::pin!(freed);
+                freed.as_mut().enable();
+                match self.try_take(circuit) {
+                    Ok(permit) => return permit,
+                    Err((_, Some(wait))) => tokio::time::sleep(wait).await,
+                    Err((_, None)) => freed.await,
+                }
+            }
+        })
+        .await
+        .map_err(|_| Limited::QueueTimeout);
+        self.state.lock().unwrap().queued -= 1;
+        admitted
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.sessions -= 1;
+        if let Some(count) = state.per_circuit.get_mut(&self.circuit) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_circuit.remove(&self.circuit);
+            }
+        }
+        drop(state);
+        self.limiter.freed.notify_waiters();
+    }
+}
+
+/// A token bucket holding up to `burst` tokens, refilled at `rate` per
+/// second.
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or says how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * f64::from(self.limit.rate)).min(f64::from(self.limit.burst));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / f64::from(self.limit.rate),
+            ))
+        }
+    }
+}
+
+/// What arti reported about the introductions to one service.
+#[derive(Debug, Default)]
+struct PowStats {
+    /// The effort of the latest accepted introduction, 0 without proof of
+    /// work. Clients pay what the service suggests, so this follows it.
+    effort: u32,
+    /// The effort of a verified proof whose introduction is being queued.
+    pending_effort: Option<u32>,
+    /// Since the last summary: introductions with proof of work, the
+    /// highest effort among them, failed proofs and dropped introductions.
+    solved: u64,
+    highest: u32,
+    failed: u64,
+    dropped: u64,
+}
+
+/// Proof-of-work statistics by service nickname.
+static POW_STATS: LazyLock<Mutex<HashMap<String, PowStats>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The effort clients currently pay to introduce themselves to `nickname`.
+pub(crate) fn current_effort(nickname: &HsNickname) -> u32 {
+    POW_STATS
+        .lock()
+        .unwrap()
+        .get(&nickname.to_string())
+        .map_or(0, |stats| stats.effort)
+}
+
+/// Starts collecting proof-of-work statistics. Call once, before launching
+/// services.
+pub(crate) fn init() {
+    let _ = metrics::set_global_recorder(PowRecorder);
+}
+
+/// Logs, every few minutes, which services had to ask for proof of work.
+pub(crate) async fn log_pow_activity() {
+    let mut interval = tokio::time::interval(POW_LOG_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut stats = POW_STATS.lock().unwrap();
+        for (nickname, stats) in stats.iter_mut() {
+            if stats.solved == 0 && stats.failed == 0 && stats.dropped == 0 {
+                continue;
+            }
+            info!(
+                "{nickname}: {} introductions with proof of work in the last {} minutes \
+                 (highest effort {}, now {}), {} invalid, {} dropped",
+                stats.solved,
+                POW_LOG_INTERVAL.as_secs() / 60,
+                stats.highest,
+                stats.effort,
+                stats.failed,
+                stats.dropped,
+            );
+            stats.solved = 0;
+            stats.highest = 0;
+            stats.failed = 0;
+            stats.dropped = 0;
+        }
+    }
+}
+
+/// The metrics of arti's proof-of-work code we keep track of.
+#[derive(Debug, Clone, Copy)]
+enum PowMetric {
+    /// `arti_hss_pow_rendrequest_effort_hist`: a proof was verified.
+    Effort,
+    /// `arti_hss_pow_rendrequest_enqueued_total`: an introduction was queued.
+    Enqueued,
+    /// `arti_hss_pow_rendrequest_verification_failure_total`.
+    Failed,
+    /// `arti_hss_pow_rend_queue_overflow_total`: the queue was full.
+    Dropped,
+}
+
+impl PowMetric {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "arti_hss_pow_rendrequest_effort_hist" => Some(PowMetric::Effort),
+            "arti_hss_pow_rendrequest_enqueued_total" => Some(PowMetric::Enqueued),
+            "arti_hss_pow_rendrequest_verification_failure_total" => Some(PowMetric::Failed),
+            "arti_hss_pow_rend_queue_overflow_total" => Some(PowMetric::Dropped),
+            _ => None,
+        }
+    }
+}
+
+/// One [`PowMetric`] of one service.
+struct PowHandle {
+    metric: PowMetric,
+    nickname: String,
+}
+
+impl PowHandle {
+    fn update(&self, value: u64) {
+        let mut stats = POW_STATS.lock().unwrap();
+        let stats = stats.entry(self.nickname.clone()).or_default();
+        match self.metric {
+            PowMetric::Effort => {
+                let effort = u32::try_from(value).unwrap_or(u32::MAX);
+                stats.pending_effort = Some(effort);
+                stats.solved += 1;
+                stats.highest = stats.highest.max(effort);
+            }
+            // arti handles one introduction at a time, so the proof
+            // verified last belongs to the introduction queued now.
+            PowMetric::Enqueued => stats.effort = stats.pending_effort.take().unwrap_or(0),
+            PowMetric::Failed => stats.failed += value,
+            PowMetric::Dropped => stats.dropped += value,
+        }
+    }
+}
+
+impl CounterFn for PowHandle {
+    fn increment(&self, value: u64) {
+        self.update(value);
+    }
+
+    fn absolute(&self, _value: u64) {}
+}
+
+impl HistogramFn for PowHandle {
+    fn record(&self, value: f64) {
+        self.update(value as u64);
+    }
+}
+
+/// Hands out [`PowHandle`]s for the metrics we track and no-ops for the rest.
+struct PowRecorder;
+
+impl PowRecorder {
+    fn handle(key: &Key) -> Option<Arc<PowHandle>> {
+        let metric = PowMetric::from_name(key.name())?;
+        let nickname = key.labels().find(|label| label.key() == "nickname")?;
+        Some(Arc::new(PowHandle {
+            metric,
+            nickname: nickname.value().to_owned(),
+        }))
+    }
+}
+
+impl Recorder for PowRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Self::handle(key).map_or_else(Counter::noop, Counter::from_arc)
+    }
+
+    fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        Self::handle(key).map_or_else(Histogram::noop, Histogram::from_arc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rate_limits() {
+        let limit: RateLimit = "10/20".parse().unwrap();
+        assert_eq!(
+            limit,
+            RateLimit {
+                rate: 10,
+                burst: 20
+            }
+        );
+        assert_eq!(limit.to_string(), "10/20");
+        assert!("5/5".parse::<RateLimit>().is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_rate_limits() {
+        for s in [
+            "10",
+            "x/20",
+            "10/",
+            "-1/20",
+            "0/20",
+            "10/5",
+            "2147483648/2147483648",
+        ] {
+            assert!(s.parse::<RateLimit>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 2, burst: 4 });
+        let start = bucket.refilled;
+        for _ in 0..4 {
+            assert_eq!(bucket.take(start), Ok(()));
+        }
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn token_bucket_refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 2, burst: 4 });
+        let start = bucket.refilled;
+        for _ in 0..4 {
+            bucket.take(start).unwrap();
+        }
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(later), Err(Duration::from_millis(250)));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(later), Ok(()));
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn token_bucket_holds_at_most_a_burst() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 2, burst: 4 });
+        let later = bucket.refilled + Duration::from_secs(3600);
+        for _ in 0..4 {
+            assert_eq!(bucket.take(later), Ok(()));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    fn limiter(max_sessions: usize, max_sessions_per_circuit: usize) -> Arc<SessionLimiter> {
+        SessionLimiter::new(&DosSettings {
+            max_sessions,
+            max_sessions_per_circuit,
+            when_limited: WhenLimited::Queue,
+            ..DosSettings::default()
+        })
+    }
+
+    #[test]
+    fn rejects_at_the_session_limit() {
+        let limiter = limiter(2, 0);
+        let first = limiter.try_admit(1).unwrap();
+        let _second = limiter.try_admit(2).unwrap();
+        assert_eq!(limiter.try_admit(3).err(), Some(Limited::Sessions));
+
+        drop(first);
+        assert!(limiter.try_admit(3).is_ok());
+    }
+
+    #[test]
+    fn rejects_at_the_per_circuit_limit() {
+        let limiter = limiter(0, 2);
+        let first = limiter.try_admit(1).unwrap();
+        let _second = limiter.try_admit(1).unwrap();
+        assert_eq!(
+            limiter.try_admit(1).err(),
+            Some(Limited::SessionsPerCircuit)
+        );
+        let _other = limiter.try_admit(2).unwrap();
+
+        drop(first);
+        let _third = limiter.try_admit(1).unwrap();
+        let sessions = limiter.state.lock().unwrap().sessions;
+        assert_eq!(sessions, 3);
+    }
+
+    #[test]
+    fn forgets_circuits_without_sessions() {
+        let limiter = limiter(0, 1);
+        drop(limiter.try_admit(7).unwrap());
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.sessions, 0);
+        assert!(state.per_circuit.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wakes_a_queued_stream_when_a_slot_frees() {
+        let limiter = limiter(1, 0);
+        let permit = limiter.try_admit(1).unwrap();
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.admit(2).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(!queued.is_finished());
+
+        drop(permit);
+        assert_eq!(queued.await.unwrap(), Ok(()));
+        let queued = limiter.state.lock().unwrap().queued;
+        assert_eq!(queued, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_queued_streams_after_the_timeout() {
+        let limiter = limiter(1, 0);
+        let _permit = limiter.try_admit(1).unwrap();
+        let started = tokio::time::Instant::now();
+        assert_eq!(limiter.admit(2).await.err(), Some(Limited::QueueTimeout));
+        assert_eq!(started.elapsed(), QUEUE_TIMEOUT);
+        let queued = limiter.state.lock().unwrap().queued;
+        assert_eq!(queued, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_to_queue_too_many_streams() {
+        let limiter = limiter(1, 0);
+        let _permit = limiter.try_admit(1).unwrap();
+        let tasks: Vec<_> = (0..MAX_QUEUED_STREAMS)
+            .map(|i| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.admit(i as u64).await.map(|_| ()) })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let queued = limiter.state.lock().unwrap().queued;
+        assert_eq!(queued, MAX_QUEUED_STREAMS);
+        assert_eq!(limiter.admit(0).await.err(), Some(Limited::QueueFull));
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Err(Limited::QueueTimeout));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! The egress allowlist of the server: which addresses clients may have it
+//! connect to with `-L` and `-D` (see [`crate::tunnel`]).
+//!
+//! Each `--allow-egress` (or `allow_egress` in a `[[service]]`) entry is a
+//! `CIDR[:PORTS]` rule. A destination is allowed if any rule matches it; an
+//! empty list allows nothing.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// One `--allow-egress` entry: a network in CIDR notation plus an optional
+/// port range the server may connect to on behalf of a client.
+///
+/// Parsed from `<cidr>[:<ports>]`, where `<ports>` is a single port or a
+/// `low-high` range and defaults to all ports. IPv6 networks that carry a
+/// port range must be bracketed. Examples: `10.0.0.0/8`, `127.0.0.1:9090`,
+/// `192.168.1.0/24:80-443`, `[fd00::/8]:22`, `0.0.0.0/0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EgressRule {
+    network: IpAddr,
+    prefix_len: u8,
+    ports: RangeInclusive<u16>,
+}
+
+impl EgressRule {
+    /// Whether this rule allows connecting to `addr`.
+    pub(crate) fn allows(&self, addr: &SocketAddr) -> bool {
+        self.ports.contains(&addr.port()) && self.contains(addr.ip())
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4-mapped IPv6 addresses like the IPv4 address they carry.
+        let ip = ip.to_canonical();
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_ports(ports: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port {p:?}"));
+    match ports.split_once('-') {
+        Some((low, high)) => {
+            let (low, high) = (parse(low)?, parse(high)?);
+            if low > high {
+                return Err(format!("empty port range {ports:?}"));
+            }
+            Ok(low..=high)
+        }
+        None => {
+            let port = parse(ports)?;
+            Ok(port..=port)
+        }
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cidr, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (cidr, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("missing ']' in {s:?}"))?;
+            match rest.strip_prefix(':') {
+                Some(ports) => (cidr, Some(ports)),
+                None if rest.is_empty() => (cidr, None),
+                None => return Err(format!("unexpected {rest:?} after ']'")),
+            }
+        } else if s.matches(':').count() > 1 {
+            // A bare IPv6 network cannot carry a port range.
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((cidr, ports)) => (cidr, Some(ports)),
+                None => (s, None),
+            }
+        };
+
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (cidr, None),
+        };
+        let network = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid IP address {addr:?}"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length {len:?}"))?,
+            None => max_len,
+        };
+        let ports = match ports {
+            Some(ports) => parse_ports(ports)?,
+            None => 0..=u16::MAX,
+        };
+
+        Ok(EgressRule {
+            network,
+            prefix_len,
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let net = format!("{}/{}", self.network, self.prefix_len);
+        let ports = match (*self.ports.start(), *self.ports.end()) {
+            (0, u16::MAX) => return write!(f, "{net}"),
+            (low, high) if low == high => format!("{low}"),
+            (low, high) => format!("{low}-{high}"),
+        };
+        if self.network.is_ipv6() {
+            write!(f, "[{net}]:{ports}")
+        } else {
+            write!(f, "{net}:{ports}")
+        }
+    }
+}
+
+/// Whether any of `rules` allows connecting to `addr`.
+pub(crate) fn is_allowed(rules: &[EgressRule], addr: &SocketAddr) -> bool {
+    rules.iter().any(|rule| rule.allows(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> EgressRule {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_ports() {
+        assert_eq!(rule("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(rule("127.0.0.1:9090").to_string(), "127.0.0.1/32:9090");
+        assert_eq!(
+            rule("192.168.1.0/24:80-443").to_string(),
+            "192.168.1.0/24:80-443"
+        );
+        assert_eq!(rule("[fd00::/8]:22").to_string(), "[fd00::/8]:22");
+        assert_eq!(rule("[::1]").to_string(), "::1/128");
+        assert_eq!(rule("fd00::/8").to_string(), "fd00::/8");
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "example.com",
+            "10.0.0.1:",
+            "10.0.0.1:70000",
+            "10.0.0.1:443-80",
+            "[fd00::/8",
+            "[fd00::/8]22",
+        ] {
+            assert!(s.parse::<EgressRule>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn matches_network_and_port_range() {
+        let rule = rule("192.168.1.0/24:80-443");
+        assert!(rule.allows(&addr("192.168.1.7:80")));
+        assert!(rule.allows(&addr("192.168.1.255:443")));
+        assert!(!rule.allows(&addr("192.168.1.7:444")));
+        assert!(!rule.allows(&addr("192.168.2.7:80")));
+    }
+
+    #[test]
+    fn matches_whole_address_space() {
+        assert!(rule("0.0.0.0/0").allows(&addr("203.0.113.9:1")));
+        assert!(rule("::/0").allows(&addr("[2001:db8::1]:65535")));
+        assert!(!rule("0.0.0.0/0").allows(&addr("[2001:db8::1]:22")));
+    }
+
+    #[test]
+    fn matches_ipv6_networks() {
+        let rule = rule("[fd00::/8]:22");
+        assert!(rule.allows(&addr("[fd12:3456::1]:22")));
+        assert!(!rule.allows(&addr("[fe80::1]:22")));
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_addresses_as_ipv4() {
+        assert!(rule("127.0.0.1").allows(&addr("[::ffff:127.0.0.1]:22")));
+    }
+
+    #[test]
+    fn allows_if_any_rule_does() {
+        let rules = [rule("10.0.0.0/8:22"), rule("127.0.0.1")];
+        assert!(is_allowed(&rules, &addr("10.1.2.3:22")));
+        assert!(is_allowed(&rules, &addr("127.0.0.1:5432")));
+        assert!(!is_allowed(&rules, &addr("10.1.2.3:80")));
+        assert!(!is_allowed(&[], &addr("127.0.0.1:22")));
+    }
+}
@@ -0,0 +1,293 @@
+use log::{debug, error};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::audit::{self, AuditEvent};
+use crate::sessions::SESSIONS;
+
+/// Where a forwarded onion-service port is connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ForwardTarget {
+    /// A TCP `host:port`; the host is resolved at connection time.
+    Inet(String),
+    /// A Unix-domain stream socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardTarget::Inet(addr) => write!(f, "{addr}"),
+            ForwardTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Maps one virtual port of the onion service to a local target.
+///
+/// Parsed from `<onion-port>:<host>:<port>` or `<onion-port>:unix:<path>`,
+/// e.g. `22:127.0.0.1:22`, `80:[::1]:8080` or `5432:unix:/run/postgresql/.s.PGSQL.5432`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ForwardRule {
+    pub(crate) onion_port: u16,
+    pub(crate) target: ForwardTarget,
+}
+
+impl FromStr for ForwardRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, target) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <onion-port>:<target>, got {s:?}"))?;
+        let onion_port = port
+            .parse::<u16>()
+            .ok()
+            .filter(|p| *p != 0)
+            .ok_or_else(|| format!("invalid onion port {port:?}"))?;
+
+        let target = if let Some(path) = target.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("empty Unix socket path".into());
+            }
+            ForwardTarget::Unix(PathBuf::from(path))
+        } else {
+            let (host, port) = target
+                .rsplit_once(':')
+                .ok_or_else(|| format!("expected <host>:<port>, got {target:?}"))?;
+            if host.is_empty() {
+                return Err(format!("missing host in {target:?}"));
+            }
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid target port {port:?}"))?;
+            ForwardTarget::Inet(target.to_owned())
+        };
+
+        Ok(ForwardRule { onion_port, target })
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.onion_port, self.target)
+    }
+}
+
+/// Connects an accepted onion-service stream to the rule's local target and
+/// copies bytes in both directions until either side closes.
+pub(crate) async fn handle_forward_connection<S>(
+    stream: S,
+    target: ForwardTarget,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let target_name = target.to_string();
+    debug!("Forwarding port {port} to {target_name}");
+
+    let connected = match &target {
+        ForwardTarget::Inet(addr) => match tokio::net::TcpStream::connect(addr.as_str()).await {
+            Ok(local) => {
+                splice(stream, local, &service, circuit, port, &target_name).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        #[cfg(unix)]
+        ForwardTarget::Unix(path) => match tokio::net::UnixStream::connect(path).await {
+            Ok(local) => {
+                splice(stream, local, &service, circuit, port, &target_name).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        #[cfg(not(unix))]
+        ForwardTarget::Unix(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+    };
+
+    if let Err(e) = connected {
+        error!("Failed to connect to forward target {target_name}: {e}");
+    }
+}
+
+/// Copies bytes between the onion-service stream and the local socket,
+/// recording the session in the audit log.
+pub(crate) async fn splice<S, L>(
+    stream: S,
+    mut local: L,
+    service: &str,
+    circuit: u64,
+    port: u16,
+    target: &str,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    L: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let handle = SESSIONS.register(service, circuit, port, "forward", target);
+    let session = handle.id();
+    let started = Instant::now();
+    audit::record(AuditEvent::ForwardStart {
+        service,
+        circuit,
+        session,
+        port,
+        target,
+    });
+
+    // Counted as they pass, so a session that is killed or fails still
+    // reports what it transferred.
+    let mut stream = Counted::new(stream);
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut stream, &mut local) => {
+            if let Err(e) = result {
+                debug!("Forward to {target} ended with error: {e}");
+            }
+        }
+        () = handle.cancelled() => debug!("Forward session {session} killed"),
+    }
+
+    audit::record(AuditEvent::SessionEnd {
+        service,
+        circuit,
+        session,
+        duration_secs: started.elapsed().as_secs_f64(),
+        bytes_in: stream.read,
+        bytes_out: stream.written,
+        exit_code: None,
+        signal: None,
+    });
+    debug!("Forward to {target} closed");
+}
+
+/// A stream that counts the bytes read from and written to it.
+struct Counted<S> {
+    inner: S,
+    read: u64,
+    written: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Counted {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.written += n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn rule(s: &str) -> Result<ForwardRule, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_tcp_targets() {
+        assert_eq!(
+            rule("22:127.0.0.1:22"),
+            Ok(ForwardRule {
+                onion_port: 22,
+                target: ForwardTarget::Inet("127.0.0.1:22".into()),
+            })
+        );
+        assert_eq!(
+            rule("80:[::1]:8080").unwrap().target,
+            ForwardTarget::Inet("[::1]:8080".into())
+        );
+        assert_eq!(
+            rule("80:localhost:8080").unwrap().target.to_string(),
+            "localhost:8080"
+        );
+    }
+
+    #[test]
+    fn parses_unix_targets() {
+        let rule = rule("5432:unix:/run/postgresql/.s.PGSQL.5432").unwrap();
+        assert_eq!(rule.onion_port, 5432);
+        assert_eq!(
+            rule.target,
+            ForwardTarget::Unix("/run/postgresql/.s.PGSQL.5432".into())
+        );
+        assert_eq!(
+            rule.target.to_string(),
+            "unix:/run/postgresql/.s.PGSQL.5432"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for s in [
+            "22",
+            "0:127.0.0.1:22",
+            "x:127.0.0.1:22",
+            "22:127.0.0.1",
+            "22::22",
+            "22:127.0.0.1:70000",
+            "22:unix:",
+        ] {
+            assert!(rule(s).is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_each_way() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut counted = Counted::new(ours);
+        theirs.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        counted.read_exact(&mut buf).await.unwrap();
+        counted.write_all(b"abc").await.unwrap();
+        assert_eq!((counted.read, counted.written), (5, 3));
+    }
+}
@@ -0,0 +1,209 @@
+//! Logging straight to the systemd journal, so that entries keep their
+//! priority, origin and fields instead of arriving as plain text lines.
+//!
+//! Only used when systemd connected our output to the journal (see
+//! [`output_is_journal`]); everywhere else logs are formatted as usual.
+
+use std::fmt::Debug;
+use std::os::unix::net::UnixDatagram;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+/// The socket journald receives native protocol datagrams on.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Whether stdout or stderr is a stream to the journal, as announced by
+/// systemd in `$JOURNAL_STREAM` (`DEVICE:INODE`).
+pub(crate) fn output_is_journal() -> bool {
+    let Some(stream) = std::env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((device, inode)) = stream.to_str().and_then(|stream| stream.split_once(':')) else {
+        return false;
+    };
+    let (Ok(device), Ok(inode)) = (device.parse::<u64>(), inode.parse::<u64>()) else {
+        return false;
+    };
+    [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .any(|fd| {
+            // SAFETY: `stat` is plain old data, for which all zeroes is a
+            // valid value.
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            // SAFETY: fstat only writes to `stat`, and fails cleanly if `fd`
+            // is closed.
+            let found = unsafe { libc::fstat(fd, &mut stat) } == 0;
+            found && stat.st_dev as u64 == device && stat.st_ino as u64 == inode
+        })
+}
+
+/// A tracing layer sending every event to journald.
+pub(crate) struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+impl JournaldLayer {
+    pub(crate) fn connect() -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(JournaldLayer { socket })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        let mut entry = Vec::new();
+        put_field(&mut entry, "PRIORITY", priority(metadata.level()));
+        put_field(&mut entry, "SYSLOG_IDENTIFIER", "backtor");
+        put_field(&mut entry, "MESSAGE", &fields.message);
+        // Events bridged from the `log` crate carry their origin as fields.
+        put_field(
+            &mut entry,
+            "TARGET",
+            fields.target.as_deref().unwrap_or(metadata.target()),
+        );
+        if let Some(file) = fields.file.as_deref().or(metadata.file()) {
+            put_field(&mut entry, "CODE_FILE", file);
+        }
+        if let Some(line) = fields.line.or(metadata.line().map(u64::from)) {
+            put_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        for (name, value) in &fields.other {
+            put_field(&mut entry, name, value);
+        }
+        // Nowhere to report a failure to log.
+        let _ = self.socket.send(&entry);
+    }
+}
+
+/// The syslog priority of a tracing level.
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Appends one field in journald's native format; values containing a
+/// newline use the length-prefixed form.
+fn put_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Turns a tracing field name into a valid journal field name: uppercase
+/// letters, digits and underscores, not starting with an underscore (those
+/// are reserved for journald) or a digit.
+fn journal_field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches('_');
+    match name.chars().next() {
+        None => "FIELD".to_owned(),
+        Some('0'..='9') => format!("F_{name}"),
+        Some(_) => name.to_owned(),
+    }
+}
+
+/// The fields of one event.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    target: Option<String>,
+    file: Option<String>,
+    line: Option<u64>,
+    other: Vec<(String, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_owned(),
+            "log.target" => self.target = Some(value.to_owned()),
+            "log.file" => self.file = Some(value.to_owned()),
+            "log.module_path" => {}
+            name => self
+                .other
+                .push((journal_field_name(name), value.to_owned())),
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "log.line" => self.line = Some(value),
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            "log.target" | "log.file" | "log.module_path" | "log.line" => {}
+            name => self
+                .other
+                .push((journal_field_name(name), format!("{value:?}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puts_single_line_values_after_an_equals_sign() {
+        let mut entry = Vec::new();
+        put_field(&mut entry, "MESSAGE", "hello");
+        put_field(&mut entry, "PRIORITY", "6");
+        assert_eq!(entry, b"MESSAGE=hello\nPRIORITY=6\n");
+    }
+
+    #[test]
+    fn puts_multi_line_values_with_their_length() {
+        let mut entry = Vec::new();
+        put_field(&mut entry, "MESSAGE", "one\ntwo");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.extend_from_slice(b"one\ntwo\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn field_names_are_valid_journal_fields() {
+        assert_eq!(journal_field_name("service"), "SERVICE");
+        assert_eq!(journal_field_name("log.peer"), "LOG_PEER");
+        assert_eq!(journal_field_name("_reserved"), "RESERVED");
+        assert_eq!(journal_field_name("2fa"), "F_2FA");
+        assert_eq!(journal_field_name(""), "FIELD");
+        assert_eq!(journal_field_name("__"), "FIELD");
+    }
+
+    #[test]
+    fn levels_map_to_syslog_priorities() {
+        assert_eq!(priority(&Level::ERROR), "3");
+        assert_eq!(priority(&Level::WARN), "4");
+        assert_eq!(priority(&Level::INFO), "6");
+        assert_eq!(priority(&Level::DEBUG), "7");
+        assert_eq!(priority(&Level::TRACE), "7");
+    }
+}
@@ -0,0 +1,206 @@
+//! `backtor key`: moves the identity keys of onion services without a `key`
+//! of their own, which the Tor keystore holds, between machines.
+//!
+//! Keys are exported in C Tor's `hs_ed25519_secret_key` format, so they can
+//! also be taken over from, or handed to, a C Tor onion service.
+
+use anyhow::{Context, Error, bail};
+use arti_client::config::TorClientConfig;
+use std::io::{Read, Write};
+use std::path::Path;
+use tor_hscrypto::pk::HsIdKeypair;
+use tor_hsservice::HsIdKeypairSpecifier;
+use tor_keymgr::config::ArtiKeystoreKind;
+use tor_keymgr::{ArtiNativeKeystore, KeyMgr, KeyMgrBuilder, KeystoreSelector};
+use tor_llcrypto::pk::ed25519::ExpandedKeypair;
+
+use crate::config::keystore_nickname;
+use crate::dirs::TorDirectories;
+use crate::utils::get_onion_address;
+
+/// What `hs_ed25519_secret_key` files start with, before the 64-byte
+/// expanded secret key.
+const CTOR_SECRET_KEY_HEADER: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+
+/// The path that means stdin or stdout.
+const STDIO: &str = "-";
+
+/// Writes the stored key of `service` to `file` (`-` for stdout).
+pub(crate) fn export(
+    config: &TorClientConfig,
+    directories: &TorDirectories,
+    service: &str,
+    file: &Path,
+) -> Result<(), Error> {
+    let keymgr = open_keystore(config, directories)?;
+    let spec = HsIdKeypairSpecifier::new(keystore_nickname(service)?);
+    let Some(keypair) = keymgr.get::<HsIdKeypair>(&spec)? else {
+        bail!(
+            "Service {service:?} has no stored key in {}; it is created when the service \
+             first starts",
+            directories.state.display()
+        );
+    };
+    let keypair: &ExpandedKeypair = keypair.as_ref();
+
+    let contents = encode_secret_key(keypair);
+    if file == Path::new(STDIO) {
+        std::io::stdout().write_all(&contents)?;
+    } else {
+        write_private(file, &contents)
+            .with_context(|| format!("cannot write {}", file.display()))?;
+    }
+    eprintln!(
+        "Exported the key of {}.onion (service {service:?})",
+        get_onion_address(keypair.public().as_bytes())
+    );
+    Ok(())
+}
+
+/// Stores the key in `file` (`-` for stdin) as the key of `service`,
+/// replacing an existing one only if `force` is set.
+pub(crate) fn import(
+    config: &TorClientConfig,
+    directories: &TorDirectories,
+    service: &str,
+    file: &Path,
+    force: bool,
+) -> Result<(), Error> {
+    let mut contents = Vec::new();
+    if file == Path::new(STDIO) {
+        std::io::stdin().read_to_end(&mut contents)?;
+    } else {
+        contents =
+            std::fs::read(file).with_context(|| format!("cannot read {}", file.display()))?;
+    }
+    let Some(keypair) = decode_secret_key(&contents) else {
+        bail!(
+            "{} is not an onion service key (hs_ed25519_secret_key) as written by \
+             backtor key export or C Tor",
+            file.display()
+        );
+    };
+    let address = get_onion_address(keypair.public().as_bytes());
+
+    let keymgr = open_keystore(config, directories)?;
+    let spec = HsIdKeypairSpecifier::new(keystore_nickname(service)?);
+    if !force && let Some(existing) = keymgr.get::<HsIdKeypair>(&spec)? {
+        let existing: &ExpandedKeypair = existing.as_ref();
+        let existing = get_onion_address(existing.public().as_bytes());
+        if existing == address {
+            eprintln!("Service {service:?} already has the key of {address}.onion");
+            return Ok(());
+        }
+        bail!(
+            "Service {service:?} already has a key, for {existing}.onion; pass --force to \
+             replace it, which gives up that address unless you exported its key"
+        );
+    }
+    keymgr.insert(
+        HsIdKeypair::from(keypair),
+        &spec,
+        KeystoreSelector::Primary,
+        true,
+    )?;
+    eprintln!(
+        "Imported the key of {address}.onion as service {service:?}. A running server uses \
+         it once the service restarts (backtor ctl stop {service}; backtor ctl start {service})."
+    );
+    Ok(())
+}
+
+/// The contents of a C Tor `hs_ed25519_secret_key` file holding `keypair`.
+fn encode_secret_key(keypair: &ExpandedKeypair) -> Vec<u8> {
+    let mut contents = CTOR_SECRET_KEY_HEADER.to_vec();
+    contents.extend_from_slice(&keypair.to_secret_key_bytes());
+    contents
+}
+
+/// The key in the contents of an `hs_ed25519_secret_key` file, if it is one.
+fn decode_secret_key(contents: &[u8]) -> Option<ExpandedKeypair> {
+    let secret = contents.strip_prefix(CTOR_SECRET_KEY_HEADER)?;
+    <[u8; 64]>::try_from(secret)
+        .ok()
+        .and_then(ExpandedKeypair::from_secret_key_bytes)
+}
+
+/// Writes `contents` to `file`, readable only by us. An existing file is
+/// made private before the secret goes in, whatever its mode was.
+fn write_private(file: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = options.open(file)?;
+    #[cfg(unix)]
+    out.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    out.write_all(contents)
+}
+
+/// The on-disk keystore `backtor serve` would use with this configuration.
+fn open_keystore(config: &TorClientConfig, directories: &TorDirectories) -> Result<KeyMgr, Error> {
+    if config.keystore().primary_kind() != Some(ArtiKeystoreKind::Native) {
+        bail!("The Tor configuration does not keep keys on disk ([tor.storage.keystore])");
+    }
+    let dir = directories.state.join("keystore");
+    let store = ArtiNativeKeystore::from_path_and_mistrust(&dir, config.fs_mistrust())
+        .with_context(|| format!("cannot open the keystore in {}", dir.display()))
+        .with_context(|| directories.permission_hint())?;
+    Ok(KeyMgrBuilder::default()
+        .primary_store(Box::new(store))
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> ExpandedKeypair {
+        ExpandedKeypair::from_secret_key_bytes([7; 64]).unwrap()
+    }
+
+    #[test]
+    fn secret_keys_round_trip() {
+        let contents = encode_secret_key(&keypair());
+        assert_eq!(contents.len(), 96);
+        assert!(contents.starts_with(b"== ed25519v1-secret: type0 =="));
+        let decoded = decode_secret_key(&contents).unwrap();
+        assert_eq!(decoded.public(), keypair().public());
+        assert_eq!(decoded.to_secret_key_bytes(), [7; 64]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let contents = encode_secret_key(&keypair());
+        let mut public = contents.clone();
+        public[..32].copy_from_slice(b"== ed25519v1-public: type0 ==\0\0\0");
+        for bad in [
+            &public[..],
+            &contents[..95],
+            &[contents.as_slice(), b"\n"].concat(),
+            &contents[32..],
+            b"",
+        ] {
+            assert!(
+                decode_secret_key(bad).is_none(),
+                "{} bytes decoded",
+                bad.len()
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exported_keys_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let file = std::env::temp_dir().join(format!("backtor-{}-key", std::process::id()));
+        std::fs::write(&file, b"old").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&file, b"secret").unwrap();
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read(&file).unwrap(), b"secret");
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -1,23 +1,90 @@
+#[cfg(feature = "server")]
+mod audit;
+mod bridges;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(all(feature = "server", unix))]
+mod control;
+mod dirs;
+#[cfg(feature = "server")]
+mod dos;
+#[cfg(feature = "server")]
+mod egress;
+#[cfg(feature = "server")]
+mod forward;
+#[cfg(unix)]
+mod journald;
+#[cfg(feature = "server")]
+mod keys;
 #[cfg(feature = "client")]
 mod onion_client;
 #[cfg(feature = "server")]
 mod onion_server;
+#[cfg(feature = "client")]
+mod predict;
+#[cfg(feature = "server")]
+mod screen_sync;
+#[cfg(feature = "server")]
+mod sessions;
+mod settings;
+#[cfg(feature = "server")]
+mod sftp;
+mod shell_protocol;
+// The server only uses the parts needed to relay bridge connections.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+mod socks;
+#[cfg(feature = "client")]
+mod socks_proxy;
+#[cfg(all(feature = "server", unix))]
+mod systemd;
+#[cfg(test)]
+mod test_support;
+mod transfer;
+mod tunnel;
 mod utils;
 
-use anyhow::Result;
-use arti_client::{TorClient, config::TorClientConfigBuilder};
+use anyhow::{Context, Result};
+use arti_client::{ErrorKind, HasKind, TorClient};
+#[cfg(feature = "server")]
+use clap::Args;
 use clap::{Parser, Subcommand};
-use log::debug;
+#[cfg(feature = "server")]
+use config::{ServerConfig, ServiceConfig};
+#[cfg(all(feature = "server", unix))]
+use control::{ControlRequest, Controller};
+use dirs::TorDirectories;
+#[cfg(feature = "server")]
+use dos::{RateLimit, WhenLimited};
+#[cfg(feature = "server")]
+use egress::EgressRule;
+#[cfg(feature = "server")]
+use forward::ForwardRule;
+#[cfg(feature = "server")]
+use log::error;
+use log::{debug, info};
 #[cfg(feature = "client")]
 use onion_client::OnionShellClient;
 #[cfg(feature = "server")]
-use onion_server::onion_service_from_sk;
+use onion_server::{ServiceSpec, ShellPort, onion_service_from_sk};
+use settings::{LogSettings, Settings};
+#[cfg(feature = "client")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
+#[cfg(all(feature = "server", unix))]
+use std::sync::Arc;
+use std::time::Duration;
 use tor_rtcompat::PreferredRuntime;
 use tracing_subscriber::{
     filter::{EnvFilter, LevelFilter},
     fmt,
     prelude::*,
 };
+#[cfg(feature = "client")]
+use transfer::CopyLocation;
+#[cfg(feature = "client")]
+use tunnel::{DynamicForward, LocalForward, RemoteForward};
+#[cfg(feature = "client")]
+use utils::{DEFAULT_SHELL_PORT, split_host_port};
 
 /// backtor – a Tor-native remote shell.
 ///
@@ -29,6 +96,23 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count, help = "Increase verbosity level")]
     verbose: u8,
 
+    /// Read this configuration file after `/etc/backtor/config.toml` and
+    /// `~/.config/backtor/config.toml`, overriding their settings. See the
+    /// README for the format.
+    #[arg(short, long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// Where Tor keeps its state, including the keys of services started
+    /// without `--key` (default: `$STATE_DIRECTORY`, `/var/lib/backtor` as
+    /// root, else `$XDG_STATE_HOME/backtor`).
+    #[arg(long, value_name = "DIR", global = true)]
+    state_dir: Option<PathBuf>,
+
+    /// Where Tor caches directory information (default: `$CACHE_DIRECTORY`,
+    /// `/var/cache/backtor` as root, else `$XDG_CACHE_HOME/backtor`).
+    #[arg(long, value_name = "DIR", global = true)]
+    cache_dir: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -37,38 +121,371 @@ struct Cli {
 enum Command {
     /// Expose the local shell as a Tor onion service (default when no subcommand is given).
     #[cfg(feature = "server")]
-    Serve {
-        /// A 32-byte hex secret key used to derive a stable onion address.
-        /// If omitted a fresh ephemeral address is generated each run.
-        #[arg(short, long, value_name = "HEX")]
-        key: Option<String>,
-    },
+    Serve(Box<ServeArgs>),
 
     /// Connect to a backtor shell service.
     #[cfg(feature = "client")]
     Connect {
-        /// The onion address to connect to (with or without the .onion suffix).
+        /// The onion address to connect to (with or without the .onion
+        /// suffix), optionally followed by `:PORT` (default 23).
+        #[arg(value_name = "ADDRESS[:PORT]")]
         address: String,
+
+        /// Forward a local port through the server: connections to
+        /// `[BIND_ADDRESS:]PORT` on this machine are tunnelled to
+        /// `HOST:HOSTPORT` as seen from the server. May be given multiple
+        /// times. The server must allow the destinations with
+        /// `--allow-egress` (`allow_egress` in a `[[service]]` table).
+        #[arg(short = 'L', value_name = "[BIND_ADDRESS:]PORT:HOST:HOSTPORT")]
+        local_forwards: Vec<LocalForward>,
+
+        /// Forward a port on the server back to this machine: the server
+        /// listens on its loopback `REMOTEPORT` and connections are tunnelled
+        /// to `HOST:HOSTPORT` as seen from here. May be given multiple times.
+        /// The server must run with `--allow-remote-forwarding`.
+        #[arg(short = 'R', value_name = "REMOTEPORT:HOST:HOSTPORT")]
+        remote_forwards: Vec<RemoteForward>,
+
+        /// Run a local SOCKS5 proxy on `[BIND_ADDRESS:]PORT` whose
+        /// connections are made from the server's network position. May be
+        /// given multiple times. The server must allow the destinations with
+        /// `--allow-egress`.
+        #[arg(short = 'D', value_name = "[BIND_ADDRESS:]PORT")]
+        dynamic_forwards: Vec<DynamicForward>,
+
+        /// Tell the server every SECS seconds that the session is still
+        /// wanted, so an idle session is not closed by its `--idle-timeout`.
+        #[arg(long, value_name = "SECS")]
+        keepalive: Option<u64>,
+
+        /// Draw what you type right away, underlined until the server
+        /// echoes it, instead of a round trip later.
+        #[arg(long)]
+        predict: bool,
+
+        /// Have the server send its screen instead of its output, skipping
+        /// whatever the circuit cannot keep up with.
+        #[arg(long)]
+        sync: bool,
+    },
+
+    /// Copy files to or from a backtor server.
+    ///
+    /// Exactly one of SOURCE and DESTINATION is remote, written as
+    /// `ADDRESS:PATH`. Relative remote paths start at the server's first
+    /// transfer root. Permissions and modification times are preserved.
+    #[cfg(feature = "client")]
+    Cp {
+        /// Local path, or `ADDRESS:PATH` to download.
+        source: String,
+
+        /// Local path, or `ADDRESS:PATH` to upload to.
+        destination: String,
+
+        /// Copy directories recursively.
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Continue partially copied files instead of starting over. Only
+        /// use this when the partial copies came from the same source.
+        #[arg(long)]
+        resume: bool,
+    },
+
+    /// Relay stdin and stdout to a port of a backtor service, without
+    /// touching the terminal.
+    ///
+    /// Use it as an OpenSSH proxy, e.g.
+    /// `ssh -o ProxyCommand='backtor pipe %h:22' user@ADDRESS.onion`, or to
+    /// reach the SFTP subsystem with `sftp -D 'backtor pipe ADDRESS:115'`.
+    #[cfg(feature = "client")]
+    Pipe {
+        /// The onion address (with or without the .onion suffix) and port
+        /// to connect to.
+        #[arg(value_name = "ADDRESS:PORT", value_parser = parse_address_with_port)]
+        address: (String, u16),
+    },
+
+    /// Run a SOCKS5 proxy over the embedded Tor client, so other programs
+    /// can reach onion services without a separate Tor daemon.
+    ///
+    /// Clients using different SOCKS usernames or passwords get isolated
+    /// circuits. Tor's RESOLVE and RESOLVE_PTR extensions are supported.
+    #[cfg(feature = "client")]
+    Socks {
+        /// Address to listen on (default: 127.0.0.1:9150).
+        #[arg(long, value_name = "ADDRESS:PORT")]
+        listen: Option<SocketAddr>,
+    },
+
+    /// Manage a running `backtor serve` through its control socket.
+    #[cfg(all(feature = "server", unix))]
+    Ctl {
+        /// The server's control socket (default: `control_socket` from the
+        /// configuration, else `$XDG_RUNTIME_DIR/backtor.sock`).
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        request: ControlRequest,
+    },
+
+    /// Print an example systemd unit that runs `backtor serve` with
+    /// readiness notification, a watchdog and sandboxing.
+    #[cfg(all(feature = "server", unix))]
+    SystemdUnit {
+        /// Run the service as this user instead of root.
+        #[arg(long, value_name = "USER")]
+        user: Option<String>,
     },
+
+    /// Export or import the stored keys of services without `--key`, e.g.
+    /// to move a service's address to another machine.
+    #[cfg(feature = "server")]
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+
+    /// Inspect the configuration files.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigAction {
+    /// Validate the configuration files and print the settings in effect,
+    /// including defaults and command-line overrides.
+    Check,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Subcommand)]
+enum KeyAction {
+    /// Write a service's key to FILE (`-` for stdout), in C Tor's
+    /// `hs_ed25519_secret_key` format.
+    Export {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// The service's name in the configuration (default: the service
+        /// `backtor serve` runs from its flags).
+        #[arg(long, value_name = "NAME", default_value = config::DEFAULT_SERVICE_NAME)]
+        service: String,
+    },
+
+    /// Read a key exported by `backtor key export` or C Tor from FILE (`-`
+    /// for stdin) and give it to a service.
+    Import {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// The service's name in the configuration (default: the service
+        /// `backtor serve` runs from its flags).
+        #[arg(long, value_name = "NAME", default_value = config::DEFAULT_SERVICE_NAME)]
+        service: String,
+
+        /// Replace the service's existing key, giving up its address.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Forwards given on the command line replace the configured ones.
+#[cfg(feature = "client")]
+fn or_configured<T>(flags: Vec<T>, configured: Vec<T>) -> Vec<T> {
+    if flags.is_empty() { configured } else { flags }
+}
+
+/// Parses an `ADDRESS:PORT` argument whose port is mandatory.
+#[cfg(feature = "client")]
+fn parse_address_with_port(address: &str) -> Result<(String, u16), String> {
+    if !address.contains(':') {
+        return Err("missing port; expected ADDRESS:PORT".to_owned());
+    }
+    let (host, port) = split_host_port(address, 0).map_err(|e| e.to_string())?;
+    Ok((host.to_owned(), port))
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Default, Args)]
+struct ServeArgs {
+    /// A 32-byte hex secret key used to derive a stable onion address.
+    /// If omitted, the address is kept in the Tor state directory and
+    /// reused on every run (see `backtor key`).
+    #[arg(short, long, value_name = "HEX")]
+    key: Option<String>,
+
+    /// Keep keys in memory only: services without `--key` get a new address
+    /// on every run, which is never written to disk.
+    #[arg(long, conflicts_with = "key")]
+    ephemeral: bool,
+
+    /// Write a JSON-lines audit log of service and session events to
+    /// this file, or to the system log if set to `syslog`.
+    #[arg(long, value_name = "PATH|syslog")]
+    audit_log: Option<String>,
+
+    /// Accept `backtor ctl` commands on this Unix socket (default:
+    /// `$XDG_RUNTIME_DIR/backtor.sock`).
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// On SIGTERM or SIGINT, give sessions this many seconds to end before
+    /// killing them (default: 10). Shell users are warned.
+    #[arg(long, value_name = "SECS")]
+    shutdown_grace: Option<u64>,
+
+    /// Serve a shell on this onion-service port, optionally running a
+    /// specific command instead of the login shell. May be given multiple
+    /// times; defaults to port 23.
+    ///
+    /// The command is split on whitespace, without quoting; wrap anything
+    /// more complex in a script.
+    ///
+    /// Examples: `23`, `2222:/usr/bin/fish --private`.
+    #[arg(
+        short,
+        long = "port",
+        value_name = "PORT[:COMMAND]",
+        conflicts_with = "no_shell"
+    )]
+    ports: Vec<ShellPort>,
+
+    /// Forward an onion-service port to a local TCP or Unix-socket target,
+    /// alongside the shell. May be given multiple times.
+    ///
+    /// Examples: `22:127.0.0.1:22`, `80:localhost:8080`,
+    /// `5432:unix:/run/postgresql/.s.PGSQL.5432`.
+    #[arg(long = "forward", value_name = "ONION_PORT:HOST:PORT")]
+    forwards: Vec<ForwardRule>,
+
+    /// Do not serve a shell; only expose the `--forward` ports.
+    #[arg(long, requires = "forwards")]
+    no_shell: bool,
+
+    /// Let clients connect to destinations in this network from this
+    /// machine (`backtor connect -L` and `-D`). May be given multiple times;
+    /// without it, clients cannot connect anywhere.
+    ///
+    /// Format: `CIDR[:PORT[-PORT]]`, e.g. `127.0.0.1:9090`,
+    /// `10.0.0.0/8:80-443`, `[fd00::/8]:22` or `0.0.0.0/0` for anything.
+    #[arg(long = "allow-egress", value_name = "CIDR[:PORTS]")]
+    allow_egress: Vec<EgressRule>,
+
+    /// Let clients open loopback listeners on this machine whose connections
+    /// are tunnelled back to the client (`backtor connect -R`).
+    #[arg(long)]
+    allow_remote_forwarding: bool,
+
+    /// Accept `backtor cp` uploads and downloads inside this directory.
+    /// May be given multiple times; without it, file transfers are
+    /// disabled. Use `/` to allow the whole file system.
+    #[arg(long = "transfer-root", value_name = "DIR")]
+    transfer_roots: Vec<PathBuf>,
+
+    /// Also serve an SFTP subsystem on onion port 115, confined to the
+    /// `--transfer-root` directories.
+    #[arg(long, requires = "transfer_roots")]
+    sftp: bool,
+
+    /// Close shell sessions without input or output for this many minutes,
+    /// warning the client a minute before. Clients can keep wanted sessions
+    /// open with `backtor connect --keepalive`.
+    #[arg(long, value_name = "MINUTES")]
+    idle_timeout: Option<u64>,
+
+    /// Close shell sessions after this many minutes, warning the client a
+    /// minute before.
+    #[arg(long, value_name = "MINUTES")]
+    max_session_time: Option<u64>,
+
+    /// Never ask clients for proof of work. By default, a flooded service
+    /// makes clients solve puzzles that get harder the worse the flood, and
+    /// serves the hardest-working clients first.
+    #[arg(long)]
+    no_pow: bool,
+
+    /// How many introductions may wait for an answer while proof of work is
+    /// required; beyond that the lowest-effort ones are dropped (default:
+    /// 8192, a few KB each).
+    #[arg(long, value_name = "N", conflicts_with = "no_pow")]
+    pow_queue_depth: Option<usize>,
+
+    /// Have the introduction points pass on at most RATE introductions per
+    /// second on average and BURST at once (default: a limit chosen by the
+    /// Tor network).
+    #[arg(long, value_name = "RATE/BURST")]
+    intro_rate_limit: Option<RateLimit>,
+
+    /// Serve at most N streams at once, shells and forwards together
+    /// (default: 64; 0 for no limit).
+    #[arg(long, value_name = "N")]
+    max_sessions: Option<usize>,
+
+    /// Serve at most N streams at once to one circuit, that is one client
+    /// connection (default: 32; 0 for no limit).
+    #[arg(long, value_name = "N")]
+    max_sessions_per_circuit: Option<usize>,
+
+    /// Accept at most RATE new streams per second on average and BURST at
+    /// once (default: no limit).
+    #[arg(long, value_name = "RATE/BURST")]
+    stream_rate_limit: Option<RateLimit>,
+
+    /// What to do with streams that arrive while a limit is reached: reject
+    /// them, or queue them for up to 30 seconds.
+    #[arg(long, value_name = "ACTION")]
+    when_limited: Option<WhenLimited>,
 }
 
-fn init_logging(cli_loglevel: u8) {
-    // Start with: default=info, arti crates=error
+/// The arti crates whose levels `ARTI_LOG` and `[log] arti` set.
+const ARTI_CRATES: &[&str] = &[
+    "arti_client",
+    "tor_hsservice",
+    "tor_dirmgr",
+    "tor_guardmgr",
+    "tor_circmgr",
+];
 
-    let log_level = match cli_loglevel {
+/// backtor's log level for a `-v` count.
+fn verbosity_level(verbose: u8) -> LevelFilter {
+    match verbose {
         0 => LevelFilter::ERROR,
         1 => LevelFilter::INFO,
         2 => LevelFilter::DEBUG,
         _ => LevelFilter::TRACE,
-    };
+    }
+}
+
+/// backtor's log level: from `-v` if given, else from `[log] level`.
+fn log_level(cli_loglevel: u8, settings: &LogSettings) -> Result<LevelFilter> {
+    match &settings.level {
+        Some(level) if cli_loglevel == 0 => level
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid [log] level {level:?}")),
+        _ => Ok(verbosity_level(cli_loglevel)),
+    }
+}
 
-    let mut filter = EnvFilter::builder()
-        .parse_lossy(format!("backtor={log_level},arti_client=error,tor_hsservice=error,tor_dirmgr=error,tor_guardmgr=error,tor_circmgr=error"));
+fn init_logging(log_level: LevelFilter, settings: &LogSettings, to_stderr: bool) {
+    // Start with: default=error, arti crates=error
+    let mut filter = EnvFilter::builder().parse_lossy(format!(
+        "backtor={log_level},{}",
+        ARTI_CRATES
+            .iter()
+            .map(|krate| format!("{krate}=error"))
+            .collect::<Vec<_>>()
+            .join(",")
+    ));
 
-    // If ARTI_LOG is set, override the arti crate levels with whatever it says.
-    // e.g. ARTI_LOG=debug  → sets both arti crates to debug
+    // ARTI_LOG, or else `[log] arti`, overrides the arti crate levels.
+    // e.g. ARTI_LOG=debug  → sets all arti crates to debug
     // e.g. ARTI_LOG=arti_client=warn,tor_stuff=trace  → fine-grained control
-    if let Ok(arti_log) = std::env::var("ARTI_LOG") {
+    if let Some(arti_log) = std::env::var("ARTI_LOG").ok().or(settings.arti.clone()) {
         for directive in arti_log.split(',') {
             let directive = directive.trim();
             if directive.is_empty() {
@@ -77,12 +494,9 @@ fn init_logging(cli_loglevel: u8) {
 
             // If it's a bare level like "debug", apply it to all arti crates
             if let Ok(level) = directive.parse::<LevelFilter>() {
-                filter = filter
-                    .add_directive(format!("arti_client={level}").parse().unwrap())
-                    .add_directive(format!("tor_hsservice={level}").parse().unwrap())
-                    .add_directive(format!("tor_dirmgr={level}").parse().unwrap())
-                    .add_directive(format!("tor_guardmgr={level}").parse().unwrap())
-                    .add_directive(format!("tor_circmgr={level}").parse().unwrap());
+                for krate in ARTI_CRATES {
+                    filter = filter.add_directive(format!("{krate}={level}").parse().unwrap());
+                }
             } else {
                 // Otherwise treat it as a full directive like "arti_client=warn"
                 if let Ok(d) = directive.parse() {
@@ -92,76 +506,541 @@ fn init_logging(cli_loglevel: u8) {
         }
     }
 
-    // Also respect RUST_LOG for your own crate's level, if set.
-    // Directives added later override earlier ones for the same target,
-    // so RUST_LOG can still override everything if you want.
-    if let Ok(rust_log) = std::env::var("RUST_LOG") {
-        for directive in rust_log.split(',') {
+    // Then `[log] filter`, then RUST_LOG. Directives added later override
+    // earlier ones for the same target, so RUST_LOG can still override
+    // everything if you want.
+    let extra = [settings.filter.clone(), std::env::var("RUST_LOG").ok()];
+    for directives in extra.into_iter().flatten() {
+        for directive in directives.split(',') {
             if let Ok(d) = directive.trim().parse() {
                 filter = filter.add_directive(d);
             }
         }
     }
 
+    // Under systemd, log to the journal natively to keep levels and fields.
+    #[cfg(unix)]
+    let journald = journald::output_is_journal()
+        .then(journald::JournaldLayer::connect)
+        .and_then(Result::ok);
+    #[cfg(not(unix))]
+    let journald: Option<fmt::Layer<_>> = None;
+
+    let layer = if let Some(journald) = journald {
+        journald.boxed()
+    } else if to_stderr {
+        fmt::layer().with_writer(std::io::stderr).boxed()
+    } else {
+        fmt::layer().boxed()
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(layer)
         .with(filter)
         .init();
 }
 
+/// Seconds sessions get to end when the server shuts down.
+#[cfg(feature = "server")]
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+
+/// Where `backtor socks` listens by default.
+#[cfg(feature = "client")]
+const DEFAULT_SOCKS_LISTEN: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+    std::net::Ipv4Addr::LOCALHOST,
+    9150,
+));
+
+/// `backtor config check`: validates the configuration as the commands using
+/// it would, then prints the merged files with defaults and command-line
+/// overrides filled in.
+fn check_config(
+    mut settings: Settings,
+    directories: &TorDirectories,
+    log_level: LevelFilter,
+) -> Result<()> {
+    settings.tor_config(directories, false)?;
+
+    let mut effective = std::mem::take(&mut settings.merged);
+    effective.insert(
+        "state_dir".into(),
+        directories.state.display().to_string().into(),
+    );
+    effective.insert(
+        "cache_dir".into(),
+        directories.cache.display().to_string().into(),
+    );
+    section(&mut effective, "log")
+        .insert("level".into(), log_level.to_string().to_lowercase().into());
+    #[cfg(feature = "client")]
+    {
+        let port = settings.connect.port.unwrap_or(DEFAULT_SHELL_PORT);
+        section(&mut effective, "connect").insert("port".into(), i64::from(port).into());
+        let listen = settings.socks.listen.unwrap_or(DEFAULT_SOCKS_LISTEN);
+        section(&mut effective, "socks").insert("listen".into(), listen.to_string().into());
+    }
+    #[cfg(feature = "server")]
+    {
+        let server = settings.server;
+        effective.insert("ephemeral".into(), server.ephemeral.into());
+        let grace = server.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        effective.insert("shutdown_grace".into(), (grace as i64).into());
+        #[cfg(unix)]
+        {
+            let socket = server
+                .control_socket
+                .clone()
+                .unwrap_or_else(control::default_socket_path);
+            effective.insert("control_socket".into(), socket.display().to_string().into());
+        }
+        let pty = section(&mut effective, "pty");
+        pty.insert("rows".into(), i64::from(server.pty.rows).into());
+        pty.insert("cols".into(), i64::from(server.pty.cols).into());
+        server.into_specs()?;
+    }
+
+    if settings.sources.is_empty() {
+        println!("# No configuration files found; showing the defaults.");
+    }
+    for source in &settings.sources {
+        println!("# Read {}", source.display());
+    }
+    print!("{}", toml::to_string_pretty(&effective)?);
+    Ok(())
+}
+
+/// The table `name` of `table`, created if missing.
+fn section<'a>(table: &'a mut toml::Table, name: &str) -> &'a mut toml::Table {
+    let value = table
+        .entry(name)
+        .or_insert_with(|| toml::Table::new().into());
+    if !value.is_table() {
+        *value = toml::Table::new().into();
+    }
+    value.as_table_mut().expect("just made a table")
+}
+
+/// What `serve` runs, validated before Tor is bootstrapped.
+#[cfg(feature = "server")]
+struct Server {
+    services: Vec<ServiceSpec>,
+    /// Whether the services came from the configuration files, which
+    /// `backtor ctl reload` then re-reads.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    from_config: bool,
+    /// Whether keys are kept in memory only.
+    ephemeral: bool,
+    #[cfg(unix)]
+    control_socket: PathBuf,
+    shutdown_grace: Duration,
+}
+
+/// Builds the services `serve` runs, from the `[[service]]` sections of the
+/// configuration or else from its flags, and sets up the audit log.
+#[cfg(feature = "server")]
+fn load_server(args: ServeArgs, mut config: ServerConfig) -> Result<Server> {
+    let ServeArgs {
+        key,
+        ephemeral,
+        audit_log,
+        #[cfg(unix)]
+        control_socket,
+        shutdown_grace,
+        ports,
+        forwards,
+        no_shell,
+        allow_egress,
+        allow_remote_forwarding,
+        transfer_roots,
+        sftp,
+        idle_timeout,
+        max_session_time,
+        no_pow,
+        pow_queue_depth,
+        intro_rate_limit,
+        max_sessions,
+        max_sessions_per_circuit,
+        stream_rate_limit,
+        when_limited,
+    } = args;
+
+    let from_config = !config.services.is_empty();
+    // Flags that describe the single service run without [[service]] sections.
+    let service_flags = [
+        ("--key", key.is_some()),
+        ("--port", !ports.is_empty()),
+        ("--forward", !forwards.is_empty()),
+        ("--no-shell", no_shell),
+        ("--allow-egress", !allow_egress.is_empty()),
+        ("--allow-remote-forwarding", allow_remote_forwarding),
+        ("--transfer-root", !transfer_roots.is_empty()),
+        ("--sftp", sftp),
+        ("--idle-timeout", idle_timeout.is_some()),
+        ("--max-session-time", max_session_time.is_some()),
+        ("--no-pow", no_pow),
+        ("--pow-queue-depth", pow_queue_depth.is_some()),
+        ("--intro-rate-limit", intro_rate_limit.is_some()),
+        ("--max-sessions", max_sessions.is_some()),
+        (
+            "--max-sessions-per-circuit",
+            max_sessions_per_circuit.is_some(),
+        ),
+        ("--stream-rate-limit", stream_rate_limit.is_some()),
+        ("--when-limited", when_limited.is_some()),
+    ];
+    let given: Vec<_> = service_flags
+        .iter()
+        .filter(|(_, given)| *given)
+        .map(|(flag, _)| *flag)
+        .collect();
+    if from_config && !given.is_empty() {
+        anyhow::bail!(
+            "The configuration defines [[service]] sections, which {} cannot be combined \
+             with; set these per service instead",
+            given.join(", ")
+        );
+    }
+    if !from_config {
+        config.services.push(ServiceConfig {
+            key,
+            ports,
+            no_shell,
+            forwards,
+            allow_egress,
+            allow_remote_forwarding,
+            transfer_roots,
+            sftp,
+            idle_timeout,
+            max_session_time,
+            no_pow,
+            pow_queue_depth,
+            intro_rate_limit,
+            max_sessions,
+            max_sessions_per_circuit,
+            stream_rate_limit,
+            when_limited: when_limited.unwrap_or_default(),
+            ..ServiceConfig::default()
+        });
+    }
+    if let Some(target) = audit_log.or_else(|| config.audit_log.take()) {
+        audit::init(&target)?;
+    }
+    #[cfg(unix)]
+    let control_socket = control_socket
+        .or_else(|| config.control_socket.take())
+        .unwrap_or_else(control::default_socket_path);
+    let shutdown_grace = shutdown_grace
+        .or(config.shutdown_grace)
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+    Ok(Server {
+        ephemeral: ephemeral || config.ephemeral,
+        services: config.into_specs()?,
+        from_config,
+        #[cfg(unix)]
+        control_socket,
+        shutdown_grace: Duration::from_secs(shutdown_grace),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    init_logging(cli.verbose);
+    // `ctl` only talks to a running server, and must keep working when the
+    // configuration is broken.
+    #[cfg(all(feature = "server", unix))]
+    if let Some(Command::Ctl { socket, request }) = &cli.command {
+        let socket = socket
+            .clone()
+            .or_else(|| Settings::control_socket(cli.config.as_deref()))
+            .unwrap_or_else(control::default_socket_path);
+        return control::send(&socket, request).await;
+    }
+
+    let mut settings = Settings::load(cli.config.as_deref())?;
+    let log_level = log_level(cli.verbose, &settings.log)?;
 
     // Default to serve mode when no subcommand is given.
-    let command = cli.command.unwrap_or(Command::Serve { key: None });
+    #[cfg(feature = "server")]
+    let mut command = cli.command.unwrap_or(Command::Serve(Box::default()));
+    #[cfg(not(feature = "server"))]
+    let Some(command) = cli.command else {
+        anyhow::bail!("No command given; see `backtor --help`");
+    };
 
-    debug!("Bootstrapping Tor – this may take a moment…");
-    
-    let current_directory = std::env::current_dir().expect("failed to determine current directory");
-    
-    let mut cfg_builder = TorClientConfigBuilder::from_directories(
-        current_directory.join(".backtor").join("config"),
-        current_directory.join(".backtor").join("cache"),
+    let directories = TorDirectories::resolve(
+        cli.state_dir.or(settings.state_dir.take()),
+        cli.cache_dir.or(settings.cache_dir.take()),
+    )?;
+    if let Command::Config {
+        action: ConfigAction::Check,
+    } = &command
+    {
+        return check_config(settings, &directories, log_level);
+    }
+
+    // `pipe` relays a data stream over stdout, so logs must stay out of it.
+    #[cfg(feature = "client")]
+    let log_to_stderr = matches!(command, Command::Pipe { .. });
+    #[cfg(not(feature = "client"))]
+    let log_to_stderr = false;
+    init_logging(log_level, &settings.log, log_to_stderr);
+
+    // Validate the server configuration before spending time on Tor.
+    #[cfg(feature = "server")]
+    let server = match &mut command {
+        Command::Serve(args) => Some(load_server(
+            *std::mem::take(args),
+            std::mem::take(&mut settings.server),
+        )?),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+
+    #[cfg(all(feature = "server", unix))]
+    if let Command::SystemdUnit { user } = &command {
+        let config = cli.config.as_deref().map(std::path::absolute).transpose()?;
+        let executable = std::env::current_exe()?;
+        let grace = settings
+            .server
+            .shutdown_grace
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        print!(
+            "{}",
+            systemd::unit_file(
+                &executable,
+                config.as_deref(),
+                user.as_deref(),
+                Duration::from_secs(grace),
+            )
+        );
+        return Ok(());
+    }
+
+    directories.create()?;
+    directories.warn_about_legacy_state();
+    debug!(
+        "Tor state in {}, cache in {}",
+        directories.state.display(),
+        directories.cache.display()
     );
-    cfg_builder.storage().permissions().dangerously_trust_everyone();
-    let cfg = cfg_builder.build()?;
-    let tor_client = TorClient::<PreferredRuntime>::create_bootstrapped(cfg).await?;
+
+    #[cfg(feature = "server")]
+    let ephemeral_keys = server.as_ref().is_some_and(|server| server.ephemeral);
+    #[cfg(not(feature = "server"))]
+    let ephemeral_keys = false;
+    let (cfg, proxy_relay) = settings.tor_config(&directories, ephemeral_keys)?;
+
+    // `key` works on the keystore directly, without Tor.
+    #[cfg(feature = "server")]
+    if let Command::Key { action } = &command {
+        return match action {
+            KeyAction::Export { file, service } => keys::export(&cfg, &directories, service, file),
+            KeyAction::Import {
+                file,
+                service,
+                force,
+            } => keys::import(&cfg, &directories, service, file, *force),
+        };
+    }
+    if ephemeral_keys {
+        info!("Keeping keys in memory only; services without a key get a new address");
+    }
+
+    debug!("Bootstrapping Tor – this may take a moment…");
+
+    if let Some(relay) = proxy_relay {
+        tokio::spawn(relay.run());
+    }
+    let tor_client = match TorClient::<PreferredRuntime>::create_bootstrapped(cfg).await {
+        Ok(tor_client) => tor_client,
+        Err(e) if e.kind() == ErrorKind::FsPermissions => {
+            return Err(e).context(directories.permission_hint());
+        }
+        Err(e) => return Err(e.into()),
+    };
 
     debug!("Tor bootstrapped.");
 
     match command {
         // ── Server mode ───────────────────────────────────────────────────────
         #[cfg(feature = "server")]
-        Command::Serve { key } => {
-            let secret_key: Option<[u8; 32]> = match key {
-                Some(hex) => {
-                    let bytes =
-                        hex::decode(&hex).map_err(|e| anyhow::anyhow!("Invalid hex key: {e}"))?;
-                    let arr: [u8; 32] = bytes.try_into().map_err(|_| {
-                        anyhow::anyhow!("Key must be exactly 32 bytes (64 hex chars)")
-                    })?;
-                    Some(arr)
-                }
-                None => None,
-            };
+        Command::Serve(_) => {
+            let server = server.expect("serve arguments were loaded");
+            #[cfg(unix)]
+            {
+                systemd::expect_services(server.services.len());
+                tokio::spawn(systemd::run_status_updates());
+            }
+            dos::init();
+            tokio::spawn(dos::log_pow_activity());
+            for spec in &server.services {
+                debug!("Starting onion service {}…", spec.nickname);
+                onion_service_from_sk(tor_client.clone(), spec.clone()).await?;
+            }
 
-            debug!("Starting shell service…");
-            onion_service_from_sk(tor_client, secret_key, None).await;
+            #[cfg(unix)]
+            {
+                let controller = Arc::new(Controller::new(
+                    tor_client.clone(),
+                    server.from_config,
+                    cli.config,
+                    &server.services,
+                ));
+                let listening = match control::bind(&server.control_socket) {
+                    Ok(listener) => {
+                        debug!("Control socket at {}", server.control_socket.display());
+                        tokio::spawn(control::run_control_socket(listener, controller.clone()));
+                        true
+                    }
+                    Err(e) => {
+                        error!("Control socket disabled: {e:#}");
+                        false
+                    }
+                };
 
-            // Park the main task; the service runs on spawned tasks.
-            std::future::pending::<()>().await;
+                // The services run on spawned tasks until we are told to stop.
+                control::wait_for_shutdown_signal(&controller).await?;
+                if listening {
+                    let _ = std::fs::remove_file(&server.control_socket);
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                tokio::signal::ctrl_c().await?;
+                log::info!("Interrupted, shutting down");
+            }
+
+            #[cfg(unix)]
+            systemd::notify("STOPPING=1");
+            onion_server::shutdown(server.shutdown_grace).await;
         }
 
         // ── Client mode ───────────────────────────────────────────────────────
         #[cfg(feature = "client")]
-        Command::Connect { address } => {
-            debug!("Connecting to {address}…");
-            OnionShellClient::new(tor_client).connect(&address).await?;
+        Command::Connect {
+            address,
+            local_forwards,
+            remote_forwards,
+            dynamic_forwards,
+            keepalive,
+            predict,
+            sync,
+        } => {
+            let connect = settings.connect;
+            let default_port = connect.port.unwrap_or(DEFAULT_SHELL_PORT);
+            let (host, port) = split_host_port(&address, default_port)?;
+            debug!("Connecting to {host}:{port}…");
+            OnionShellClient::new(tor_client)
+                .local_forwards(or_configured(local_forwards, connect.local_forwards))
+                .remote_forwards(or_configured(remote_forwards, connect.remote_forwards))
+                .dynamic_forwards(or_configured(dynamic_forwards, connect.dynamic_forwards))
+                .keepalive(
+                    keepalive
+                        .or(connect.keepalive)
+                        .filter(|secs| *secs > 0)
+                        .map(Duration::from_secs),
+                )
+                .predict(predict || connect.predict)
+                .sync(sync || connect.sync)
+                .connect(host, port)
+                .await?;
+        }
+
+        #[cfg(feature = "client")]
+        Command::Cp {
+            source,
+            destination,
+            recursive,
+            resume,
+        } => {
+            transfer::copy(
+                &tor_client,
+                CopyLocation::parse(&source),
+                CopyLocation::parse(&destination),
+                recursive,
+                resume,
+            )
+            .await?;
+        }
+
+        #[cfg(feature = "client")]
+        Command::Pipe {
+            address: (host, port),
+        } => {
+            OnionShellClient::new(tor_client)
+                .pipe_stdio(&host, port)
+                .await?;
+        }
+
+        #[cfg(feature = "client")]
+        Command::Socks { listen } => {
+            let listen = listen
+                .or(settings.socks.listen)
+                .unwrap_or(DEFAULT_SOCKS_LISTEN);
+            let listener = tokio::net::TcpListener::bind(listen)
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot listen on {listen}: {e}"))?;
+            socks_proxy::run_socks_proxy(tor_client, listener).await?;
+        }
+
+        #[cfg(all(feature = "server", unix))]
+        Command::Ctl { .. } | Command::SystemdUnit { .. } => {
+            unreachable!("handled before bootstrapping")
         }
+
+        #[cfg(feature = "server")]
+        Command::Key { .. } => unreachable!("handled before bootstrapping"),
+
+        Command::Config { .. } => unreachable!("handled before bootstrapping"),
     }
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_flags_make_the_only_service() {
+        let args = ServeArgs {
+            max_sessions: Some(5),
+            ..ServeArgs::default()
+        };
+        let server = load_server(args, ServerConfig::default()).unwrap();
+        assert!(!server.from_config);
+        assert_eq!(server.services.len(), 1);
+        assert_eq!(server.services[0].dos.max_sessions, 5);
+    }
+
+    #[test]
+    fn service_flags_are_refused_with_service_sections() {
+        let args = ServeArgs {
+            sftp: true,
+            max_sessions: Some(5),
+            ..ServeArgs::default()
+        };
+        let config = ServerConfig {
+            services: vec![ServiceConfig::default()],
+            ..ServerConfig::default()
+        };
+        let error = load_server(args, config).err().unwrap().to_string();
+        assert!(error.contains("--sftp, --max-sessions cannot"), "{error}");
+        assert!(!error.contains("--key"), "{error}");
+    }
+
+    #[test]
+    fn server_flags_combine_with_service_sections() {
+        let args = ServeArgs {
+            shutdown_grace: Some(60),
+            ..ServeArgs::default()
+        };
+        let config = ServerConfig {
+            services: vec![ServiceConfig::default()],
+            ..ServerConfig::default()
+        };
+        let server = load_server(args, config).unwrap();
+        assert!(server.from_config);
+        assert_eq!(server.shutdown_grace, Duration::from_secs(60));
+    }
+}
@@ -1,13 +1,29 @@
-use anyhow::Error;
+use anyhow::{Error, bail};
 use arti_client::{DataStream, TorClient};
-use crossterm::terminal;
-use log::{error, info, debug};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crossterm::{cursor, terminal};
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Stdout};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::Interval;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tor_rtcompat::PreferredRuntime;
 
-/// The port the server shell service listens on (matches `SHELL_PORT` in onion_server.rs).
-const SHELL_PORT: u16 = 23;
+use crate::predict::Predictor;
+use crate::shell_protocol::{self, AckScanner, Frame};
+use crate::transfer::human_bytes;
+use crate::tunnel::{self, DynamicForward, LocalForward, RemoteForward};
+
+/// How long to wait for the server to offer frames before assuming it
+/// predates them.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether the terminal changed size, when the server
+/// sends the screen.
+const RESIZE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 /// A Tor-native shell client.
 ///
@@ -18,16 +34,74 @@ const SHELL_PORT: u16 = 23;
 /// channel and the shared secret respectively.
 pub struct OnionShellClient {
     client: TorClient<PreferredRuntime>,
+    local_forwards: Vec<LocalForward>,
+    remote_forwards: Vec<RemoteForward>,
+    dynamic_forwards: Vec<DynamicForward>,
+    keepalive: Option<Duration>,
+    predict: bool,
+    sync: bool,
 }
 
 impl OnionShellClient {
     /// Create a new client from an already-bootstrapped [`TorClient`].
     pub fn new(client: TorClient<PreferredRuntime>) -> Self {
-        Self { client }
+        Self {
+            client,
+            local_forwards: Vec::new(),
+            remote_forwards: Vec::new(),
+            dynamic_forwards: Vec::new(),
+            keepalive: None,
+            predict: false,
+            sync: false,
+        }
+    }
+
+    /// Forward local TCP connections through the server for the duration of
+    /// the session (`-L`).
+    pub fn local_forwards(mut self, forwards: Vec<LocalForward>) -> Self {
+        self.local_forwards = forwards;
+        self
+    }
+
+    /// Let the server forward connections on its loopback ports back to
+    /// targets reachable from this machine for the duration of the session
+    /// (`-R`).
+    pub fn remote_forwards(mut self, forwards: Vec<RemoteForward>) -> Self {
+        self.remote_forwards = forwards;
+        self
+    }
+
+    /// Run local SOCKS5 proxies whose connections are made from the server's
+    /// network position for the duration of the session (`-D`).
+    pub fn dynamic_forwards(mut self, forwards: Vec<DynamicForward>) -> Self {
+        self.dynamic_forwards = forwards;
+        self
+    }
+
+    /// Tell the server every `period` that the session is still wanted, so
+    /// it does not close the session for being idle.
+    pub fn keepalive(mut self, period: Option<Duration>) -> Self {
+        self.keepalive = period;
+        self
     }
 
-    /// Connect to the shell service at `onion_host` and run an interactive
-    /// session until the connection is closed from either side.
+    /// Draw printable keystrokes before the server echoes them (see
+    /// [`predict`](crate::predict)).
+    pub fn predict(mut self, predict: bool) -> Self {
+        self.predict = predict;
+        self
+    }
+
+    /// Have the server send the screen rather than its output, so a flood
+    /// of output does not hold up the session (see
+    /// [`screen_sync`](crate::screen_sync)).
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Connect to the shell service at `onion_host`:`port` and run an
+    /// interactive session until the connection is closed from either side.
     ///
     /// `onion_host` may be supplied with or without the `.onion` suffix.
     ///
@@ -36,27 +110,59 @@ impl OnionShellClient {
     /// etc.) are forwarded verbatim to the remote PTY. The terminal is
     /// restored to its original mode when this function returns, even if an
     /// error occurs.
-    pub async fn connect(&self, onion_host: &str) -> Result<(), Error> {
-        // Normalise the host: ensure it ends with ".onion".
-        let host = if onion_host.ends_with(".onion") {
-            onion_host.to_owned()
-        } else {
-            format!("{onion_host}.onion")
-        };
+    pub async fn connect(&self, onion_host: &str, port: u16) -> Result<(), Error> {
+        let host = normalize_host(onion_host);
 
-        debug!("Connecting to {host}:{SHELL_PORT} via Tor…");
+        debug!("Connecting to {host}:{port} via Tor…");
 
         let stream: DataStream = self
             .client
-            .connect((host.as_str(), SHELL_PORT))
+            .connect((host.as_str(), port))
             .await
             .map_err(|e| anyhow::anyhow!("Tor connect failed: {e}"))?;
 
         debug!("Connected to {host}. Starting shell session.");
 
+        // Set up all forwards before entering raw mode so that a busy port or
+        // a refusing server is reported as a normal error.
+        let mut forward_tasks = Vec::new();
+        for forward in &self.local_forwards {
+            let listener = TcpListener::bind(forward.listen_addr())
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {e}", forward.listen_addr()))?;
+            info!("Forwarding {forward}");
+            forward_tasks.push(tokio::spawn(tunnel::run_local_forward(
+                self.client.clone(),
+                host.clone(),
+                forward.clone(),
+                listener,
+            )));
+        }
+        for forward in &self.dynamic_forwards {
+            let listener = TcpListener::bind(forward.listen_addr())
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {e}", forward.listen_addr()))?;
+            info!("Running {forward}");
+            forward_tasks.push(tokio::spawn(tunnel::run_dynamic_forward(
+                self.client.clone(),
+                host.clone(),
+                listener,
+            )));
+        }
+        for forward in &self.remote_forwards {
+            let control = tunnel::request_remote_forward(&self.client, &host, forward).await?;
+            info!("Forwarding {forward}");
+            forward_tasks.push(tokio::spawn(tunnel::run_remote_forward(
+                self.client.clone(),
+                host.clone(),
+                forward.clone(),
+                control,
+            )));
+        }
+
         // Print a short banner before entering raw mode so it ends up with
         // normal line endings.
-        info!("Connected. Press Ctrl-D to end the session.");
+        info!("Connected. Press Ctrl-D to end the session, or type ~? after Enter for help.");
 
         // Enter raw mode: the local terminal will no longer do any local
         // processing – every byte from stdin goes straight to the network.
@@ -68,6 +174,10 @@ impl OnionShellClient {
         // Always restore the terminal, regardless of how the session ended.
         let _ = terminal::disable_raw_mode();
 
+        for task in forward_tasks {
+            task.abort();
+        }
+
         // Print with explicit CR so the line starts at column 0 even though
         // we just left raw mode.
         info!("\r\nSession closed.");
@@ -75,17 +185,97 @@ impl OnionShellClient {
         result
     }
 
+    /// Connect to `port` of the service and relay the stream over stdin and
+    /// stdout, without touching the terminal.
+    ///
+    /// This is [`connect`](Self::connect) without raw mode or escape
+    /// handling, so other programs can use a backtor service as their
+    /// transport, e.g. OpenSSH through `ProxyCommand`. Returns once both
+    /// directions have finished.
+    pub async fn pipe_stdio(&self, onion_host: &str, port: u16) -> Result<(), Error> {
+        let host = normalize_host(onion_host);
+        debug!("Connecting to {host}:{port} via Tor…");
+        let stream = self
+            .client
+            .connect((host.as_str(), port))
+            .await
+            .map_err(|e| anyhow::anyhow!("Tor connect failed: {e}"))?;
+
+        let (sent, received) = relay(
+            tokio::io::stdin(),
+            tokio::io::stdout(),
+            &mut stream.compat(),
+        )
+        .await?;
+        debug!("Relay to {host}:{port} closed ({sent} bytes sent, {received} received)");
+        Ok(())
+    }
+
     /// Internal: run the bidirectional copy loop between the local terminal
-    /// and the Tor `DataStream`.
+    /// and the Tor `DataStream`, in frames if the server offers them.
     ///
     /// Returns when either the server closes the connection or stdin reaches
-    /// EOF (Ctrl-D).
+    /// EOF (Ctrl-D or `~.`), and with an error if the server stopped
+    /// answering pings.
     async fn run_session(&self, stream: DataStream) -> Result<(), Error> {
         // DataStream implements futures::io::AsyncRead + AsyncWrite.
         // Wrap it with the tokio-util compat layer so we can use the tokio
         // AsyncRead / AsyncWrite traits and tokio::io::split.
         let compat = stream.compat();
-        let (mut net_read, mut net_write) = tokio::io::split(compat);
+        let (net_read, mut net_write) = tokio::io::split(compat);
+        let mut net_read = BufReader::new(net_read);
+
+        let offer = tokio::time::timeout(
+            OFFER_TIMEOUT,
+            shell_protocol::read_prefix(&mut net_read, shell_protocol::OFFER),
+        )
+        .await;
+        let framed = match offer {
+            Ok(Ok(Ok(()))) => {
+                net_write.write_all(shell_protocol::REQUEST).await?;
+                net_write.flush().await?;
+                true
+            }
+            Ok(Ok(Err(raw))) => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(&raw).await?;
+                stdout.flush().await?;
+                false
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => false,
+        };
+        if !framed && self.keepalive.is_some() {
+            debug!("The server does not support keepalives");
+        }
+        if !framed && self.sync {
+            debug!("The server does not support screen updates");
+        }
+        let sync = self.sync && framed;
+        if sync && let Ok((cols, rows)) = terminal::size() {
+            shell_protocol::write_frame(&mut net_write, &Frame::Sync { rows, cols }).await?;
+        }
+        let keepalive = self.keepalive.filter(|_| framed);
+        let link = Arc::new(Link::new(framed));
+        let predictor = if self.predict {
+            match (terminal::size(), cursor::position()) {
+                (Ok((cols, rows)), Ok(position)) => Some(Arc::new(tokio::sync::Mutex::new(
+                    Predictor::new(rows, cols, position),
+                ))),
+                _ => {
+                    debug!("Cannot tell where the cursor is; not predicting keystrokes");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Everything for the server goes through one writer: keystrokes and
+        // keepalives from stdin, answers to the server's pings, and our own
+        // pings.
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let (pong_tx, pong_rx) = mpsc::unbounded_channel();
 
         // ── stdin → network ─────────────────────────────────────────────────
         //
@@ -94,70 +284,558 @@ impl OnionShellClient {
         // avoids the process hanging on a spawn_blocking thread that is stuck
         // in a blocking stdin.read() call after the server closes the
         // connection.
-        let mut stdin_to_net = tokio::spawn(async move {
-            let mut stdin = tokio::io::stdin();
-            let mut buf = [0u8; 256];
-            loop {
-                match stdin.read(&mut buf).await {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        // In raw mode Ctrl-D is sent as byte 0x04; treat it as a local
-                        // escape to end the session without forwarding it.
-                        if buf[..n].contains(&0x04) {
-                            break;
-                        }
-
-                        if net_write.write_all(&buf[..n]).await.is_err() {
-                            break;
-                        }
-                        if net_write.flush().await.is_err() {
-                            break;
-                        }
-                    }
+        let stdin_to_net = tokio::spawn(forward_keyboard(
+            input_tx,
+            keepalive,
+            sync,
+            predictor.clone(),
+            link.clone(),
+        ));
+        let mut net_writer = tokio::spawn(write_to_server(
+            net_write,
+            framed,
+            input_rx,
+            pong_rx,
+            link.clone(),
+        ));
+
+        // ── network → stdout ────────────────────────────────────────────────
+        let mut net_to_stdout =
+            tokio::spawn(forward_output(net_read, framed, pong_tx, predictor, link));
+
+        let result = tokio::select! {
+            res = &mut net_writer => {
+                net_to_stdout.abort();
+                res.unwrap_or_else(|e| {
+                    error!("net writer task panicked: {e}");
+                    Ok(())
+                })
+            }
+            res = &mut net_to_stdout => {
+                net_writer.abort();
+                if let Err(e) = res {
+                    error!("net→stdout task panicked: {e}");
                 }
+                Ok(())
             }
-            debug!("stdin→net task finished");
-        });
+        };
+        stdin_to_net.abort();
 
-        // ── network → stdout ────────────────────────────────────────────────
-        //
-        // The remote PTY already handles CRLF translation, so we write the
-        // bytes verbatim to stdout.
-        let mut net_to_stdout = tokio::spawn(async move {
-            let mut stdout = tokio::io::stdout();
-            let mut buf = [0u8; 4096];
-            loop {
-                match net_read.read(&mut buf).await {
-                    Ok(0) | Err(_) => break,
-                    Ok(n) => {
-                        if stdout.write_all(&buf[..n]).await.is_err() {
-                            break;
-                        }
-                        if stdout.flush().await.is_err() {
-                            break;
-                        }
+        result
+    }
+}
+
+/// What the client knows about its connection to the server, for `~s`.
+struct Link {
+    /// Whether the server speaks frames, and so answers pings.
+    framed: bool,
+    started: Instant,
+    /// Milliseconds from `started` until the server was last heard from.
+    heard: AtomicU64,
+    /// Round trip of the last answered ping in milliseconds, or `u64::MAX`.
+    round_trip: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Link {
+    fn new(framed: bool) -> Self {
+        Link {
+            framed,
+            started: Instant::now(),
+            heard: AtomicU64::new(0),
+            round_trip: AtomicU64::new(u64::MAX),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// Milliseconds since `started`, which is what our pings carry.
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn heard(&self) {
+        self.heard.store(self.now(), Ordering::Relaxed);
+    }
+
+    fn silent_for(&self) -> Duration {
+        Duration::from_millis(
+            self.now()
+                .saturating_sub(self.heard.load(Ordering::Relaxed)),
+        )
+    }
+
+    fn round_trip(&self) -> Option<Duration> {
+        match self.round_trip.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    fn pong(&self, sent: u64) {
+        let round_trip = self.now().saturating_sub(sent);
+        self.round_trip.store(round_trip, Ordering::Relaxed);
+    }
+
+    fn status(&self) -> String {
+        let round_trip = match self.round_trip.load(Ordering::Relaxed) {
+            _ if !self.framed => "round trip unknown (the server does not answer pings)".into(),
+            u64::MAX => "round trip not measured yet".into(),
+            ms => format!("round trip {ms} ms"),
+        };
+        format!(
+            "\r\nbacktor: {round_trip}, last heard from the server {:.1}s ago, {} sent, {} \
+             received\r\n",
+            self.silent_for().as_secs_f64(),
+            human_bytes(self.sent.load(Ordering::Relaxed) as f64),
+            human_bytes(self.received.load(Ordering::Relaxed) as f64),
+        )
+    }
+}
+
+const ESCAPE_HELP: &str = "\r\nSupported escape sequences (after Enter):\r\n\
+    ~.  end the session\r\n\
+    ~s  show the round trip to the server and the bytes transferred\r\n\
+    ~?  show this help\r\n\
+    ~~  send a ~\r\n";
+
+/// An escape sequence typed by the user.
+#[derive(Debug, PartialEq, Eq)]
+enum Escape {
+    Disconnect,
+    Status,
+    Help,
+}
+
+/// Picks escape sequences, like OpenSSH's `~.`, out of the keystrokes: a `~`
+/// at the start of a line followed by a command character.
+struct EscapeReader {
+    at_line_start: bool,
+    /// Just read a `~` at the start of a line.
+    pending: bool,
+}
+
+impl EscapeReader {
+    fn new() -> Self {
+        EscapeReader {
+            at_line_start: true,
+            pending: false,
+        }
+    }
+
+    /// Feeds one keystroke, adding what should be sent to `out`.
+    fn push(&mut self, byte: u8, out: &mut Vec<u8>) -> Option<Escape> {
+        if self.pending {
+            self.pending = false;
+            match byte {
+                b'.' => return Some(Escape::Disconnect),
+                b's' => return Some(Escape::Status),
+                b'?' => return Some(Escape::Help),
+                b'~' => {
+                    out.push(b'~');
+                    self.at_line_start = false;
+                    return None;
+                }
+                _ => out.push(b'~'),
+            }
+        } else if self.at_line_start && byte == b'~' {
+            self.pending = true;
+            return None;
+        }
+        out.push(byte);
+        self.at_line_start = matches!(byte, b'\r' | b'\n');
+        None
+    }
+}
+
+/// Reads keystrokes and hands them to [`write_to_server`], handling
+/// escape sequences, predicting their echo and sending keepalives every
+/// `keepalive`, and with `sync` the terminal's new size when it changes.
+async fn forward_keyboard(
+    input_tx: mpsc::Sender<Frame>,
+    keepalive: Option<Duration>,
+    sync: bool,
+    predictor: Option<SharedPredictor>,
+    link: Arc<Link>,
+) {
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut buf = [0u8; 256];
+    let mut escapes = EscapeReader::new();
+    let mut keepalive = keepalive
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    let mut size = terminal::size().ok();
+    let mut resize_checks = sync.then(|| tokio::time::interval(RESIZE_CHECK_INTERVAL));
+    'read: loop {
+        let deadline = match &predictor {
+            Some(predictor) => predictor.lock().await.deadline(link.round_trip()),
+            None => None,
+        };
+        let n = tokio::select! {
+            read = stdin.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            },
+            () = tick(&mut keepalive) => {
+                if input_tx.send(Frame::Keepalive).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            () = tick(&mut resize_checks) => {
+                let new_size = terminal::size().ok();
+                if new_size != size {
+                    size = new_size;
+                    if let Some((cols, rows)) = size
+                        && input_tx.send(Frame::Sync { rows, cols }).await.is_err()
+                    {
+                        break;
                     }
                 }
+                continue;
             }
-            debug!("net→stdout task finished");
-        });
+            () = sleep_until(deadline) => {
+                if let Some(predictor) = &predictor {
+                    let mut predictor = predictor.lock().await;
+                    let out = predictor.expire(link.round_trip());
+                    if stdout.write_all(&out).await.is_err() || stdout.flush().await.is_err() {
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
 
+        // In raw mode Ctrl-D is sent as byte 0x04; treat it as a local
+        // escape to end the session without forwarding it.
+        if buf[..n].contains(&0x04) {
+            break;
+        }
 
-        tokio::select! {
-            res = &mut stdin_to_net => {
-                net_to_stdout.abort();
-                if let Err(e) = res {
-                    error!("stdin→net task panicked: {e}");
+        let mut data = Vec::with_capacity(n);
+        let mut disconnect = false;
+        for &byte in &buf[..n] {
+            let message = match escapes.push(byte, &mut data) {
+                None => continue,
+                Some(Escape::Disconnect) => {
+                    disconnect = true;
+                    break;
                 }
+                Some(Escape::Status) => link.status(),
+                Some(Escape::Help) => ESCAPE_HELP.to_owned(),
+            };
+            if show(&mut stdout, &predictor, message.as_bytes())
+                .await
+                .is_err()
+            {
+                break 'read;
             }
-            res = &mut net_to_stdout => {
-                stdin_to_net.abort();
-                if let Err(e) = res {
-                    error!("net→stdout task panicked: {e}");
+        }
+        if !data.is_empty() {
+            link.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+            if let Some(predictor) = &predictor {
+                let mut predictor = predictor.lock().await;
+                let out = predictor.input(&data);
+                if stdout.write_all(&out).await.is_err() || stdout.flush().await.is_err() {
+                    break;
+                }
+            }
+            if input_tx.send(Frame::Data(data)).await.is_err() {
+                break;
+            }
+            // Typing is as good as a keepalive.
+            if let Some(keepalive) = &mut keepalive {
+                keepalive.reset();
+            }
+        }
+        if disconnect {
+            break;
+        }
+    }
+    debug!("stdin→net task finished");
+}
+
+/// Writes what [`forward_keyboard`] and [`forward_output`] have for the
+/// server, and pings it if it speaks frames.
+///
+/// Returns once the keyboard is done or the stream fails, and with an error
+/// if the server stopped answering.
+async fn write_to_server<W>(
+    mut net_write: W,
+    framed: bool,
+    mut input_rx: mpsc::Receiver<Frame>,
+    mut pong_rx: mpsc::UnboundedReceiver<u64>,
+    link: Arc<Link>,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut pings = tokio::time::interval(shell_protocol::PING_INTERVAL);
+    loop {
+        let frame = tokio::select! {
+            frame = input_rx.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            Some(value) = pong_rx.recv() => Frame::Pong(value),
+            _ = pings.tick(), if framed => {
+                let silent = link.silent_for();
+                if silent > shell_protocol::PEER_TIMEOUT {
+                    bail!(
+                        "The server has not answered for {}s; the connection is probably dead",
+                        silent.as_secs()
+                    );
                 }
+                Frame::Ping(link.now())
             }
+        };
+        let written = match frame {
+            frame if framed => shell_protocol::write_frame(&mut net_write, &frame).await,
+            Frame::Data(data) => match net_write.write_all(&data).await {
+                Ok(()) => net_write.flush().await.map_err(Error::from),
+                Err(e) => Err(e.into()),
+            },
+            _ => Ok(()),
+        };
+        if written.is_err() {
+            break;
         }
+    }
+    debug!("net writer task finished");
+    Ok(())
+}
 
-        Ok(())
+/// Writes the server's output to stdout, and has pings of the server
+/// answered.
+///
+/// The remote PTY already handles CRLF translation, so output is written
+/// verbatim.
+async fn forward_output<R>(
+    mut net_read: BufReader<R>,
+    framed: bool,
+    pong_tx: mpsc::UnboundedSender<u64>,
+    predictor: Option<SharedPredictor>,
+    link: Arc<Link>,
+) where
+    R: AsyncRead + Unpin,
+{
+    let mut stdout = tokio::io::stdout();
+    if framed {
+        // Output the server sent before it saw our request is raw.
+        let mut scanner = AckScanner::default();
+        loop {
+            let Ok(byte) = net_read.read_u8().await else {
+                debug!("net→stdout task finished");
+                return;
+            };
+            link.heard();
+            let Some(output) = scanner.push(byte) else {
+                break;
+            };
+            if show(&mut stdout, &predictor, &output).await.is_err() {
+                return;
+            }
+        }
+        while let Ok(Some(frame)) = shell_protocol::read_frame(&mut net_read).await {
+            link.heard();
+            match frame {
+                Frame::Data(data) | Frame::Screen(data) => {
+                    link.received
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    if show(&mut stdout, &predictor, &data).await.is_err() {
+                        break;
+                    }
+                }
+                Frame::Ping(value) => {
+                    let _ = pong_tx.send(value);
+                }
+                Frame::Pong(sent) => link.pong(sent),
+                Frame::Keepalive | Frame::Sync { .. } | Frame::Unknown(_) => {}
+            }
+        }
+    } else {
+        let mut buf = [0u8; 4096];
+        loop {
+            match net_read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    link.heard();
+                    link.received.fetch_add(n as u64, Ordering::Relaxed);
+                    if show(&mut stdout, &predictor, &buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    debug!("net→stdout task finished");
+}
+
+/// The screen model predictions are checked against, shared by the tasks
+/// writing to the terminal.
+type SharedPredictor = Arc<tokio::sync::Mutex<Predictor>>;
+
+/// Writes server output, or a message of our own, to the terminal, past the
+/// predictor if there is one.
+async fn show(
+    stdout: &mut Stdout,
+    predictor: &Option<SharedPredictor>,
+    data: &[u8],
+) -> std::io::Result<()> {
+    match predictor {
+        Some(predictor) => {
+            // Hold the lock until written, so nothing gets drawn in between.
+            let mut predictor = predictor.lock().await;
+            if let Ok((cols, rows)) = terminal::size() {
+                predictor.resize(rows, cols);
+            }
+            stdout.write_all(&predictor.output(data)).await?;
+            stdout.flush().await
+        }
+        None => {
+            stdout.write_all(data).await?;
+            stdout.flush().await
+        }
+    }
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever without one.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Ensures the host ends with ".onion".
+fn normalize_host(onion_host: &str) -> String {
+    if onion_host.ends_with(".onion") {
+        onion_host.to_owned()
+    } else {
+        format!("{onion_host}.onion")
+    }
+}
+
+/// Copies `input` to `stream` and `stream` to `output` until both directions
+/// have finished, returning the bytes sent and received. Once `input` ends,
+/// the stream's sending side is shut down while its replies keep coming.
+async fn relay<I, O, S>(input: I, output: O, stream: &mut S) -> std::io::Result<(u64, u64)>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut local = tokio::io::join(input, output);
+    tokio::io::copy_bidirectional(&mut local, stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Types `keys`, returning what is sent and the escapes recognised.
+    fn type_keys(escapes: &mut EscapeReader, keys: &[u8]) -> (Vec<u8>, Vec<Escape>) {
+        let mut sent = Vec::new();
+        let mut found = Vec::new();
+        for &byte in keys {
+            found.extend(escapes.push(byte, &mut sent));
+        }
+        (sent, found)
+    }
+
+    #[test]
+    fn recognises_escapes_at_the_start_of_a_line() {
+        let mut escapes = EscapeReader::new();
+        assert_eq!(
+            type_keys(&mut escapes, b"~."),
+            (vec![], vec![Escape::Disconnect])
+        );
+        let mut escapes = EscapeReader::new();
+        assert_eq!(
+            type_keys(&mut escapes, b"ls\r~s"),
+            (b"ls\r".to_vec(), vec![Escape::Status])
+        );
+        // An escape leaves the line start where it was.
+        assert_eq!(type_keys(&mut escapes, b"~?"), (vec![], vec![Escape::Help]));
+        assert_eq!(
+            type_keys(&mut escapes, b"x~?\n~."),
+            (b"x~?\n".to_vec(), vec![Escape::Disconnect])
+        );
+    }
+
+    #[test]
+    fn sends_a_doubled_tilde_once() {
+        let mut escapes = EscapeReader::new();
+        assert_eq!(type_keys(&mut escapes, b"~~."), (b"~.".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn passes_other_tildes_through() {
+        let mut escapes = EscapeReader::new();
+        assert_eq!(
+            type_keys(&mut escapes, b"cd ~.\r"),
+            (b"cd ~.\r".to_vec(), vec![])
+        );
+        assert_eq!(type_keys(&mut escapes, b"~/x"), (b"~/x".to_vec(), vec![]));
+    }
+
+    #[tokio::test]
+    async fn relays_stdio_and_half_closes_on_eof() {
+        let (mut stdin, input) = tokio::io::duplex(64);
+        let (output, mut stdout) = tokio::io::duplex(64);
+        let (mut stream, mut server) = tokio::io::duplex(64);
+        let relaying = tokio::spawn(async move { relay(input, output, &mut stream).await });
+
+        stdin.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap();
+        drop(stdin);
+        // The server sees the end of stdin, and can still answer.
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"SSH-2.0-OpenSSH\r\n");
+        server.write_all(b"SSH-2.0-backtor\r\n").await.unwrap();
+        drop(server);
+
+        let mut reply = Vec::new();
+        stdout.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"SSH-2.0-backtor\r\n");
+        assert_eq!(relaying.await.unwrap().unwrap(), (17, 17));
+    }
+
+    #[tokio::test]
+    async fn relays_more_than_a_buffer_both_ways_at_once() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let (mut stdin, input) = tokio::io::duplex(1024);
+        let (output, mut stdout) = tokio::io::duplex(1024);
+        let (mut stream, server) = tokio::io::duplex(1024);
+        let relaying = tokio::spawn(async move { relay(input, output, &mut stream).await });
+
+        // The server echoes, so neither direction can finish on its own.
+        let echo = tokio::spawn(async move {
+            let (mut read, mut write) = tokio::io::split(server);
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        let sent = data.clone();
+        let writing = tokio::spawn(async move {
+            stdin.write_all(&sent).await.unwrap();
+        });
+        let mut echoed = Vec::new();
+        stdout.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+
+        writing.await.unwrap();
+        echo.await.unwrap();
+        assert_eq!(relaying.await.unwrap().unwrap(), (100_000, 100_000));
     }
 }
@@ -1,33 +1,173 @@
-use arti_client::TorClient;
-use futures::{Stream, StreamExt};
-use log::{error, info, debug};
+use anyhow::{Error, bail};
+use arti_client::{ErrorKind, HasKind, TorClient};
+use futures::future::Either;
+use futures::{FutureExt, Stream, StreamExt};
+use log::{debug, error, info};
 use portable_pty::{CommandBuilder, PtySize, native_pty_system};
 use safelog::DisplayRedacted;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
-use std::net::SocketAddr;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tokio_util::sync::CancellationToken;
-use tor_cell::relaycell::msg::Connected;
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hscrypto::pk::{HsClientDescEncKey, HsIdKeypair};
 use tor_hsservice::config::OnionServiceConfigBuilder;
+use tor_hsservice::config::restricted_discovery::HsClientNickname;
+use tor_hsservice::{
+    HsIdKeypairSpecifier, HsNickname, RendRequest, RunningOnionService, StreamRequest,
+};
 use tor_proto::client::stream::IncomingStreamRequest;
 use tor_rtcompat::PreferredRuntime;
 use tor_rtcompat::SpawnExt;
 
+use crate::audit::{self, AuditEvent};
+use crate::dos::{DosSettings, Limited, SessionLimiter, WhenLimited};
+use crate::forward::{ForwardTarget, handle_forward_connection};
+use crate::screen_sync::{self, ScreenSync};
+use crate::sessions::{Registry, SESSIONS};
+use crate::sftp::handle_sftp_connection;
+use crate::shell_protocol::{self, Frame};
+use crate::transfer::{TransferPolicy, handle_transfer_connection};
+use crate::tunnel::{TunnelPolicy, handle_tunnel_connection};
 use crate::utils;
-use crate::utils::get_onion_address;
-use tor_hsrproxy::{
-    OnionServiceReverseProxy,
-    config::{Encapsulation, ProxyAction, ProxyConfigBuilder, ProxyPattern, ProxyRule, TargetAddr},
-};
 
-// The port on which the shell service listens (telnet-like)
-const SHELL_PORT: u16 = 23;
+/// How to start the shell served on one virtual port.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ShellConfig {
+    /// Program and arguments to run; empty means the user's login shell.
+    pub(crate) command: Vec<String>,
+    /// Run the session as this user through `su` instead of as ourselves.
+    pub(crate) user: Option<String>,
+    /// The terminal size the shell starts with.
+    pub(crate) pty_size: PtySize,
+    /// Close sessions without input or output for this long.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Close sessions that have lasted this long.
+    pub(crate) max_session_time: Option<Duration>,
+}
+
+impl ShellConfig {
+    /// The program and arguments to spawn for a new session.
+    fn resolve_command(&self) -> Vec<String> {
+        match &self.user {
+            None if self.command.is_empty() => vec![get_login_shell()],
+            None => self.command.clone(),
+            Some(user) => {
+                let mut argv = vec!["su".to_owned(), "-".to_owned(), user.clone()];
+                if !self.command.is_empty() {
+                    argv.push("-c".to_owned());
+                    argv.push(shell_quote(&self.command));
+                }
+                argv
+            }
+        }
+    }
+}
+
+/// Joins `argv` into a single POSIX shell command line.
+fn shell_quote(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A shell port given on the command line as `<port>[:<command>]`, e.g. `23`
+/// or `2222:/usr/bin/fish --private`.
+///
+/// The command is split on whitespace. There is no quoting, so quotes are
+/// refused rather than passed on to the command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShellPort {
+    pub(crate) port: u16,
+    pub(crate) shell: ShellConfig,
+}
 
-type RunningOnionServices = HashMap<String, CancellationToken>;
+impl FromStr for ShellPort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, command) = s.split_once(':').unwrap_or((s, ""));
+        let port = port
+            .parse::<u16>()
+            .ok()
+            .filter(|p| *p != 0)
+            .ok_or_else(|| format!("invalid port {port:?}"))?;
+        if command.contains(['"', '\'']) {
+            return Err(format!(
+                "quotes are not supported in {command:?}: arguments are split on \
+                 whitespace, so put the command in a script instead"
+            ));
+        }
+        let command = command.split_whitespace().map(str::to_owned).collect();
+
+        Ok(ShellPort {
+            port,
+            shell: ShellConfig {
+                command,
+                ..ShellConfig::default()
+            },
+        })
+    }
+}
+
+/// What to do with a stream opened to one virtual port of the onion service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PortAction {
+    /// Hand the stream a freshly spawned shell.
+    Shell(ShellConfig),
+    /// Splice the stream to a local TCP or Unix-socket target.
+    Forward(ForwardTarget),
+    /// Serve client-requested port forwards (see [`crate::tunnel`]).
+    Tunnel(TunnelPolicy),
+    /// Serve `backtor cp` uploads and downloads (see [`crate::transfer`]).
+    Transfer(TransferPolicy),
+    /// Serve the SFTP subsystem (see [`crate::sftp`]).
+    Sftp(TransferPolicy),
+}
+
+/// The virtual ports an onion service accepts connections on.
+///
+/// Streams to any port missing from the map close the whole circuit.
+pub(crate) type PortMap = BTreeMap<u16, PortAction>;
+
+/// Everything needed to launch one onion service.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ServiceSpec {
+    /// The name the service is managed by, e.g. in `backtor ctl`.
+    pub(crate) name: String,
+    pub(crate) nickname: HsNickname,
+    /// Secret key for a stable address; `None` uses the keystore entry of
+    /// `nickname`, creating it on first use.
+    pub(crate) secret_key: Option<[u8; 32]>,
+    pub(crate) ports: PortMap,
+    /// Clients allowed to discover the service; empty means everyone.
+    pub(crate) authorized_clients: Vec<(HsClientNickname, HsClientDescEncKey)>,
+    pub(crate) dos: DosSettings,
+}
+
+/// How long [`shutdown`] waits for killed sessions to clean up.
+pub(crate) const SESSION_REAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Process-wide counter used to label circuits in the audit log.
+static NEXT_CIRCUIT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A launched onion service.
+pub(crate) struct RunningService {
+    pub(crate) address: Arc<str>,
+    pub(crate) service: Arc<RunningOnionService>,
+    /// Stops the tasks serving the service.
+    pub(crate) cancel: CancellationToken,
+}
+
+/// Running services by name.
+type RunningOnionServices = HashMap<String, RunningService>;
 
 pub(crate) static RUNNING_ONION_SERVICES: LazyLock<Arc<Mutex<RunningOnionServices>>> =
     LazyLock::new(|| Arc::new(Mutex::new(HashMap::new())));
@@ -43,10 +183,10 @@ fn get_login_shell() -> String {
     #[cfg(unix)]
     {
         // First try $SHELL
-        if let Ok(shell) = std::env::var("SHELL") {
-            if !shell.is_empty() {
-                return shell;
-            }
+        if let Ok(shell) = std::env::var("SHELL")
+            && !shell.is_empty()
+        {
+            return shell;
         }
 
         // Fall back to parsing /etc/passwd for the current user's login shell
@@ -54,14 +194,13 @@ fn get_login_shell() -> String {
         if let Ok(content) = std::fs::read_to_string("/etc/passwd") {
             for line in content.lines() {
                 let fields: Vec<&str> = line.split(':').collect();
-                if fields.len() >= 7 {
-                    if let Ok(entry_uid) = fields[2].parse::<u32>() {
-                        if entry_uid == uid {
-                            let shell = fields[6].trim();
-                            if !shell.is_empty() {
-                                return shell.to_string();
-                            }
-                        }
+                if fields.len() >= 7
+                    && let Ok(entry_uid) = fields[2].parse::<u32>()
+                    && entry_uid == uid
+                {
+                    let shell = fields[6].trim();
+                    if !shell.is_empty() {
+                        return shell.to_string();
                     }
                 }
             }
@@ -87,25 +226,28 @@ fn get_login_shell() -> String {
     }
 }
 
-/// Spawns a login shell inside a PTY and bridges its I/O to the provided
-/// async stream (the Tor onion-service data stream).
+/// Spawns the configured shell inside a PTY and bridges its I/O to the
+/// provided async stream (the Tor onion-service data stream).
+///
+/// `service` and `circuit` identify the connection in audit events.
 ///
 /// The function returns once either side closes the connection.
-async fn handle_shell_connection<S>(stream: S)
-where
+async fn handle_shell_connection<S>(
+    stream: S,
+    shell_config: ShellConfig,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) where
     S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
-    let shell = get_login_shell();
+    let argv = shell_config.resolve_command();
+    let shell = argv.join(" ");
     debug!("Incoming shell connection – spawning: {shell}");
 
     // Open a PTY pair.
     let pty_system = native_pty_system();
-    let pair = match pty_system.openpty(PtySize {
-        rows: 24,
-        cols: 80,
-        pixel_width: 0,
-        pixel_height: 0,
-    }) {
+    let pair = match pty_system.openpty(shell_config.pty_size) {
         Ok(p) => p,
         Err(e) => {
             error!("Failed to open PTY: {e}");
@@ -114,7 +256,7 @@ where
     };
 
     // Spawn the shell attached to the PTY slave.
-    let cmd = CommandBuilder::new(&shell);
+    let cmd = CommandBuilder::from_argv(argv.iter().map(Into::into).collect());
     let mut child = match pair.slave.spawn_command(cmd) {
         Ok(c) => c,
         Err(e) => {
@@ -145,20 +287,46 @@ where
         }
     };
 
+    // Only register the session once nothing can fail before its end is
+    // recorded, so every SessionStart has a matching SessionEnd.
+    let started = Instant::now();
+    let handle = SESSIONS.register(&service, circuit, port, "shell", &shell);
+    let session = handle.id();
+    audit::record(AuditEvent::SessionStart {
+        service: &service,
+        circuit,
+        session,
+        port,
+        command: &shell,
+    });
+
     // Channels used to bridge the sync PTY world and the async Tor stream.
     // pty_out  : PTY master → Tor stream
     // stream_in: Tor stream → PTY master
     let (pty_out_tx, mut pty_out_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
     let (stream_in_tx, mut stream_in_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
+    // Lets us slip a shutdown notice into the output without keeping the
+    // channel open once the shell is gone.
+    let notice_tx = pty_out_tx.downgrade();
+    // Tells the task writing to the Tor stream to switch to frames or to the
+    // screen, about pings and pongs, or to write a last notice and stop.
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::unbounded_channel::<OutputControl>();
+    let input_control_tx = control_tx.clone();
+    // Input and output keep a session from idling; any frame, pongs
+    // included, shows the client is still there.
+    let activity = Arc::new(Activity::new());
+    let client_heard = Arc::new(Activity::new());
 
     // Blocking task: read bytes from the PTY master and forward them through
     // the channel to the async writer task below.
+    let pty_activity = activity.clone();
     tokio::task::spawn_blocking(move || {
         let mut buf = [0u8; 4096];
         loop {
             match pty_reader.read(&mut buf) {
                 Ok(0) | Err(_) => break,
                 Ok(n) => {
+                    pty_activity.touch();
                     if pty_out_tx.blocking_send(buf[..n].to_vec()).is_err() {
                         break;
                     }
@@ -180,246 +348,1066 @@ where
         debug!("PTY writer task finished");
     });
 
-    let (mut stream_read, mut stream_write) = tokio::io::split(stream);
+    let (stream_read, mut stream_write) = tokio::io::split(stream);
+
+    // Byte counters for the audit log, updated by the two copy tasks below.
+    let bytes_in = Arc::new(AtomicU64::new(0));
+    let bytes_out = Arc::new(AtomicU64::new(0));
+    let bytes_in_task = bytes_in.clone();
+    let bytes_out_task = bytes_out.clone();
 
     // Async task: read from the Tor stream and forward to the PTY writer task.
+    // Clients that take up the offer of frames send them from the start.
+    let input_activity = activity.clone();
+    let input_client_heard = client_heard.clone();
     let mut stream_to_pty = tokio::spawn(async move {
-        let mut buf = [0u8; 4096];
-        loop {
-            match stream_read.read(&mut buf).await {
-                Ok(0) | Err(_) => break,
-                Ok(n) => {
-                    if stream_in_tx.send(buf[..n].to_vec()).await.is_err() {
+        let mut stream_read = tokio::io::BufReader::new(stream_read);
+        match shell_protocol::read_prefix(&mut stream_read, shell_protocol::REQUEST).await {
+            Ok(Ok(())) => {
+                debug!("Client switched to frames");
+                let _ = input_control_tx.send(OutputControl::Upgrade);
+                while let Ok(Some(frame)) = shell_protocol::read_frame(&mut stream_read).await {
+                    input_client_heard.touch();
+                    match frame {
+                        Frame::Data(data) => {
+                            input_activity.touch();
+                            bytes_in_task.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            if stream_in_tx.send(data).await.is_err() {
+                                break;
+                            }
+                        }
+                        Frame::Keepalive => input_activity.touch(),
+                        Frame::Ping(value) => {
+                            let _ = input_control_tx.send(OutputControl::Pong(value));
+                        }
+                        Frame::Pong(value) => {
+                            let _ = input_control_tx.send(OutputControl::Answered(value));
+                        }
+                        Frame::Sync { rows, cols } => {
+                            let _ = input_control_tx.send(OutputControl::Sync { rows, cols });
+                        }
+                        Frame::Screen(_) | Frame::Unknown(_) => {}
+                    }
+                }
+            }
+            Ok(Err(raw)) => {
+                let mut data = raw;
+                let mut buf = [0u8; 4096];
+                while !data.is_empty() {
+                    input_activity.touch();
+                    bytes_in_task.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    if stream_in_tx.send(data).await.is_err() {
                         break;
                     }
+                    data = match stream_read.read(&mut buf).await {
+                        Ok(n) => buf[..n].to_vec(),
+                        Err(_) => break,
+                    };
                 }
             }
+            Err(_) => {}
         }
         debug!("Stream→PTY task finished");
     });
 
     // Async task: receive from the PTY reader task and write to the Tor stream.
+    // Once the client speaks frames, also ping it, and give up on it when it
+    // stops answering. Pings carry a count, so their pongs tell which screen
+    // updates the client has.
+    //
+    // The screen is only tracked once the client asks for it, so raw and
+    // plain framed sessions do not pay for a terminal emulator. Its first
+    // update redraws the screen from scratch, and the resize that comes with
+    // the request makes most programs draw theirs again.
+    let master = pair.master;
+    let pty_size = shell_config.pty_size;
     let mut pty_to_stream = tokio::spawn(async move {
-        while let Some(data) = pty_out_rx.recv().await {
-            if stream_write.write_all(&data).await.is_err() {
-                break;
+        let mut framed = false;
+        let mut screen: Option<ScreenSync> = None;
+        let mut pings = tokio::time::interval(shell_protocol::PING_INTERVAL);
+        let mut pings_sent = 0;
+        if stream_write.write_all(shell_protocol::OFFER).await.is_err() {
+            return;
+        }
+        'output: loop {
+            let data = tokio::select! {
+                biased;
+                Some(control) = control_rx.recv() => {
+                    let frame = match control {
+                        OutputControl::Upgrade => {
+                            if stream_write.write_all(shell_protocol::ACK).await.is_err() {
+                                break;
+                            }
+                            framed = true;
+                            client_heard.touch();
+                            pings.reset_immediately();
+                            continue;
+                        }
+                        OutputControl::Pong(value) => Frame::Pong(value),
+                        OutputControl::Answered(value) => {
+                            if let Some(screen) = &mut screen {
+                                screen.answered(value);
+                            }
+                            continue;
+                        }
+                        OutputControl::Sync { rows, cols } => {
+                            let valid = 1..=screen_sync::MAX_SCREEN_SIZE;
+                            if !valid.contains(&rows) || !valid.contains(&cols) {
+                                debug!("Ignoring a request for a {rows}x{cols} screen");
+                                continue;
+                            }
+                            let size = PtySize { rows, cols, ..pty_size };
+                            if let Err(e) = master.resize(size) {
+                                debug!("Failed to resize PTY: {e}");
+                            }
+                            match &mut screen {
+                                Some(screen) => screen.resize(rows, cols),
+                                None => {
+                                    debug!("Client switched to screen updates");
+                                    screen = Some(ScreenSync::new(rows, cols));
+                                }
+                            }
+                            continue;
+                        }
+                        OutputControl::Close(notice) => {
+                            let notice = notice.into_bytes();
+                            if let Some(screen) = &mut screen {
+                                screen.process(&notice);
+                                let frame = Frame::Screen(screen.update(pings_sent));
+                                let _ = shell_protocol::write_frame(&mut stream_write, &frame).await;
+                            } else {
+                                let _ = write_output(&mut stream_write, framed, notice).await;
+                            }
+                            break;
+                        }
+                    };
+                    if shell_protocol::write_frame(&mut stream_write, &frame).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                _ = pings.tick(), if framed => {
+                    if client_heard.last().elapsed() > shell_protocol::PEER_TIMEOUT {
+                        info!("Closing shell session {session}: the client stopped answering");
+                        break;
+                    }
+                    pings_sent += 1;
+                    let frame = Frame::Ping(pings_sent);
+                    if shell_protocol::write_frame(&mut stream_write, &frame).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // Before more output, so a flood of it does not hold updates
+                // back.
+                () = sleep_until(screen.as_ref().and_then(ScreenSync::next_update)) => {
+                    let Some(screen) = &mut screen else { continue };
+                    // Catch up on output that came in while we waited, but
+                    // not on what keeps coming in meanwhile.
+                    for _ in 0..pty_out_rx.len() {
+                        match pty_out_rx.try_recv() {
+                            Ok(data) => screen.process(&data),
+                            Err(_) => break,
+                        }
+                    }
+                    pings_sent += 1;
+                    let update = screen.update(pings_sent);
+                    let len = update.len() as u64;
+                    let frames = [Frame::Screen(update), Frame::Ping(pings_sent)];
+                    for frame in &frames {
+                        if shell_protocol::write_frame(&mut stream_write, frame).await.is_err() {
+                            break 'output;
+                        }
+                    }
+                    bytes_out_task.fetch_add(len, Ordering::Relaxed);
+                    continue;
+                }
+                data = pty_out_rx.recv() => match data {
+                    Some(data) => data,
+                    None => {
+                        if let Some(screen) = &mut screen {
+                            // The shell's last words.
+                            let frame = Frame::Screen(screen.update(pings_sent));
+                            let _ = shell_protocol::write_frame(&mut stream_write, &frame).await;
+                        }
+                        break;
+                    }
+                },
+            };
+            if let Some(screen) = &mut screen {
+                screen.process(&data);
+                continue;
             }
-            if stream_write.flush().await.is_err() {
+            let len = data.len() as u64;
+            if write_output(&mut stream_write, framed, data).await.is_err() {
                 break;
             }
+            bytes_out_task.fetch_add(len, Ordering::Relaxed);
         }
         debug!("PTY→stream task finished");
     });
 
-    // Wait for both directions to close, then clean up the child process.
-    tokio::select! {
-        res = &mut pty_to_stream => {
-            stream_to_pty.abort();
-            if let Err(e) = res {
-                error!("Stream→PTY task panicked: {e}");
+    // Wait for either direction to close (or the session to be killed from
+    // the control socket or by a shutdown, or to time out), then clean up the
+    // child process. A shutdown or an upcoming timeout first only warns the
+    // client.
+    let mut timers = SessionTimers::new(&shell_config, activity);
+    let mut warned = false;
+    let killed = loop {
+        let wakeup = timers.next_wakeup();
+        tokio::select! {
+            res = &mut pty_to_stream => {
+                stream_to_pty.abort();
+                if let Err(e) = res {
+                    error!("Stream→PTY task panicked: {e}");
+                }
+                break false;
+            }
+            res = &mut stream_to_pty => {
+                pty_to_stream.abort();
+                if let Err(e) = res {
+                    error!("PTY→stream task panicked: {e}");
+                }
+                break false;
+            }
+            grace = handle.draining(), if !warned => {
+                warned = true;
+                let notice = format!(
+                    "\r\n*** backtor: server shutting down, closing this session in {}s ***\r\n",
+                    grace.as_secs(),
+                );
+                if let Some(tx) = notice_tx.upgrade() {
+                    let _ = tx.send(notice.into_bytes()).await;
+                }
+            }
+            () = sleep_until(wakeup) => match timers.poll(Instant::now()) {
+                Some(TimerEvent::Warn(notice)) => {
+                    if let Some(tx) = notice_tx.upgrade() {
+                        let _ = tx.send(notice.into_bytes()).await;
+                    }
+                }
+                Some(TimerEvent::Expire(reason)) => {
+                    info!("Closing shell session {session}: {reason}");
+                    stream_to_pty.abort();
+                    let notice = format!("\r\n*** backtor: {reason}, closing this session ***\r\n");
+                    if control_tx.send(OutputControl::Close(notice)).is_ok() {
+                        let _ = tokio::time::timeout(NOTICE_FLUSH_TIMEOUT, &mut pty_to_stream).await;
+                    }
+                    pty_to_stream.abort();
+                    break true;
+                }
+                None => {}
+            },
+            () = handle.cancelled() => {
+                debug!("Shell session {session} killed");
+                stream_to_pty.abort();
+                pty_to_stream.abort();
+                break true;
+            }
+        }
+    };
+
+    // Kill the shell if it is still running, then reap it so we can report
+    // how it ended. A killed session takes the jobs it started along: the
+    // shell leads its own process group.
+    let process_group = child.process_id().filter(|_| killed);
+    let status = tokio::task::spawn_blocking(move || match child.try_wait() {
+        Ok(Some(status)) => Some(status),
+        _ => {
+            signal_process_group(process_group, Signal::Hangup);
+            let _ = child.kill();
+            let status = child.wait().ok();
+            signal_process_group(process_group, Signal::Kill);
+            status
+        }
+    })
+    .await
+    .ok()
+    .flatten();
+
+    audit::record(AuditEvent::SessionEnd {
+        service: &service,
+        circuit,
+        session,
+        duration_secs: started.elapsed().as_secs_f64(),
+        bytes_in: bytes_in.load(Ordering::Relaxed),
+        bytes_out: bytes_out.load(Ordering::Relaxed),
+        exit_code: status.as_ref().map(|s| s.exit_code()),
+        signal: status.as_ref().and_then(|s| s.signal()),
+    });
+    debug!("Shell connection closed");
+}
+
+/// How long before closing an idle or overlong session its client is
+/// warned, at most.
+const TIMEOUT_WARNING: Duration = Duration::from_secs(60);
+
+/// How long a session that timed out gets to deliver its last notice.
+const NOTICE_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Instructions for the task writing a shell's output to its Tor stream.
+enum OutputControl {
+    /// The client asked for frames; acknowledge and send frames from now on.
+    Upgrade,
+    /// Answer a ping of the client.
+    Pong(u64),
+    /// The client answered the ping carrying this count.
+    Answered(u64),
+    /// The client asked for the screen, at this size.
+    Sync { rows: u16, cols: u16 },
+    /// Write this notice and stop.
+    Close(String),
+}
+
+/// Writes shell output to the client, in a frame if it asked for frames.
+async fn write_output<W>(writer: &mut W, framed: bool, data: Vec<u8>) -> Result<(), Error>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    if framed {
+        shell_protocol::write_frame(writer, &Frame::Data(data)).await
+    } else {
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// When something last happened in a shell session, e.g. input or output.
+struct Activity {
+    started: Instant,
+    /// Milliseconds from `started` to the last time it happened.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// What [`SessionTimers::poll`] found.
+enum TimerEvent {
+    /// Tell the client the session is about to be closed.
+    Warn(String),
+    /// Close the session, for this reason.
+    Expire(String),
+}
+
+/// The idle timeout and maximum duration of one shell session.
+struct SessionTimers {
+    idle_timeout: Option<Duration>,
+    max_session_time: Option<Duration>,
+    activity: Arc<Activity>,
+    /// The idle deadline the client was last warned of; activity since then
+    /// moved the deadline.
+    idle_warned: Option<Instant>,
+    time_warned: bool,
+}
+
+impl SessionTimers {
+    fn new(shell_config: &ShellConfig, activity: Arc<Activity>) -> Self {
+        SessionTimers {
+            idle_timeout: shell_config.idle_timeout,
+            max_session_time: shell_config.max_session_time,
+            activity,
+            idle_warned: None,
+            time_warned: false,
+        }
+    }
+
+    /// When [`poll`](Self::poll) may next have something to report.
+    fn next_wakeup(&self) -> Option<Instant> {
+        let time = self.max_session_time.map(|limit| {
+            let deadline = self.activity.started + limit;
+            if self.time_warned {
+                deadline
+            } else {
+                deadline - warning_lead(limit)
+            }
+        });
+        let idle = self.idle_timeout.map(|limit| {
+            let deadline = self.activity.last() + limit;
+            if self.idle_warned == Some(deadline) {
+                deadline
+            } else {
+                deadline - warning_lead(limit)
+            }
+        });
+        time.into_iter().chain(idle).min()
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<TimerEvent> {
+        if let Some(limit) = self.max_session_time {
+            let deadline = self.activity.started + limit;
+            if now >= deadline {
+                return Some(TimerEvent::Expire(format!(
+                    "session time limit of {} reached",
+                    format_minutes(limit)
+                )));
+            }
+            if !self.time_warned && now >= deadline - warning_lead(limit) {
+                self.time_warned = true;
+                return Some(TimerEvent::Warn(format!(
+                    "\r\n*** backtor: this session reaches its time limit of {} in {}s ***\r\n",
+                    format_minutes(limit),
+                    (deadline - now).as_secs_f64().round(),
+                )));
             }
         }
-        res = &mut stream_to_pty => {
-            pty_to_stream.abort();
-            if let Err(e) = res {
-                error!("PTY→stream task panicked: {e}");
+        if let Some(limit) = self.idle_timeout {
+            let deadline = self.activity.last() + limit;
+            if now >= deadline {
+                return Some(TimerEvent::Expire(format!(
+                    "idle for {}",
+                    format_minutes(limit)
+                )));
+            }
+            if now >= deadline - warning_lead(limit) && self.idle_warned != Some(deadline) {
+                self.idle_warned = Some(deadline);
+                return Some(TimerEvent::Warn(format!(
+                    "\r\n*** backtor: this session is idle and will be closed in {}s ***\r\n",
+                    (deadline - now).as_secs_f64().round(),
+                )));
             }
         }
+        None
     }
+}
 
-    // Best-effort: kill the shell if it is still running.
-    let _ = child.kill();
-    debug!("Shell connection closed");
+/// How long before `limit` runs out the client is warned.
+fn warning_lead(limit: Duration) -> Duration {
+    (limit / 4).min(TIMEOUT_WARNING)
 }
 
-/// Starts a Tor onion service that gives remote callers an interactive shell.
+fn format_minutes(duration: Duration) -> String {
+    match duration.as_secs() / 60 {
+        1 => "1 minute".to_owned(),
+        minutes => format!("{minutes} minutes"),
+    }
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Signals [`signal_process_group`] can send.
+enum Signal {
+    Hangup,
+    Kill,
+}
+
+/// Sends `signal` to every process in the group led by `leader`, if any.
+fn signal_process_group(leader: Option<u32>, signal: Signal) {
+    #[cfg(unix)]
+    if let Some(leader) = leader {
+        let signal = match signal {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Kill => libc::SIGKILL,
+        };
+        unsafe { libc::killpg(leader as libc::pid_t, signal) };
+    }
+    #[cfg(not(unix))]
+    let _ = (leader, signal);
+}
+
+/// Accepts every incoming rendezvous request and flattens the resulting
+/// per-circuit stream requests into a single stream, tagging each request
+/// with a process-unique circuit number.
 ///
-/// Connections arrive on [`SHELL_PORT`] (22). Each connection is handed a
-/// freshly-spawned login shell through a PTY, making the service behave like a
-/// stripped-down, Tor-native SSH replacement.
+/// This is [`tor_hsservice::handle_rend_requests`] plus the bookkeeping the
+/// audit log needs to tell clients apart.
+fn accept_rend_requests<S>(
+    rend_requests: S,
+    service: Arc<str>,
+) -> impl Stream<Item = (u64, StreamRequest)>
+where
+    S: Stream<Item = RendRequest>,
+{
+    rend_requests.flat_map_unordered(None, move |rend_request| {
+        let circuit = NEXT_CIRCUIT_ID.fetch_add(1, Ordering::Relaxed);
+        let service = service.clone();
+        Box::pin(rend_request.accept())
+            .map(move |outcome| match outcome {
+                Ok(stream_requests) => {
+                    Either::Left(stream_requests.map(move |request| (circuit, request)))
+                }
+                Err(e) => {
+                    error!("Problem while accepting rendezvous request: {e}");
+                    audit::record(AuditEvent::CircuitRejected {
+                        service: &service,
+                        reason: &e.to_string(),
+                    });
+                    Either::Right(futures::stream::empty())
+                }
+            })
+            .flatten_stream()
+    })
+}
+
+/// A launched service and its rendezvous requests, or `None` if disabled.
+type Launched = Option<(
+    Arc<RunningOnionService>,
+    Pin<Box<dyn Stream<Item = RendRequest> + Send>>,
+)>;
+
+/// Erases the stream type of a launch result, which differs between the
+/// `launch_onion_service*` methods.
+fn boxed<S>(launched: Option<(Arc<RunningOnionService>, S)>) -> Launched
+where
+    S: Stream<Item = RendRequest> + Send + 'static,
+{
+    launched.map(|(service, stream)| (service, Box::pin(stream) as Pin<Box<_>>))
+}
+
+/// Starts a Tor onion service that gives remote callers an interactive shell.
 ///
-/// If `forward_proxy` is supplied the onion service is instead wired up to an
-/// existing local TCP listener via [`OnionServiceReverseProxy`], which is
-/// useful for tunnelling an actual SSH daemon (or any other service).
+/// Incoming streams are dispatched by virtual port according to the spec's
+/// `ports`. Several services may be started on the same `tor_client`.
+/// Shell ports (by default [`utils::DEFAULT_SHELL_PORT`], 23) hand each connection a
+/// freshly-spawned shell through a PTY, making the service behave like a
+/// stripped-down, Tor-native SSH replacement. Forwarded ports are connected to
+/// a local TCP or Unix-socket target, which is useful for tunnelling an actual
+/// SSH daemon, a web UI or a database alongside the shell.
 ///
 /// The onion address is printed to stdout once the service is fully reachable.
+/// The service is registered in [`RUNNING_ONION_SERVICES`] under its name
+/// until [`stop_service`] is called.
 pub(crate) async fn onion_service_from_sk(
     tor_client: TorClient<PreferredRuntime>,
-    secret_key: Option<[u8; 32]>,
-    forward_proxy: Option<(u16, SocketAddr)>,
-) {
-    let nickname = if let Some(sk) = secret_key {
-        format!(
-            "backtor-shell-{}",
-            get_onion_address(utils::keypair_from_sk(sk).public().as_bytes())
-        )
-    } else {
-        "backtor-shell".into()
-    };
+    spec: ServiceSpec,
+) -> Result<(), Error> {
+    let ServiceSpec {
+        name,
+        nickname,
+        secret_key,
+        ports,
+        authorized_clients,
+        dos,
+    } = spec;
+    let nickname = nickname.to_string();
+    if RUNNING_ONION_SERVICES.lock().unwrap().contains_key(&name) {
+        bail!("Service {name:?} is already running");
+    }
+
+    let mut svc_cfg_builder = OnionServiceConfigBuilder::default();
+    svc_cfg_builder.nickname(nickname.parse().unwrap());
+    dos.apply(&mut svc_cfg_builder);
+    let limiter = SessionLimiter::new(&dos);
+    if !authorized_clients.is_empty() {
+        let restricted = svc_cfg_builder.restricted_discovery();
+        restricted.enabled(true);
+        for (client, key) in authorized_clients {
+            debug!("Authorizing client {client} for {nickname}");
+            restricted.static_keys().access().push((client, key));
+        }
+    }
+    let svc_cfg = svc_cfg_builder.build()?;
 
-    let svc_cfg = OnionServiceConfigBuilder::default()
-        .nickname(nickname.parse().unwrap())
-        .build()
-        .unwrap();
-
-    let (onion_service, request_stream): (
-        _,
-        Pin<Box<dyn Stream<Item = tor_hsservice::RendRequest> + Send>>,
-    ) = if secret_key.is_none() {
-        match tor_client
-            .launch_onion_service(svc_cfg)
-            .expect("error creating onion service")
+    // Make it obvious when a service is about to get a new address.
+    if secret_key.is_none() {
+        let hsid_spec = HsIdKeypairSpecifier::new(svc_cfg.nickname().clone());
+        if tor_client
+            .keymgr()?
+            .get::<HsIdKeypair>(&hsid_spec)?
+            .is_some()
         {
-            Some((service, stream)) => (service, Box::pin(stream)),
-            None => {
-                panic!("Failed to launch onion service");
-            }
+            debug!("Reusing the stored key of service {name:?}");
+        } else {
+            info!("Service {name:?} has no stored key; creating a new address");
         }
-    } else {
-        let sk = secret_key.unwrap();
+    }
+
+    let launched = if let Some(sk) = secret_key {
         let expanded_key_pair = utils::keypair_from_sk(sk);
         let encodable_key = tor_hscrypto::pk::HsIdKeypair::from(expanded_key_pair);
 
-        match tor_client
-            .launch_onion_service_with_hsid(svc_cfg.clone(), encodable_key)
-            .expect("error creating onion service")
-        {
-            Some((service, stream)) => (service, Box::pin(stream)),
-            None => {
-                // Key already registered – reuse the existing slot.
-                match tor_client
-                    .launch_onion_service(svc_cfg)
-                    .expect("error creating onion service")
-                {
-                    Some((service, stream)) => (service, Box::pin(stream)),
-                    None => {
-                        panic!("Failed to launch onion service");
-                    }
-                }
+        match tor_client.launch_onion_service_with_hsid(svc_cfg.clone(), encodable_key) {
+            Ok(launched) => boxed(launched),
+            // The keystore already holds a key for this nickname (arti
+            // reports that as API misuse). Keyed services are named after
+            // their address, so it is this very key: reuse the existing slot.
+            Err(e) if e.kind() == ErrorKind::BadApiUsage => {
+                debug!("Reusing stored key for {nickname}: {e}");
+                boxed(tor_client.launch_onion_service(svc_cfg)?)
             }
+            Err(e) => return Err(e.into()),
         }
+    } else {
+        boxed(tor_client.launch_onion_service(svc_cfg)?)
+    };
+    let Some((onion_service, request_stream)) = launched else {
+        bail!("Onion service {name:?} is disabled");
     };
 
     debug!("Onion service status: {:?}", onion_service.status());
     let clone_onion_service = onion_service.clone();
 
+    let service: Arc<str> = onion_service
+        .onion_address()
+        .unwrap()
+        .display_unredacted()
+        .to_string()
+        .into();
+    audit::record(AuditEvent::ServiceStart {
+        service: &service,
+        nickname: &nickname,
+    });
+    let reachable_service = service.clone();
+    let announced_ports = ports.clone();
+
+    // Register the service so the control socket can report and stop it.
+    let cancel_token = CancellationToken::new();
+    RUNNING_ONION_SERVICES.lock().unwrap().insert(
+        name,
+        RunningService {
+            address: service.clone(),
+            service: onion_service.clone(),
+            cancel: cancel_token.clone(),
+        },
+    );
+    let announce_cancel = cancel_token.clone();
+
     // Announce the onion address as soon as the service is fully reachable.
     let _ = tor_client.clone().runtime().spawn(async move {
-        while let Some(event) = clone_onion_service.status_events().next().await {
-            if event.state().is_fully_reachable() {
-                break;
+        let mut events = clone_onion_service.status_events();
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) if event.state().is_fully_reachable() => break,
+                    Some(_) => {}
+                    None => return,
+                },
+                () = announce_cancel.cancelled() => return,
+            }
+        }
+        let address = clone_onion_service.onion_address().unwrap();
+        for (port, action) in &announced_ports {
+            match action {
+                PortAction::Shell(_) => info!(
+                    "Shell service available at: {}:{}",
+                    address.display_unredacted(),
+                    port,
+                ),
+                PortAction::Forward(target) => info!(
+                    "Forwarding {}:{} to {}",
+                    address.display_unredacted(),
+                    port,
+                    target,
+                ),
+                PortAction::Tunnel(_) => debug!(
+                    "Accepting client port forwards on {}:{}",
+                    address.display_unredacted(),
+                    port,
+                ),
+                PortAction::Transfer(policy) => info!(
+                    "File transfers available at: {}:{} (roots: {})",
+                    address.display_unredacted(),
+                    port,
+                    policy
+                        .roots
+                        .iter()
+                        .map(|root| root.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                PortAction::Sftp(_) => info!(
+                    "SFTP available at: {}:{}",
+                    address.display_unredacted(),
+                    port,
+                ),
             }
         }
-        info!(
-            "Shell service available at: {}:{}",
-            clone_onion_service
-                .onion_address()
-                .unwrap()
-                .display_unredacted(),
-            SHELL_PORT,
-        );
         debug!(
             "Onion service fully reachable: {:?}",
             clone_onion_service.status()
         );
+        audit::record(AuditEvent::ServiceReachable {
+            service: &reachable_service,
+        });
+        #[cfg(unix)]
+        crate::systemd::service_reachable();
     });
 
     let _ = tor_client.clone().runtime().spawn(async move {
-        let cancel_token = CancellationToken::new();
-
-        // Register the cancellation token so callers can stop the service.
-        {
-            let mut running = RUNNING_ONION_SERVICES.lock().unwrap();
-            running.insert(
-                onion_service
-                    .onion_address()
-                    .unwrap()
-                    .display_unredacted()
-                    .to_string()
-                    .trim_end_matches(".onion")
-                    .to_owned(),
-                cancel_token.clone(),
-            );
-        }
-
-        if let Some((local_port, target_addr)) = forward_proxy {
-            // ----------------------------------------------------------------
-            // Forward mode: proxy onion-service traffic to a local TCP socket.
-            // Useful for tunnelling a real SSH daemon.
-            // ----------------------------------------------------------------
-            let proxy_rule = ProxyRule::new(
-                ProxyPattern::one_port(local_port)
-                    .map_err(|e| error!("Invalid port: {e}"))
-                    .unwrap(),
-                ProxyAction::Forward(Encapsulation::Simple, TargetAddr::Inet(target_addr)),
-            );
-
-            let mut proxy_config = ProxyConfigBuilder::default();
-            proxy_config.set_proxy_ports(vec![proxy_rule]);
-            let proxy = OnionServiceReverseProxy::new(
-                proxy_config.build().expect("proxy config incomplete"),
-            );
+        // ----------------------------------------------------------------
+        // Accept connections and dispatch them by virtual port: shell ports
+        // get a PTY shell for each connection, forwarded ports are spliced
+        // to their local target. Streams beyond the session limits are
+        // rejected or wait for a free slot.
+        // ----------------------------------------------------------------
+        let accepted_streams = accept_rend_requests(request_stream, service.clone());
+        tokio::pin!(accepted_streams);
 
+        loop {
             tokio::select! {
-                result = proxy.handle_requests(
-                    tor_client.runtime().clone(),
-                    nickname.parse().unwrap(),
-                    request_stream,
-                ) => {
-                    match result {
-                        Ok(()) => debug!("Reverse proxy finished normally"),
-                        Err(e) => error!("Reverse proxy error: {e}"),
-                    }
-                }
-                () = cancel_token.cancelled() => {
-                    debug!("Onion service cancelled via token (forward mode)");
-                }
-            }
-        } else {
-            // ----------------------------------------------------------------
-            // Direct shell mode: accept connections and spawn a PTY shell for
-            // each one.
-            // ----------------------------------------------------------------
-            let accepted_streams = tor_hsservice::handle_rend_requests(request_stream);
-            tokio::pin!(accepted_streams);
-
-            loop {
-                tokio::select! {
-                    Some(stream_request) = accepted_streams.next() => {
-                        let request = stream_request.request().clone();
-                        match request {
-                            IncomingStreamRequest::Begin(begin)
-                                if begin.port() == SHELL_PORT =>
-                            {
-                                debug!("Accepting shell connection on port {SHELL_PORT}");
-                                match stream_request.accept(Connected::new_empty()).await {
-                                    Ok(data_stream) => {
-                                        // Bridge futures-style async I/O (arti DataStream)
-                                        // to tokio-style async I/O expected by our handler.
-                                        let compat_stream = data_stream.compat();
-                                        tokio::spawn(handle_shell_connection(compat_stream));
+                Some((circuit, stream_request)) = accepted_streams.next() => {
+                    let request = stream_request.request().clone();
+                    let port = match &request {
+                        IncomingStreamRequest::Begin(begin) => Some(begin.port()),
+                        _ => None,
+                    };
+                    let action = port.and_then(|port| ports.get(&port));
+
+                    match (port, action) {
+                        (Some(port), Some(action)) => {
+                            let admitted = limiter.try_admit(circuit);
+                            let action = action.clone();
+                            let service = service.clone();
+                            let limiter = limiter.clone();
+                            let cancel = cancel_token.clone();
+                            tokio::spawn(async move {
+                                let admitted = match admitted {
+                                    Err(_) if limiter.when_limited() == WhenLimited::Queue => {
+                                        debug!("Queueing stream on port {port}");
+                                        tokio::select! {
+                                            admitted = limiter.admit(circuit) => admitted,
+                                            () = cancel.cancelled() => Err(Limited::Stopped),
+                                        }
                                     }
-                                    Err(e) => {
-                                        error!("Failed to accept stream: {e}");
+                                    admitted => admitted,
+                                };
+                                // A stream queued while the service was
+                                // stopped must not be served after all.
+                                let admitted = admitted.and_then(|permit| {
+                                    if cancel.is_cancelled() {
+                                        Err(Limited::Stopped)
+                                    } else {
+                                        Ok(permit)
                                     }
-                                }
-                            }
-                            _ => {
-                                debug!(
-                                    "Rejecting stream request for unexpected port/type"
-                                );
-                                stream_request.shutdown_circuit().unwrap_or_else(|e| {
-                                    error!("Error shutting down circuit: {e}");
                                 });
-                            }
+                                match admitted {
+                                    Ok(permit) => {
+                                        serve_stream(stream_request, action, service, circuit, port)
+                                            .await;
+                                        drop(permit);
+                                    }
+                                    Err(limited) => {
+                                        limiter.warn_rejected(&service, limited);
+                                        audit::record(AuditEvent::StreamRejected {
+                                            service: &service,
+                                            circuit,
+                                            port: Some(port),
+                                            reason: limited.reason(),
+                                        });
+                                        let end = End::new_with_reason(EndReason::RESOURCELIMIT);
+                                        if let Err(e) = stream_request.reject(end).await {
+                                            debug!("Failed to reject stream: {e}");
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                        _ => {
+                            debug!("Rejecting stream request for unexpected port/type");
+                            audit::record(AuditEvent::StreamRejected {
+                                service: &service,
+                                circuit,
+                                port,
+                                reason: "unexpected port or request type",
+                            });
+                            stream_request.shutdown_circuit().unwrap_or_else(|e| {
+                                error!("Error shutting down circuit: {e}");
+                            });
                         }
-                    }
-                    () = cancel_token.cancelled() => {
-                        debug!("Onion service shutting down");
-                        return;
                     }
                 }
+                () = cancel_token.cancelled() => {
+                    debug!("Onion service shutting down");
+                    // Dropping the last handle takes the service offline.
+                    drop(onion_service);
+                    return;
+                }
             }
         }
     });
+    Ok(())
+}
+
+/// Accepts a stream to `port` and serves it as `action` says, until the
+/// session ends.
+async fn serve_stream(
+    stream_request: StreamRequest,
+    action: PortAction,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) {
+    debug!("Accepting connection on port {port}");
+    let data_stream = match stream_request.accept(Connected::new_empty()).await {
+        Ok(data_stream) => data_stream,
+        Err(e) => {
+            error!("Failed to accept stream: {e}");
+            return;
+        }
+    };
+    audit::record(AuditEvent::StreamAccepted {
+        service: &service,
+        circuit,
+        port,
+    });
+    // Bridge futures-style async I/O (arti DataStream) to tokio-style async
+    // I/O expected by our handlers.
+    let compat_stream = data_stream.compat();
+    match action {
+        PortAction::Shell(shell) => {
+            handle_shell_connection(compat_stream, shell, service, circuit, port).await
+        }
+        PortAction::Forward(target) => {
+            handle_forward_connection(compat_stream, target, service, circuit, port).await
+        }
+        PortAction::Tunnel(policy) => {
+            handle_tunnel_connection(compat_stream, policy, service, circuit, port).await
+        }
+        PortAction::Transfer(policy) => {
+            handle_transfer_connection(compat_stream, policy, service, circuit, port).await
+        }
+        PortAction::Sftp(policy) => {
+            handle_sftp_connection(compat_stream, policy, service, circuit, port).await
+        }
+    }
+}
+
+/// Stops the running service called `name` and ends its sessions.
+///
+/// Returns the service's address, or `None` if no such service runs.
+pub(crate) fn stop_service(name: &str) -> Option<Arc<str>> {
+    let running = RUNNING_ONION_SERVICES.lock().unwrap().remove(name)?;
+    running.cancel.cancel();
+    let killed = SESSIONS.kill_service(&running.address);
+    audit::record(AuditEvent::ServiceStop {
+        service: &running.address,
+    });
+    info!("Stopped service {name} ({killed} sessions ended)");
+    Some(running.address)
+}
+
+/// Stops every service, then drains their sessions: clients are warned,
+/// get `grace` to finish, and whatever is left after that is killed.
+pub(crate) async fn shutdown(grace: Duration) {
+    let running: Vec<_> = RUNNING_ONION_SERVICES.lock().unwrap().drain().collect();
+    for (name, running) in running {
+        running.cancel.cancel();
+        audit::record(AuditEvent::ServiceStop {
+            service: &running.address,
+        });
+        debug!("Stopped service {name}");
+    }
+
+    end_sessions(&SESSIONS, grace).await;
+}
+
+/// Drains the sessions in `registry`, killing those still alive after
+/// `grace`.
+async fn end_sessions(registry: &Registry, grace: Duration) {
+    let live = registry.drain_all(grace);
+    if live == 0 {
+        return;
+    }
+    info!(
+        "Waiting up to {}s for {live} sessions to end",
+        grace.as_secs()
+    );
+    if tokio::time::timeout(grace, registry.wait_until_empty())
+        .await
+        .is_err()
+    {
+        let killed = registry.kill_all();
+        info!("Killing {killed} remaining sessions");
+        // Give the sessions a moment to reap their processes and write
+        // their audit records.
+        let _ = tokio::time::timeout(SESSION_REAP_TIMEOUT, registry.wait_until_empty()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn timers(idle_timeout: Option<Duration>, max_session_time: Option<Duration>) -> SessionTimers {
+        SessionTimers {
+            idle_timeout,
+            max_session_time,
+            activity: Arc::new(Activity::new()),
+            idle_warned: None,
+            time_warned: false,
+        }
+    }
+
+    /// Records activity `after` the session started.
+    fn touch_at(timers: &SessionTimers, after: Duration) {
+        let millis = after.as_millis() as u64;
+        timers.activity.last.store(millis, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn warns_before_idle_expiry() {
+        let mut timers = timers(Some(10 * MINUTE), None);
+        let start = timers.activity.started;
+
+        assert!(timers.poll(start + 8 * MINUTE).is_none());
+        assert_eq!(timers.next_wakeup(), Some(start + 9 * MINUTE));
+        let warning = timers.poll(start + 9 * MINUTE);
+        assert!(matches!(warning, Some(TimerEvent::Warn(m)) if m.contains("closed in 60s")));
+        assert!(
+            timers
+                .poll(start + 9 * MINUTE + Duration::from_secs(1))
+                .is_none()
+        );
+        assert_eq!(timers.next_wakeup(), Some(start + 10 * MINUTE));
+        let expiry = timers.poll(start + 10 * MINUTE);
+        assert!(matches!(expiry, Some(TimerEvent::Expire(m)) if m == "idle for 10 minutes"));
+    }
+
+    #[test]
+    fn activity_moves_the_idle_deadline_and_rearms_the_warning() {
+        let mut timers = timers(Some(10 * MINUTE), None);
+        let start = timers.activity.started;
+        assert!(matches!(
+            timers.poll(start + 9 * MINUTE),
+            Some(TimerEvent::Warn(_))
+        ));
+
+        touch_at(&timers, 9 * MINUTE + MINUTE / 2);
+        assert!(timers.poll(start + 10 * MINUTE).is_none());
+        let rearmed = start + 18 * MINUTE + MINUTE / 2;
+        assert_eq!(timers.next_wakeup(), Some(rearmed));
+        assert!(timers.poll(rearmed - Duration::from_secs(1)).is_none());
+        assert!(matches!(timers.poll(rearmed), Some(TimerEvent::Warn(_))));
+        assert!(matches!(
+            timers.poll(rearmed + MINUTE),
+            Some(TimerEvent::Expire(_))
+        ));
+    }
+
+    #[test]
+    fn expires_at_the_maximum_session_time_despite_activity() {
+        let mut timers = timers(Some(10 * MINUTE), Some(30 * MINUTE));
+        let start = timers.activity.started;
+
+        touch_at(&timers, 28 * MINUTE);
+        assert_eq!(timers.next_wakeup(), Some(start + 29 * MINUTE));
+        let warning = timers.poll(start + 29 * MINUTE);
+        assert!(
+            matches!(warning, Some(TimerEvent::Warn(m)) if m.contains("time limit of 30 minutes in 60s"))
+        );
+        assert!(timers.poll(start + 29 * MINUTE + MINUTE / 2).is_none());
+        assert_eq!(timers.next_wakeup(), Some(start + 30 * MINUTE));
+
+        touch_at(&timers, 30 * MINUTE - Duration::from_secs(1));
+        let expiry = timers.poll(start + 30 * MINUTE);
+        assert!(
+            matches!(expiry, Some(TimerEvent::Expire(m)) if m == "session time limit of 30 minutes reached")
+        );
+    }
+
+    #[test]
+    fn short_limits_warn_a_quarter_ahead() {
+        assert_eq!(
+            warning_lead(Duration::from_secs(20)),
+            Duration::from_secs(5)
+        );
+        assert_eq!(warning_lead(2 * MINUTE), Duration::from_secs(30));
+        assert_eq!(warning_lead(60 * MINUTE), TIMEOUT_WARNING);
+
+        let mut timers = timers(Some(2 * MINUTE), None);
+        let start = timers.activity.started;
+        assert_eq!(timers.next_wakeup(), Some(start + Duration::from_secs(90)));
+        assert!(timers.poll(start + Duration::from_secs(89)).is_none());
+        let warning = timers.poll(start + Duration::from_secs(90));
+        assert!(matches!(warning, Some(TimerEvent::Warn(m)) if m.contains("closed in 30s")));
+        assert!(matches!(
+            timers.poll(start + 2 * MINUTE),
+            Some(TimerEvent::Expire(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_waits_out_the_grace_then_kills_the_rest() {
+        let registry: &'static Registry = Box::leak(Box::new(Registry::new()));
+        let grace = Duration::from_secs(10);
+        let start = tokio::time::Instant::now();
+
+        // Finishes up once warned, like a shell whose client logs out.
+        let polite = registry.register("shutdown.onion", 1, 23, "shell", "");
+        let polite = tokio::spawn(async move {
+            let warned = polite.draining().await;
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            (warned, start.elapsed())
+        });
+        // Only ends when killed.
+        let stubborn = registry.register("shutdown.onion", 2, 23, "shell", "");
+        let stubborn = tokio::spawn(async move {
+            stubborn.cancelled().await;
+            start.elapsed()
+        });
+
+        end_sessions(registry, grace).await;
+        let (warned, ended) = polite.await.unwrap();
+        assert_eq!(warned, grace);
+        assert!(ended < grace);
+        assert!(stubborn.await.unwrap() >= grace);
+        assert!(registry.list().is_empty());
+        assert!(start.elapsed() < grace + SESSION_REAP_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_returns_once_the_sessions_end() {
+        let registry: &'static Registry = Box::leak(Box::new(Registry::new()));
+        let start = tokio::time::Instant::now();
+        let session = registry.register("shutdown.onion", 1, 23, "shell", "");
+        tokio::spawn(async move { session.draining().await });
+        end_sessions(registry, MINUTE).await;
+        assert!(registry.list().is_empty());
+        // Well before the grace, so nothing was killed.
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    fn shell_port(s: &str) -> Result<(u16, Vec<String>), String> {
+        s.parse::<ShellPort>()
+            .map(|port| (port.port, port.shell.command))
+    }
+
+    #[test]
+    fn parses_shell_ports() {
+        assert_eq!(shell_port("23"), Ok((23, vec![])));
+        // An empty command runs the login shell, like no command at all.
+        assert_eq!(shell_port("23:"), Ok((23, vec![])));
+        assert_eq!(
+            shell_port("2222:/usr/bin/fish  --private"),
+            Ok((2222, vec!["/usr/bin/fish".into(), "--private".into()]))
+        );
+        assert_eq!(
+            shell_port("2323:top -d 1"),
+            Ok((2323, vec!["top".into(), "-d".into(), "1".into()]))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_shell_ports() {
+        for bad in ["0", "0:/bin/sh", "", ":/bin/sh", "65536", "ssh:/bin/sh"] {
+            assert!(shell_port(bad).is_err(), "{bad:?} accepted");
+        }
+        let error = shell_port("23:sh -c 'echo hi'").unwrap_err();
+        assert!(error.contains("quotes are not supported"), "{error}");
+        assert!(shell_port("23:echo \"hi there\"").is_err());
+    }
 }
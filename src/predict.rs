@@ -0,0 +1,350 @@
+//! Predictive local echo for `backtor connect --predict`, after mosh.
+//!
+//! Over Tor, a keystroke takes a round trip of half a second or more to show
+//! up. With predictions, printable keystrokes are drawn at once, underlined,
+//! and replaced by the server's echo when it arrives. To know whether the
+//! echo matches, the client keeps a model of the screen the server drew.
+//!
+//! Predictions are only shown once the current line has echoed a keystroke
+//! as predicted, so nothing typed at a password prompt is ever drawn, and
+//! are dropped when the server draws something else or does not answer in
+//! time.
+
+use std::time::{Duration, Instant};
+
+/// How long a prediction waits for the server's echo when the round trip is
+/// not known yet.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Underlines what is drawn next.
+const PREDICTION_STYLE: &[u8] = b"\x1b[0;4m";
+
+/// A keystroke drawn before the server echoed it.
+#[derive(Debug)]
+struct Prediction {
+    row: u16,
+    col: u16,
+    key: u8,
+    typed: Instant,
+}
+
+/// The screen the server drew, and the keystrokes it has yet to echo.
+///
+/// Every method returns what to write to the terminal, in place of the
+/// server's output or in addition to it.
+pub(crate) struct Predictor {
+    screen: vt100::Parser,
+    pending: Vec<Prediction>,
+    /// The current line echoed a keystroke as predicted, so pending ones are
+    /// drawn.
+    echoing: bool,
+    /// Pending predictions are on the terminal, and the cursor is after them.
+    shown: bool,
+    /// Typed something whose effect we cannot predict; wait for the server
+    /// before predicting again.
+    frozen: bool,
+}
+
+impl Predictor {
+    /// Starts with a blank screen of `rows` by `cols` and the cursor where
+    /// the terminal's is, given as (column, row) like crossterm does.
+    pub(crate) fn new(rows: u16, cols: u16, cursor: (u16, u16)) -> Self {
+        let mut screen = vt100::Parser::new(rows, cols, 0);
+        let (col, row) = cursor;
+        screen.process(format!("\x1b[{};{}H", row + 1, col + 1).as_bytes());
+        Predictor {
+            screen,
+            pending: Vec::new(),
+            echoing: false,
+            shown: false,
+            frozen: false,
+        }
+    }
+
+    /// Takes note of a new terminal size, dropping the predictions, which
+    /// the terminal may have moved anywhere.
+    pub(crate) fn resize(&mut self, rows: u16, cols: u16) {
+        if self.screen.screen().size() != (rows, cols) {
+            self.screen.screen_mut().set_size(rows, cols);
+            self.pending.clear();
+            self.shown = false;
+            self.echoing = false;
+        }
+    }
+
+    /// Output from the server.
+    pub(crate) fn output(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = self.erase();
+        out.extend_from_slice(data);
+        self.screen.process(data);
+
+        let screen = self.screen.screen();
+        let cursor_row = screen.cursor_position().0;
+        while let Some(prediction) = self.pending.first() {
+            let contents = screen
+                .cell(prediction.row, prediction.col)
+                .map_or("", vt100::Cell::contents);
+            if contents.as_bytes() == [prediction.key] {
+                self.pending.remove(0);
+                self.echoing = true;
+            } else if contents.is_empty() && prediction.row == cursor_row {
+                // Not echoed yet.
+                break;
+            } else {
+                self.pending.clear();
+                self.echoing = false;
+            }
+        }
+        if self.pending.is_empty() {
+            self.frozen = false;
+        }
+        if !self.predictable() {
+            self.pending.clear();
+        }
+        out.extend(self.draw());
+        out
+    }
+
+    /// Keystrokes on their way to the server.
+    pub(crate) fn input(&mut self, keys: &[u8]) -> Vec<u8> {
+        let mut out = self.erase();
+        for &key in keys {
+            match key {
+                b' '..=b'~' if self.predictable() && !self.frozen => {
+                    let (row, col) = match self.pending.last() {
+                        Some(last) => (last.row, last.col + 1),
+                        None => self.screen.screen().cursor_position(),
+                    };
+                    // Leave the last column alone; what happens there
+                    // depends on how the terminal wraps.
+                    if col + 1 >= self.screen.screen().size().1 {
+                        self.frozen = true;
+                        continue;
+                    }
+                    self.pending.push(Prediction {
+                        row,
+                        col,
+                        key,
+                        typed: Instant::now(),
+                    });
+                }
+                // Backspace over a keystroke the server has not seen yet.
+                0x7f | 0x08 if !self.frozen && !self.pending.is_empty() => {
+                    self.pending.pop();
+                }
+                _ => {
+                    self.frozen = true;
+                    // The next line may be a password prompt. Echoes still
+                    // to come from this one must not vouch for it.
+                    if key == b'\r' {
+                        self.echoing = false;
+                        self.pending.clear();
+                    }
+                }
+            }
+        }
+        out.extend(self.draw());
+        out
+    }
+
+    /// When the oldest prediction runs out of time, if there is one.
+    pub(crate) fn deadline(&self, round_trip: Option<Duration>) -> Option<Instant> {
+        let first = self.pending.first()?;
+        Some(first.typed + timeout(round_trip))
+    }
+
+    /// Drops the predictions if the oldest one ran out of time: the line
+    /// does not echo after all.
+    pub(crate) fn expire(&mut self, round_trip: Option<Duration>) -> Vec<u8> {
+        match self.deadline(round_trip) {
+            Some(deadline) if Instant::now() >= deadline => {
+                let out = self.erase();
+                self.pending.clear();
+                self.echoing = false;
+                self.frozen = false;
+                out
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether what the server shows is a plain line editor, as opposed to a
+    /// full-screen program that may do anything with a keystroke.
+    fn predictable(&self) -> bool {
+        let screen = self.screen.screen();
+        !screen.alternate_screen() && !screen.hide_cursor()
+    }
+
+    /// Draws the pending predictions over the screen, if the line echoes.
+    fn draw(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.echoing || self.pending.is_empty() {
+            return out;
+        }
+        for prediction in &self.pending {
+            move_to(&mut out, prediction.row, prediction.col);
+            out.extend_from_slice(PREDICTION_STYLE);
+            out.push(prediction.key);
+        }
+        out.extend(self.screen.screen().attributes_formatted());
+        self.shown = true;
+        out
+    }
+
+    /// Restores the cells under the predictions, the cursor and the drawing
+    /// attributes to what the server drew.
+    fn erase(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.shown {
+            return out;
+        }
+        let screen = self.screen.screen();
+        for prediction in &self.pending {
+            move_to(&mut out, prediction.row, prediction.col);
+            match screen.cell(prediction.row, prediction.col) {
+                Some(cell) => write_cell(&mut out, cell),
+                None => out.push(b' '),
+            }
+        }
+        let (row, col) = screen.cursor_position();
+        move_to(&mut out, row, col);
+        out.extend(screen.attributes_formatted());
+        self.shown = false;
+        out
+    }
+}
+
+/// How long predictions wait for their echo.
+fn timeout(round_trip: Option<Duration>) -> Duration {
+    match round_trip {
+        Some(round_trip) => (round_trip * 2).max(Duration::from_secs(1)),
+        None => DEFAULT_TIMEOUT,
+    }
+}
+
+fn move_to(out: &mut Vec<u8>, row: u16, col: u16) {
+    out.extend_from_slice(format!("\x1b[{};{}H", row + 1, col + 1).as_bytes());
+}
+
+/// Draws `cell` as the server did.
+fn write_cell(out: &mut Vec<u8>, cell: &vt100::Cell) {
+    let mut sgr = vec!["0".to_owned()];
+    for (on, code) in [
+        (cell.bold(), "1"),
+        (cell.dim(), "2"),
+        (cell.italic(), "3"),
+        (cell.underline(), "4"),
+        (cell.inverse(), "7"),
+    ] {
+        if on {
+            sgr.push(code.to_owned());
+        }
+    }
+    for (color, base) in [(cell.fgcolor(), 38), (cell.bgcolor(), 48)] {
+        match color {
+            vt100::Color::Default => {}
+            vt100::Color::Idx(index) => sgr.push(format!("{base};5;{index}")),
+            vt100::Color::Rgb(r, g, b) => sgr.push(format!("{base};2;{r};{g};{b}")),
+        }
+    }
+    out.extend_from_slice(format!("\x1b[{}m", sgr.join(";")).as_bytes());
+    match cell.contents() {
+        "" => out.push(b' '),
+        contents => out.extend_from_slice(contents.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A predictor at a shell prompt whose line has echoed a keystroke.
+    fn echoing() -> Predictor {
+        let mut predictor = Predictor::new(24, 80, (0, 0));
+        predictor.output(b"$ ");
+        predictor.input(b"l");
+        predictor.output(b"l");
+        assert!(predictor.echoing);
+        predictor
+    }
+
+    fn drawn(out: &[u8], key: u8) -> bool {
+        let prediction = [PREDICTION_STYLE, &[key]].concat();
+        out.windows(prediction.len()).any(|w| w == prediction)
+    }
+
+    #[test]
+    fn draws_nothing_before_the_line_echoes() {
+        let mut predictor = Predictor::new(24, 80, (0, 0));
+        predictor.output(b"Password: ");
+        assert_eq!(predictor.input(b"secret"), b"");
+        assert_eq!(predictor.output(b"\r\n"), b"\r\n");
+    }
+
+    #[test]
+    fn draws_keystrokes_once_the_line_echoes() {
+        let mut predictor = echoing();
+        let out = predictor.input(b"s");
+        assert!(drawn(&out, b's'));
+        assert_eq!(predictor.pending.len(), 1);
+        // The echo replaces the prediction.
+        let out = predictor.output(b"s");
+        assert!(out.ends_with(b"s"));
+        assert!(predictor.pending.is_empty());
+    }
+
+    #[test]
+    fn stops_after_enter_until_the_next_line_echoes() {
+        let mut predictor = echoing();
+        predictor.input(b"s\r");
+        predictor.output(b"s\r\nPassword: ");
+        assert!(!drawn(&predictor.input(b"x"), b'x'));
+    }
+
+    #[test]
+    fn late_echoes_do_not_carry_over_to_the_next_line() {
+        let mut predictor = echoing();
+        predictor.input(b"s\r");
+        predictor.output(b"s");
+        predictor.output(b"\r\nPassword: ");
+        assert!(!drawn(&predictor.input(b"x"), b'x'));
+    }
+
+    #[test]
+    fn drops_predictions_the_server_contradicts() {
+        let mut predictor = echoing();
+        predictor.input(b"ab");
+        predictor.output(b"X");
+        assert!(predictor.pending.is_empty());
+        assert!(!predictor.echoing);
+    }
+
+    #[test]
+    fn backspace_takes_back_a_pending_keystroke() {
+        let mut predictor = echoing();
+        predictor.input(b"ab\x7f");
+        assert_eq!(predictor.pending.len(), 1);
+        assert_eq!(predictor.pending[0].key, b'a');
+    }
+
+    #[test]
+    fn does_not_predict_full_screen_programs() {
+        let mut predictor = echoing();
+        predictor.output(b"\x1b[?1049h");
+        predictor.input(b"j");
+        assert!(predictor.pending.is_empty());
+    }
+
+    #[test]
+    fn waits_at_least_a_second_for_the_echo() {
+        assert_eq!(timeout(None), DEFAULT_TIMEOUT);
+        assert_eq!(
+            timeout(Some(Duration::from_millis(100))),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            timeout(Some(Duration::from_secs(2))),
+            Duration::from_secs(4)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Screen-state synchronisation for shell sessions, after mosh's SSP.
+//!
+//! On a slow circuit, the raw output of a busy program queues up, and
+//! everything behind it waits: the prompt after a Ctrl-C can take minutes to
+//! arrive. A client can ask for the screen instead ([`Frame::Sync`]). The
+//! server then runs the output through a terminal emulator and sends what
+//! changed since the last update, at most every [`UPDATE_INTERVAL`] and with
+//! at most [`UPDATES_IN_FLIGHT`] updates the client has not answered yet.
+//! Whatever the screen went through in between is never sent.
+//!
+//! An update is answered by the pong to the ping that follows it.
+//!
+//! [`Frame::Sync`]: crate::shell_protocol::Frame::Sync
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How often updates are sent at most.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many updates may be on their way to the client at once.
+const UPDATES_IN_FLIGHT: usize = 2;
+
+/// Largest screen a client may ask for, in rows and in columns.
+pub(crate) const MAX_SCREEN_SIZE: u16 = 500;
+
+/// The screen of a shell session, and what its client has been sent of it.
+pub(crate) struct ScreenSync {
+    parser: vt100::Parser<Bell>,
+    /// The screen as of the last update, or `None` if the next one redraws
+    /// it all.
+    sent: Option<vt100::Screen>,
+    /// The pings that followed the updates not answered yet, oldest first.
+    unanswered: VecDeque<u64>,
+    /// The screen changed since the last update.
+    changed: bool,
+    /// When the next update may be sent.
+    not_before: Instant,
+}
+
+impl ScreenSync {
+    /// Starts with a blank screen of `rows` by `cols`.
+    pub(crate) fn new(rows: u16, cols: u16) -> Self {
+        ScreenSync {
+            parser: vt100::Parser::new_with_callbacks(rows, cols, 0, Bell::default()),
+            sent: None,
+            unanswered: VecDeque::new(),
+            changed: false,
+            not_before: Instant::now(),
+        }
+    }
+
+    /// Output of the shell.
+    pub(crate) fn process(&mut self, data: &[u8]) {
+        self.parser.process(data);
+        self.changed = true;
+    }
+
+    /// Changes the size of the screen, which the client redraws from
+    /// scratch.
+    pub(crate) fn resize(&mut self, rows: u16, cols: u16) {
+        self.parser.screen_mut().set_size(rows, cols);
+        self.sent = None;
+        self.changed = true;
+    }
+
+    /// When the next update is due, if there is anything to send and the
+    /// client kept up with the last ones.
+    pub(crate) fn next_update(&self) -> Option<Instant> {
+        (self.changed && self.unanswered.len() < UPDATES_IN_FLIGHT).then_some(self.not_before)
+    }
+
+    /// The bytes that bring the client's screen up to date, to be followed
+    /// by a ping carrying `ping`.
+    pub(crate) fn update(&mut self, ping: u64) -> Vec<u8> {
+        let screen = self.parser.screen();
+        let mut update = match &self.sent {
+            Some(sent) => screen.state_diff(sent),
+            // Start from plain attributes, whatever the terminal was doing.
+            None => [b"\x1b[m".as_slice(), &screen.state_formatted()].concat(),
+        };
+        self.sent = Some(screen.clone());
+        if std::mem::take(&mut self.parser.callbacks_mut().rang) {
+            update.push(0x07);
+        }
+        self.unanswered.push_back(ping);
+        self.changed = false;
+        self.not_before = Instant::now() + UPDATE_INTERVAL;
+        update
+    }
+
+    /// The client answered the ping carrying `pong`, and so has every update
+    /// sent before it.
+    pub(crate) fn answered(&mut self, pong: u64) {
+        while self.unanswered.front().is_some_and(|&ping| ping <= pong) {
+            self.unanswered.pop_front();
+        }
+    }
+}
+
+/// Notes bells, which leave no trace on the screen.
+#[derive(Default)]
+struct Bell {
+    rang: bool,
+}
+
+impl vt100::Callbacks for Bell {
+    fn audible_bell(&mut self, _: &mut vt100::Screen) {
+        self.rang = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_update_redraws_then_sends_changes() {
+        let mut screen = ScreenSync::new(4, 20);
+        screen.process(b"$ ");
+        let first = screen.update(1);
+        assert!(first.starts_with(b"\x1b[m"));
+        assert!(first.windows(2).any(|w| w == b"$ "));
+        screen.process(b"ls");
+        assert_eq!(screen.update(2), b"ls");
+    }
+
+    #[test]
+    fn waits_for_changes_and_answers() {
+        let mut screen = ScreenSync::new(4, 20);
+        assert_eq!(screen.next_update(), None);
+        screen.process(b"a");
+        assert!(screen.next_update().is_some());
+        screen.update(1);
+        assert_eq!(screen.next_update(), None);
+
+        screen.process(b"b");
+        screen.update(2);
+        screen.process(b"c");
+        // Two updates in flight: wait for the client.
+        assert_eq!(screen.next_update(), None);
+        screen.answered(1);
+        assert!(screen.next_update().is_some());
+        screen.answered(2);
+        assert!(screen.unanswered.is_empty());
+    }
+
+    #[test]
+    fn spaces_updates_out() {
+        let mut screen = ScreenSync::new(4, 20);
+        screen.process(b"a");
+        let sent = Instant::now();
+        screen.update(1);
+        screen.process(b"b");
+        assert!(screen.next_update().unwrap() >= sent + UPDATE_INTERVAL);
+    }
+
+    #[test]
+    fn resize_redraws_everything() {
+        let mut screen = ScreenSync::new(4, 20);
+        screen.process(b"$ ");
+        screen.update(1);
+        screen.resize(10, 40);
+        let update = screen.update(2);
+        assert!(update.starts_with(b"\x1b[m"));
+        assert!(update.windows(2).any(|w| w == b"$ "));
+    }
+
+    #[test]
+    fn passes_bells_on() {
+        let mut screen = ScreenSync::new(4, 20);
+        screen.process(b"\x07");
+        assert_eq!(screen.update(1).last(), Some(&0x07));
+        screen.process(b"x");
+        assert_eq!(screen.update(2), b"x");
+    }
+}
@@ -0,0 +1,257 @@
+//! Registry of the sessions currently being served, so that they can be
+//! listed and ended from the control socket (see [`crate::control`]) and
+//! drained when the server shuts down.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+// Process-wide counter used to label sessions in the audit log.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The sessions of this process.
+pub(crate) static SESSIONS: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+/// A set of live sessions and whether they are being drained.
+pub(crate) struct Registry {
+    sessions: Mutex<BTreeMap<u64, Entry>>,
+    /// Set to the grace period once the server starts shutting down.
+    draining: watch::Sender<Option<Duration>>,
+}
+
+struct Entry {
+    info: SessionInfo,
+    cancel: CancellationToken,
+}
+
+/// A live session as reported by `backtor ctl sessions`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SessionInfo {
+    pub(crate) id: u64,
+    /// Onion address of the service the session belongs to (its name in
+    /// control responses).
+    pub(crate) service: String,
+    pub(crate) circuit: u64,
+    pub(crate) port: u16,
+    /// `shell`, `forward`, `listen` (a `-R` listener), `transfer` or `sftp`.
+    pub(crate) kind: String,
+    /// The command, forward target or path the session works on.
+    pub(crate) detail: String,
+    /// Start time in seconds since the Unix epoch.
+    pub(crate) started: u64,
+}
+
+/// A registered session; dropping it removes the session from the registry.
+pub(crate) struct SessionHandle {
+    id: u64,
+    cancel: CancellationToken,
+    registry: &'static Registry,
+}
+
+impl SessionHandle {
+    /// The session number used in the audit log and by `backtor ctl kill`.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Resolves once the session has been killed from the control socket.
+    pub(crate) fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.cancel.cancelled()
+    }
+
+    /// Resolves with the grace period once the server starts shutting down,
+    /// so the session can warn its client.
+    pub(crate) async fn draining(&self) -> Duration {
+        let mut draining = self.registry.draining.subscribe();
+        // The registry owns the sender and outlives us, so waiting cannot fail.
+        let grace = draining.wait_for(Option::is_some).await.map(|grace| *grace);
+        grace.ok().flatten().unwrap_or_default()
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Registry {
+    pub(crate) fn new() -> Self {
+        Registry {
+            sessions: Mutex::new(BTreeMap::new()),
+            draining: watch::Sender::new(None),
+        }
+    }
+
+    /// Allocates a session number and registers the session.
+    pub(crate) fn register(
+        &'static self,
+        service: &str,
+        circuit: u64,
+        port: u16,
+        kind: &str,
+        detail: &str,
+    ) -> SessionHandle {
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancellationToken::new();
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let info = SessionInfo {
+            id,
+            service: service.to_owned(),
+            circuit,
+            port,
+            kind: kind.to_owned(),
+            detail: detail.to_owned(),
+            started,
+        };
+        self.sessions.lock().unwrap().insert(
+            id,
+            Entry {
+                info,
+                cancel: cancel.clone(),
+            },
+        );
+        SessionHandle {
+            id,
+            cancel,
+            registry: self,
+        }
+    }
+
+    /// All live sessions, oldest first.
+    pub(crate) fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().map(|entry| entry.info.clone()).collect()
+    }
+
+    /// Ends session `id`. Returns `false` if there is no such session.
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends every session of the service at `address`, returning how many.
+    pub(crate) fn kill_service(&self, address: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut killed = 0;
+        for entry in sessions.values() {
+            if entry.info.service == address {
+                entry.cancel.cancel();
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    /// Tells every session that the server is shutting down and will end it
+    /// after `grace`. Returns the number of live sessions.
+    pub(crate) fn drain_all(&self, grace: Duration) -> usize {
+        self.draining.send_replace(Some(grace));
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Ends every session, returning how many.
+    pub(crate) fn kill_all(&self) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        for entry in sessions.values() {
+            entry.cancel.cancel();
+        }
+        sessions.len()
+    }
+
+    /// Resolves once no session is left.
+    pub(crate) async fn wait_until_empty(&self) {
+        while !self.sessions.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry of its own for each test, which may drain or kill all
+    /// its sessions without disturbing the others.
+    fn registry() -> &'static Registry {
+        Box::leak(Box::new(Registry::new()))
+    }
+
+    fn listed(registry: &Registry) -> Vec<u64> {
+        registry
+            .list()
+            .into_iter()
+            .map(|session| session.id)
+            .collect()
+    }
+
+    #[test]
+    fn registers_and_unregisters_sessions() {
+        let registry = registry();
+        let first = registry.register("register.onion", 1, 22, "shell", "/bin/sh");
+        let second = registry.register("register.onion", 2, 80, "forward", "127.0.0.1:80");
+        assert!(second.id() > first.id());
+        assert_eq!(listed(registry), [first.id(), second.id()]);
+
+        let info = registry
+            .list()
+            .into_iter()
+            .find(|s| s.id == second.id())
+            .unwrap();
+        assert_eq!((info.circuit, info.port), (2, 80));
+        assert_eq!(
+            (info.kind.as_str(), info.detail.as_str()),
+            ("forward", "127.0.0.1:80")
+        );
+
+        let id = first.id();
+        drop(first);
+        assert_eq!(listed(registry), [second.id()]);
+        assert!(!registry.kill(id));
+    }
+
+    #[test]
+    fn kills_one_session() {
+        let registry = registry();
+        let victim = registry.register("kill.onion", 1, 22, "shell", "");
+        let bystander = registry.register("kill.onion", 1, 22, "shell", "");
+        assert!(registry.kill(victim.id()));
+        assert!(victim.cancel.is_cancelled());
+        assert!(!bystander.cancel.is_cancelled());
+    }
+
+    #[test]
+    fn kills_the_sessions_of_one_service() {
+        let registry = registry();
+        let sessions = [
+            registry.register("stopped.onion", 1, 22, "shell", ""),
+            registry.register("stopped.onion", 2, 26, "transfer", ""),
+        ];
+        let other = registry.register("running.onion", 3, 22, "shell", "");
+        assert_eq!(registry.kill_service("stopped.onion"), 2);
+        assert!(sessions.iter().all(|s| s.cancel.is_cancelled()));
+        assert!(!other.cancel.is_cancelled());
+        assert_eq!(registry.kill_service("unknown.onion"), 0);
+    }
+
+    #[tokio::test]
+    async fn drain_tells_sessions_the_grace_period() {
+        let registry = registry();
+        let session = registry.register("drain.onion", 1, 22, "shell", "");
+        assert_eq!(registry.drain_all(Duration::from_secs(30)), 1);
+        assert_eq!(session.draining().await, Duration::from_secs(30));
+        // Draining asks sessions to finish; it does not end them.
+        assert!(!session.cancel.is_cancelled());
+    }
+}
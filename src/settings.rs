@@ -0,0 +1,454 @@
+//! The configuration file, read in layers: `/etc/backtor/config.toml`, then
+//! the user's `~/.config/backtor/config.toml`, then the file given with
+//! `--config`. Later files override earlier ones key by key; lists and
+//! `[[service]]` sections are replaced as a whole. Command-line flags
+//! override everything.
+//!
+//! ```toml
+//! state_dir = "/var/lib/backtor"
+//!
+//! [log]
+//! level = "info"
+//! arti = "warn"
+//! filter = "tor_guardmgr=error"
+//!
+//! [connect]
+//! port = 2222
+//! local_forwards = ["8080:127.0.0.1:80"]
+//!
+//! [socks]
+//! listen = "127.0.0.1:9050"
+//!
+//! [bridges]
+//! lines = ["192.0.2.1:443 <FINGERPRINT>"]
+//! proxy = "http://proxy.example:3128"
+//!
+//! # Passed to arti as is; see arti's example configuration.
+//! [tor.channel]
+//! padding = "reduced"
+//! ```
+//!
+//! The server keys (`audit_log`, `[[service]]`, ...) are described in
+//! [`crate::config`].
+
+use anyhow::{Context, Error, anyhow, bail};
+use arti_client::config::{CfgPath, TorClientConfig, TorClientConfigBuilder};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::Table;
+use tor_config::ExplicitOrAuto;
+use tor_keymgr::config::ArtiKeystoreKind;
+
+use crate::bridges::{BridgeSettings, ProxyRelay};
+#[cfg(feature = "server")]
+use crate::config::ServerConfig;
+use crate::dirs::{TorDirectories, non_empty_var};
+#[cfg(feature = "client")]
+use crate::tunnel::{DynamicForward, LocalForward, RemoteForward};
+
+/// The configuration shared by everyone on the machine.
+pub(crate) const SYSTEM_CONFIG: &str = "/etc/backtor/config.toml";
+
+/// The keys allowed at the top level of a configuration file, including
+/// those of features this build lacks, so one file serves every build.
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "state_dir",
+    "cache_dir",
+    "log",
+    "tor",
+    "bridges",
+    "connect",
+    "socks",
+    "audit_log",
+    "control_socket",
+    "shutdown_grace",
+    "pty",
+    "ephemeral",
+    "service",
+];
+
+/// Everything the configuration files say.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct Settings {
+    /// See `--state-dir`.
+    pub(crate) state_dir: Option<PathBuf>,
+    /// See `--cache-dir`.
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) log: LogSettings,
+    pub(crate) bridges: BridgeSettings,
+    /// arti's own configuration, e.g. `[tor.channel]` or `[tor.path_rules]`.
+    pub(crate) tor: Table,
+    #[cfg(feature = "client")]
+    pub(crate) connect: ConnectSettings,
+    #[cfg(feature = "client")]
+    pub(crate) socks: SocksSettings,
+    #[cfg(feature = "server")]
+    #[serde(flatten)]
+    pub(crate) server: ServerConfig,
+
+    /// The files these settings were read from, in order.
+    #[serde(skip)]
+    pub(crate) sources: Vec<PathBuf>,
+    /// The merged contents of those files.
+    #[serde(skip)]
+    pub(crate) merged: Table,
+}
+
+/// `[log]`: what to log. `-v` and the `ARTI_LOG` and `RUST_LOG` environment
+/// variables take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct LogSettings {
+    /// backtor's own level: `error` (the default), `warn`, `info`, `debug`
+    /// or `trace`.
+    pub(crate) level: Option<String>,
+    /// Levels of the arti crates, in `ARTI_LOG` syntax.
+    pub(crate) arti: Option<String>,
+    /// Additional filter directives, in `RUST_LOG` syntax.
+    pub(crate) filter: Option<String>,
+}
+
+/// `[connect]`: defaults for `backtor connect`.
+#[cfg(feature = "client")]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct ConnectSettings {
+    /// The port used when the address has none.
+    pub(crate) port: Option<u16>,
+    /// Used when no `-L` is given.
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) local_forwards: Vec<LocalForward>,
+    /// Used when no `-R` is given.
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) remote_forwards: Vec<RemoteForward>,
+    /// Used when no `-D` is given.
+    #[serde(deserialize_with = "parse_list")]
+    pub(crate) dynamic_forwards: Vec<DynamicForward>,
+    /// Seconds between keepalives; used when no `--keepalive` is given.
+    pub(crate) keepalive: Option<u64>,
+    /// Like `--predict`.
+    pub(crate) predict: bool,
+    /// Like `--sync`.
+    pub(crate) sync: bool,
+}
+
+/// `[socks]`: defaults for `backtor socks`.
+#[cfg(feature = "client")]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SocksSettings {
+    pub(crate) listen: Option<std::net::SocketAddr>,
+}
+
+impl Settings {
+    /// Reads and merges the system and user configuration files that exist,
+    /// then `explicit`, which must exist.
+    pub(crate) fn load(explicit: Option<&Path>) -> Result<Self, Error> {
+        Self::load_files(config_files(explicit)?)
+    }
+
+    /// Reads and merges `files`, later ones overriding earlier ones.
+    fn load_files(files: Vec<PathBuf>) -> Result<Self, Error> {
+        let mut merged = Table::new();
+        for path in &files {
+            let layer = read_layer(path)?;
+            merge(&mut merged, layer);
+        }
+        let mut settings: Settings = toml::Value::Table(merged.clone())
+            .try_into()
+            .context("invalid combination of configuration files")?;
+        settings.sources = files;
+        settings.merged = merged;
+        Ok(settings)
+    }
+
+    /// The `control_socket` the configuration files set, for `backtor ctl`.
+    ///
+    /// Nothing else is read, and broken files are skipped with a warning: a
+    /// bad edit is exactly when a server needs to be reloaded or stopped.
+    #[cfg(all(feature = "server", unix))]
+    pub(crate) fn control_socket(explicit: Option<&Path>) -> Option<PathBuf> {
+        let files = config_files(explicit).unwrap_or_else(|e| {
+            eprintln!("Ignoring the configuration: {e:#}");
+            config_files(None).unwrap_or_default()
+        });
+        Self::control_socket_in(&files)
+    }
+
+    #[cfg(all(feature = "server", unix))]
+    fn control_socket_in(files: &[PathBuf]) -> Option<PathBuf> {
+        let mut socket = None;
+        for path in files {
+            let table = std::fs::read_to_string(path)
+                .map_err(Error::from)
+                .and_then(|text| Ok(toml::from_str::<Table>(&text)?));
+            match table.as_ref().map(|table| table.get("control_socket")) {
+                Ok(None) => {}
+                Ok(Some(toml::Value::String(path))) => socket = Some(PathBuf::from(path)),
+                Ok(Some(_)) => {
+                    eprintln!("Ignoring control_socket in {}: not a path", path.display())
+                }
+                Err(e) => eprintln!("Ignoring {}: {e}", path.display()),
+            }
+        }
+        socket
+    }
+
+    /// The Tor client configuration: `[tor]` with the given directories and
+    /// `[bridges]`, and the relay to spawn when bridges are reached through
+    /// a proxy. With `ephemeral_keys`, keys live in memory only, so onion
+    /// services without a key of their own get a new address every run.
+    pub(crate) fn tor_config(
+        &self,
+        directories: &TorDirectories,
+        ephemeral_keys: bool,
+    ) -> Result<(TorClientConfig, Option<ProxyRelay>), Error> {
+        if let Some(storage) = self.tor.get("storage").and_then(toml::Value::as_table) {
+            for key in ["state_dir", "cache_dir"] {
+                if storage.contains_key(key) {
+                    bail!("set {key} at the top level of the configuration, not in [tor.storage]");
+                }
+            }
+        }
+        let mut builder: TorClientConfigBuilder = toml::Value::Table(self.tor.clone())
+            .try_into()
+            .context("invalid [tor] configuration")?;
+        // arti ignores keys it does not know, which would hide typos.
+        let understood = serde_json::to_value(&builder)?;
+        if let Some(key) = unknown_key(&self.tor, &understood) {
+            bail!("invalid [tor] configuration: unknown key tor.{key}");
+        }
+        let relay = self.bridges.apply(&mut builder)?;
+        builder
+            .storage()
+            .state_dir(CfgPath::new_literal(&directories.state))
+            .cache_dir(CfgPath::new_literal(&directories.cache));
+        if ephemeral_keys {
+            builder
+                .storage()
+                .keystore()
+                .primary()
+                .kind(ExplicitOrAuto::Explicit(ArtiKeystoreKind::Ephemeral));
+        }
+        let config = builder
+            .build()
+            .map_err(|e| anyhow!("invalid Tor configuration: {e}"))?;
+        Ok((config, relay))
+    }
+}
+
+/// The system and user configuration files that exist, then `explicit`,
+/// which must exist.
+fn config_files(explicit: Option<&Path>) -> Result<Vec<PathBuf>, Error> {
+    let mut files: Vec<PathBuf> = [Some(PathBuf::from(SYSTEM_CONFIG)), user_config()]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.is_file())
+        .collect();
+    if let Some(explicit) = explicit {
+        // Reading the same file twice would be harmless, but confusing in
+        // `config check`.
+        let canonical = std::fs::canonicalize(explicit)
+            .with_context(|| format!("cannot read {}", explicit.display()))?;
+        files.retain(|path| std::fs::canonicalize(path).ok().as_ref() != Some(&canonical));
+        files.push(explicit.to_owned());
+    }
+    Ok(files)
+}
+
+/// `$XDG_CONFIG_HOME/backtor/config.toml`, by default in `~/.config`.
+fn user_config() -> Option<PathBuf> {
+    let base = match non_empty_var("XDG_CONFIG_HOME") {
+        Some(base) => PathBuf::from(base),
+        #[cfg(windows)]
+        None => PathBuf::from(non_empty_var("APPDATA")?),
+        #[cfg(not(windows))]
+        None => PathBuf::from(non_empty_var("HOME")?).join(".config"),
+    };
+    Some(base.join("backtor").join("config.toml"))
+}
+
+/// Reads one configuration file, checking it on its own so that mistakes
+/// are reported with their file and line.
+fn read_layer(path: &Path) -> Result<Table, Error> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let table: Table =
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))?;
+    for key in table.keys() {
+        if !TOP_LEVEL_KEYS.contains(&key.as_str()) {
+            bail!("invalid config {}: unknown key {key:?}", path.display());
+        }
+    }
+    toml::from_str::<Settings>(&text)
+        .with_context(|| format!("invalid config {}", path.display()))?;
+    Ok(table)
+}
+
+/// The first key of `input` missing from `understood`, the same
+/// configuration after a round trip through its parsed form.
+fn unknown_key(input: &Table, understood: &serde_json::Value) -> Option<String> {
+    for (key, value) in input {
+        let Some(understood) = understood.get(key) else {
+            return Some(key.clone());
+        };
+        if let toml::Value::Table(table) = value
+            && let Some(nested) = unknown_key(table, understood)
+        {
+            return Some(format!("{key}.{nested}"));
+        }
+    }
+    None
+}
+
+/// Merges `layer` into `base`: tables key by key, anything else replaced.
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Deserializes a list of values written like their command-line flags.
+/// Plain integers are accepted too, so `ports = [23]` works.
+pub(crate) fn parse_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Item {
+        Text(String),
+        Number(u64),
+    }
+
+    Vec::<Item>::deserialize(deserializer)?
+        .into_iter()
+        .map(|item| {
+            let text = match item {
+                Item::Text(text) => text,
+                Item::Number(n) => n.to_string(),
+            };
+            text.parse().map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    /// Writes one configuration file per entry of `layers` into `dir`.
+    fn write_layers(dir: &Path, layers: &[&str]) -> Vec<PathBuf> {
+        layers
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let path = dir.join(format!("{i}.toml"));
+                std::fs::write(&path, text).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let dir = scratch_dir("layers");
+        let files = write_layers(
+            &dir,
+            &[
+                "state_dir = \"/srv/state\"\n\
+                 [log]\nlevel = \"info\"\narti = \"warn\"\n\
+                 [bridges]\nlines = [\"a\", \"b\"]\n",
+                "[log]\nlevel = \"debug\"\n\
+                 [bridges]\nlines = [\"c\"]\n\
+                 [tor.channel]\npadding = \"reduced\"\n",
+            ],
+        );
+        let settings = Settings::load_files(files.clone()).unwrap();
+        assert_eq!(settings.sources, files);
+        assert_eq!(settings.state_dir.as_deref(), Some(Path::new("/srv/state")));
+        assert_eq!(settings.log.level.as_deref(), Some("debug"));
+        assert_eq!(settings.log.arti.as_deref(), Some("warn"));
+        // Lists are replaced, not appended to.
+        assert_eq!(settings.bridges.lines, ["c"]);
+        assert!(settings.tor.contains_key("channel"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merges_tables_key_by_key() {
+        let mut base: Table = toml::from_str("a = 1\n[t]\nx = 1\ny = 2\n").unwrap();
+        let layer: Table = toml::from_str("t = { y = 3, z = 4 }\nb = [1]\n").unwrap();
+        merge(&mut base, layer);
+        let expected: Table = toml::from_str("a = 1\nb = [1]\n[t]\nx = 1\ny = 3\nz = 4\n").unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let dir = scratch_dir("unknown");
+        for (text, key) in [
+            ("stat_dir = \"/srv\"\n", "stat_dir"),
+            ("[log]\ncolour = \"always\"\n", "colour"),
+            ("[bridges]\nline = []\n", "line"),
+        ] {
+            let files = write_layers(&dir, &["[log]\nlevel = \"info\"\n", text]);
+            let error = format!("{:#}", Settings::load_files(files).unwrap_err());
+            assert!(error.contains("1.toml"), "{error}");
+            assert!(error.contains(key), "{error}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_the_tor_section() {
+        let directories = TorDirectories {
+            state: "/srv/state".into(),
+            cache: "/srv/cache".into(),
+        };
+        let tor_config = |text: &str| {
+            let settings = Settings {
+                tor: toml::from_str(text).unwrap(),
+                ..Settings::default()
+            };
+            settings.tor_config(&directories, false).map(|_| ())
+        };
+        assert!(tor_config("[channel]\npadding = \"reduced\"\n").is_ok());
+        let error = tor_config("[chanel]\npadding = \"reduced\"\n").unwrap_err();
+        assert!(error.to_string().contains("tor.chanel"), "{error}");
+        let error = tor_config("[storage]\nstate_dir = \"/x\"\n").unwrap_err();
+        assert!(error.to_string().contains("top level"), "{error}");
+    }
+
+    #[cfg(all(feature = "server", unix))]
+    #[test]
+    fn finds_the_control_socket_despite_broken_files() {
+        let dir = scratch_dir("control-socket");
+        let files = write_layers(
+            &dir,
+            &[
+                "control_socket = \"/run/first.sock\"\n",
+                "control_socket = \"/run/second.sock\"\n[log]\ncolour = 1\n",
+                "this is not toml",
+            ],
+        );
+        assert!(Settings::load_files(files.clone()).is_err());
+        assert_eq!(
+            Settings::control_socket_in(&files),
+            Some(PathBuf::from("/run/second.sock"))
+        );
+        assert_eq!(Settings::control_socket_in(&files[2..]), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1053 @@
+//! An SFTP version 3 server (draft-ietf-secsh-filexfer-02) for standard
+//! clients such as `sftp` and `sshfs`.
+//!
+//! The subsystem is served on its own virtual port, without an SSH layer:
+//! clients reach it through a stdio bridge (`backtor pipe`), e.g.
+//! `sftp -D 'backtor pipe ADDRESS:115'`. Every path is confined to the
+//! server's transfer roots.
+
+use anyhow::{Error, bail};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::audit::{self, AuditEvent};
+use crate::sessions::SESSIONS;
+use crate::transfer::TransferPolicy;
+
+/// The virtual port the SFTP subsystem listens on (the historical port of
+/// the Simple File Transfer Protocol).
+pub(crate) const SFTP_PORT: u16 = 115;
+
+const SFTP_VERSION: u32 = 3;
+
+/// Largest packet we accept; clients use at most 32 KiB of data per write.
+const MAX_PACKET: u32 = 256 * 1024;
+
+/// Largest read we serve in one `SSH_FXP_DATA` reply.
+const MAX_READ: u32 = 64 * 1024;
+
+/// Most files and directories one session may hold open.
+const MAX_HANDLES: usize = 256;
+
+/// Number of directory entries per `SSH_FXP_NAME` reply.
+const READDIR_BATCH: usize = 100;
+
+// Request and response packet types.
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_LSTAT: u8 = 7;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_RMDIR: u8 = 15;
+const FXP_REALPATH: u8 = 16;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_READLINK: u8 = 19;
+const FXP_SYMLINK: u8 = 20;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+
+// Status codes.
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_FAILURE: u32 = 4;
+const FX_BAD_MESSAGE: u32 = 5;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+// Attribute flags.
+const ATTR_SIZE: u32 = 0x0000_0001;
+const ATTR_UIDGID: u32 = 0x0000_0002;
+const ATTR_PERMISSIONS: u32 = 0x0000_0004;
+const ATTR_ACMODTIME: u32 = 0x0000_0008;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+// Open flags.
+const FXF_READ: u32 = 0x01;
+const FXF_WRITE: u32 = 0x02;
+const FXF_APPEND: u32 = 0x04;
+const FXF_CREAT: u32 = 0x08;
+const FXF_TRUNC: u32 = 0x10;
+const FXF_EXCL: u32 = 0x20;
+
+/// An error answered with an `SSH_FXP_STATUS` packet.
+#[derive(Debug)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Status {
+    fn from(e: std::io::Error) -> Self {
+        let code = match e.kind() {
+            ErrorKind::NotFound => FX_NO_SUCH_FILE,
+            ErrorKind::PermissionDenied => FX_PERMISSION_DENIED,
+            _ => FX_FAILURE,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+type SftpResult<T> = Result<T, Status>;
+
+/// Reads the fields of one request packet.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> SftpResult<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(Status::new(FX_BAD_MESSAGE, "truncated packet"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> SftpResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> SftpResult<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> SftpResult<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> SftpResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn path(&mut self) -> SftpResult<PathBuf> {
+        Ok(path_from_bytes(self.bytes()?))
+    }
+
+    fn attrs(&mut self) -> SftpResult<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            attrs.times = Some((self.u32()?, self.u32()?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Builds one response packet.
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new(kind: u8, id: u32) -> Self {
+        let mut packet = Packet(vec![kind]);
+        packet.u32(id);
+        packet
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.u32(b.len() as u32);
+        self.0.extend_from_slice(b);
+    }
+
+    fn attrs(&mut self, attrs: &Attrs) {
+        let mut flags = 0;
+        flags |= attrs.size.map_or(0, |_| ATTR_SIZE);
+        flags |= attrs.uid_gid.map_or(0, |_| ATTR_UIDGID);
+        flags |= attrs.permissions.map_or(0, |_| ATTR_PERMISSIONS);
+        flags |= attrs.times.map_or(0, |_| ATTR_ACMODTIME);
+        self.u32(flags);
+        if let Some(size) = attrs.size {
+            self.u64(size);
+        }
+        if let Some((uid, gid)) = attrs.uid_gid {
+            self.u32(uid);
+            self.u32(gid);
+        }
+        if let Some(permissions) = attrs.permissions {
+            self.u32(permissions);
+        }
+        if let Some((atime, mtime)) = attrs.times {
+            self.u32(atime);
+            self.u32(mtime);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Attrs {
+    size: Option<u64>,
+    uid_gid: Option<(u32, u32)>,
+    permissions: Option<u32>,
+    /// Access and modification time in Unix seconds.
+    times: Option<(u32, u32)>,
+}
+
+impl Attrs {
+    fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        let seconds = |t: std::io::Result<std::time::SystemTime>| {
+            t.ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as u32)
+        };
+        #[cfg(unix)]
+        let (uid_gid, permissions) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some((metadata.uid(), metadata.gid())), metadata.mode())
+        };
+        #[cfg(not(unix))]
+        let (uid_gid, permissions) = {
+            let kind = if metadata.is_dir() {
+                0o040755
+            } else {
+                0o100644
+            };
+            (None, kind)
+        };
+        Attrs {
+            size: Some(metadata.len()),
+            uid_gid,
+            permissions: Some(permissions),
+            times: Some((seconds(metadata.accessed()), seconds(metadata.modified()))),
+        }
+    }
+
+    /// An `ls -l` style line, which clients show for long listings.
+    fn long_name(&self, name: &str) -> String {
+        let mode = self.permissions.unwrap_or(0);
+        let kind = match mode & 0o170000 {
+            0o040000 => 'd',
+            0o120000 => 'l',
+            0o020000 => 'c',
+            0o060000 => 'b',
+            0o010000 => 'p',
+            0o140000 => 's',
+            _ => '-',
+        };
+        let mut perms = String::from(kind);
+        for shift in [6, 3, 0] {
+            let bits = (mode >> shift) & 0o7;
+            perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        let (uid, gid) = self.uid_gid.unwrap_or_default();
+        let (_, mtime) = self.times.unwrap_or_default();
+        format!(
+            "{perms}    1 {uid:<8} {gid:<8} {:>8} {} {name}",
+            self.size.unwrap_or(0),
+            format_date(mtime),
+        )
+    }
+
+    /// Applies the settable attributes (size, permissions, times) to `path`.
+    async fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(size) = self.size {
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .await?
+                .set_len(size)
+                .await?;
+        }
+        #[cfg(unix)]
+        if let Some(permissions) = self.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(permissions & 0o777);
+            tokio::fs::set_permissions(path, permissions).await?;
+        }
+        if let Some((atime, mtime)) = self.times {
+            let times = std::fs::FileTimes::new()
+                .set_accessed(UNIX_EPOCH + Duration::from_secs(atime.into()))
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime.into()));
+            let path = path.to_owned();
+            tokio::task::spawn_blocking(move || std::fs::File::open(path)?.set_times(times))
+                .await??;
+        }
+        Ok(())
+    }
+}
+
+/// Formats Unix seconds as `Mon DD HH:MM` (UTC), the way `ls` does for
+/// recent files.
+fn format_date(secs: u32) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = i64::from(secs / 86400);
+    let (hour, minute) = ((secs % 86400) / 3600, (secs % 3600) / 60);
+    // Civil-from-days (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    format!(
+        "{} {day:>2} {hour:02}:{minute:02}",
+        MONTHS[(month - 1) as usize]
+    )
+}
+
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+enum Handle {
+    File(tokio::fs::File),
+    Dir(VecDeque<(OsString, Attrs)>),
+}
+
+/// The state of one SFTP session.
+struct Session {
+    policy: TransferPolicy,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl Session {
+    fn new(policy: TransferPolicy) -> Self {
+        Self {
+            policy,
+            handles: HashMap::new(),
+            next_handle: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Resolves a client path against the first root and checks that it
+    /// stays inside the transfer roots. `..` is removed lexically; existing
+    /// symlinks are followed by [`TransferPolicy::check`], which refuses
+    /// any that cannot be resolved.
+    fn resolve(&self, path: &Path) -> SftpResult<PathBuf> {
+        let path = normalize(&self.policy.resolve(path));
+        self.policy
+            .check(&path)
+            .map_err(|e| Status::new(FX_PERMISSION_DENIED, e.to_string()))?;
+        Ok(path)
+    }
+
+    fn add_handle(&mut self, handle: Handle, id: u32) -> SftpResult<Packet> {
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(Status::new(FX_FAILURE, "too many open handles"));
+        }
+        let key = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(key, handle);
+        let mut packet = Packet::new(FXP_HANDLE, id);
+        packet.bytes(key.to_string().as_bytes());
+        Ok(packet)
+    }
+
+    fn handle_key(r: &mut Reader<'_>) -> SftpResult<u32> {
+        std::str::from_utf8(r.bytes()?)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| Status::new(FX_FAILURE, "invalid handle"))
+    }
+
+    fn file(&mut self, key: u32) -> SftpResult<&mut tokio::fs::File> {
+        match self.handles.get_mut(&key) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(Status::new(FX_FAILURE, "invalid handle")),
+        }
+    }
+
+    /// Handles one request, returning its response packet.
+    async fn dispatch(&mut self, kind: u8, id: u32, r: &mut Reader<'_>) -> SftpResult<Packet> {
+        match kind {
+            FXP_OPEN => {
+                let path = self.resolve(&r.path()?)?;
+                let pflags = r.u32()?;
+                let attrs = r.attrs()?;
+                let mut options = tokio::fs::OpenOptions::new();
+                options
+                    .read(pflags & FXF_READ != 0)
+                    .write(pflags & FXF_WRITE != 0)
+                    .append(pflags & FXF_APPEND != 0)
+                    .truncate(pflags & FXF_TRUNC != 0);
+                if pflags & FXF_EXCL != 0 {
+                    options.create_new(true);
+                } else if pflags & FXF_CREAT != 0 {
+                    options.create(true);
+                }
+                #[cfg(unix)]
+                {
+                    // Never write through a symlink: an existing file is
+                    // opened by its resolved path, which `resolve` checked.
+                    options.custom_flags(libc::O_NOFOLLOW);
+                    if let Some(permissions) = attrs.permissions {
+                        options.mode(permissions & 0o777);
+                    }
+                }
+                let path = tokio::fs::canonicalize(&path).await.unwrap_or(path);
+                let file = options.open(&path).await?;
+                debug!("SFTP open {} (flags {pflags:#x})", path.display());
+                self.add_handle(Handle::File(file), id)
+            }
+            FXP_CLOSE => {
+                let key = Self::handle_key(r)?;
+                match self.handles.remove(&key) {
+                    Some(Handle::File(mut file)) => file.flush().await?,
+                    Some(Handle::Dir(_)) => {}
+                    None => return Err(Status::new(FX_FAILURE, "invalid handle")),
+                }
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_READ => {
+                let key = Self::handle_key(r)?;
+                let offset = r.u64()?;
+                let len = r.u32()?.min(MAX_READ) as usize;
+                let file = self.file(key)?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0u8; len];
+                let mut filled = 0;
+                while filled < len {
+                    match file.read(&mut data[filled..]).await? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+                if filled == 0 && len > 0 {
+                    return Err(Status::new(FX_EOF, "End of file"));
+                }
+                self.bytes_read += filled as u64;
+                let mut packet = Packet::new(FXP_DATA, id);
+                packet.bytes(&data[..filled]);
+                Ok(packet)
+            }
+            FXP_WRITE => {
+                let key = Self::handle_key(r)?;
+                let offset = r.u64()?;
+                let data = r.bytes()?;
+                let file = self.file(key)?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(data).await?;
+                self.bytes_written += data.len() as u64;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_LSTAT | FXP_STAT => {
+                let path = self.resolve(&r.path()?)?;
+                let metadata = if kind == FXP_LSTAT {
+                    tokio::fs::symlink_metadata(&path).await?
+                } else {
+                    tokio::fs::metadata(&path).await?
+                };
+                let mut packet = Packet::new(FXP_ATTRS, id);
+                packet.attrs(&Attrs::from_metadata(&metadata));
+                Ok(packet)
+            }
+            FXP_FSTAT => {
+                let key = Self::handle_key(r)?;
+                let metadata = self.file(key)?.metadata().await?;
+                let mut packet = Packet::new(FXP_ATTRS, id);
+                packet.attrs(&Attrs::from_metadata(&metadata));
+                Ok(packet)
+            }
+            FXP_SETSTAT => {
+                let path = self.resolve(&r.path()?)?;
+                r.attrs()?.apply(&path).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_FSETSTAT => {
+                let key = Self::handle_key(r)?;
+                let attrs = r.attrs()?;
+                let file = self.file(key)?;
+                if let Some(size) = attrs.size {
+                    file.set_len(size).await?;
+                }
+                #[cfg(unix)]
+                if let Some(permissions) = attrs.permissions {
+                    use std::os::unix::fs::PermissionsExt;
+                    let permissions = std::fs::Permissions::from_mode(permissions & 0o777);
+                    file.set_permissions(permissions).await?;
+                }
+                if let Some((atime, mtime)) = attrs.times {
+                    let times = std::fs::FileTimes::new()
+                        .set_accessed(UNIX_EPOCH + Duration::from_secs(atime.into()))
+                        .set_modified(UNIX_EPOCH + Duration::from_secs(mtime.into()));
+                    let std_file = file.try_clone().await?.into_std().await;
+                    tokio::task::spawn_blocking(move || std_file.set_times(times))
+                        .await
+                        .map_err(std::io::Error::from)??;
+                }
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_OPENDIR => {
+                let path = self.resolve(&r.path()?)?;
+                let mut entries = VecDeque::new();
+                for name in [".", ".."] {
+                    let metadata = tokio::fs::metadata(path.join(name)).await?;
+                    entries.push_back((name.into(), Attrs::from_metadata(&metadata)));
+                }
+                let mut dir = tokio::fs::read_dir(&path).await?;
+                while let Some(entry) = dir.next_entry().await? {
+                    if let Ok(metadata) = tokio::fs::symlink_metadata(entry.path()).await {
+                        entries.push_back((entry.file_name(), Attrs::from_metadata(&metadata)));
+                    }
+                }
+                self.add_handle(Handle::Dir(entries), id)
+            }
+            FXP_READDIR => {
+                let key = Self::handle_key(r)?;
+                let Some(Handle::Dir(entries)) = self.handles.get_mut(&key) else {
+                    return Err(Status::new(FX_FAILURE, "invalid handle"));
+                };
+                if entries.is_empty() {
+                    return Err(Status::new(FX_EOF, "End of directory"));
+                }
+                let batch: Vec<_> = entries.drain(..entries.len().min(READDIR_BATCH)).collect();
+                let mut packet = Packet::new(FXP_NAME, id);
+                packet.u32(batch.len() as u32);
+                for (name, attrs) in &batch {
+                    let name_str = name.to_string_lossy();
+                    packet.bytes(&path_to_bytes(Path::new(name)));
+                    packet.bytes(attrs.long_name(&name_str).as_bytes());
+                    packet.attrs(attrs);
+                }
+                Ok(packet)
+            }
+            FXP_REMOVE => {
+                let path = self.resolve(&r.path()?)?;
+                tokio::fs::remove_file(&path).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_MKDIR => {
+                let path = self.resolve(&r.path()?)?;
+                let attrs = r.attrs()?;
+                tokio::fs::create_dir(&path).await?;
+                attrs.apply(&path).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_RMDIR => {
+                let path = self.resolve(&r.path()?)?;
+                tokio::fs::remove_dir(&path).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_REALPATH => {
+                let path = self.resolve(&r.path()?)?;
+                let path = tokio::fs::canonicalize(&path).await.unwrap_or(path);
+                let mut packet = Packet::new(FXP_NAME, id);
+                packet.u32(1);
+                packet.bytes(&path_to_bytes(&path));
+                packet.bytes(&path_to_bytes(&path));
+                packet.attrs(&Attrs::default());
+                Ok(packet)
+            }
+            FXP_RENAME => {
+                let from = self.resolve(&r.path()?)?;
+                let to = self.resolve(&r.path()?)?;
+                // SFTP v3 rename must not overwrite an existing target.
+                if tokio::fs::symlink_metadata(&to).await.is_ok() {
+                    return Err(Status::new(FX_FAILURE, "target already exists"));
+                }
+                tokio::fs::rename(&from, &to).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            FXP_READLINK => {
+                let path = self.resolve(&r.path()?)?;
+                let target = tokio::fs::read_link(&path).await?;
+                let mut packet = Packet::new(FXP_NAME, id);
+                packet.u32(1);
+                packet.bytes(&path_to_bytes(&target));
+                packet.bytes(&path_to_bytes(&target));
+                packet.attrs(&Attrs::default());
+                Ok(packet)
+            }
+            FXP_SYMLINK => {
+                // OpenSSH sends (targetpath, linkpath), the reverse of the
+                // draft; we follow OpenSSH, like every client expects.
+                let target = r.path()?;
+                let link = self.resolve(&r.path()?)?;
+                // Only links that point down from their own directory are
+                // created, so no chain of client-made links can lead out of
+                // the roots, whatever they are later changed to.
+                if !target
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+                {
+                    return Err(Status::new(
+                        FX_PERMISSION_DENIED,
+                        "symlink targets must be relative and must not contain ..",
+                    ));
+                }
+                symlink(&target, &link).await?;
+                Err(Status::new(FX_OK, "Success"))
+            }
+            _ => Err(Status::new(FX_OP_UNSUPPORTED, "operation not supported")),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+#[cfg(not(unix))]
+async fn symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+async fn read_packet<R>(reader: &mut R) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u32().await?;
+    if len == 0 || len > MAX_PACKET {
+        bail!("invalid SFTP packet length {len}");
+    }
+    let mut packet = vec![0u8; len as usize];
+    reader.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_packet<W>(writer: &mut W, packet: Packet) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_u32(packet.0.len() as u32).await?;
+    writer.write_all(&packet.0).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Runs the SFTP protocol until the client disconnects.
+async fn run<S>(stream: &mut S, session: &mut Session) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let init = read_packet(stream).await?;
+    if init.first() != Some(&FXP_INIT) {
+        bail!("expected SSH_FXP_INIT");
+    }
+    let mut version = Packet(vec![FXP_VERSION]);
+    version.u32(SFTP_VERSION);
+    write_packet(stream, version).await?;
+
+    loop {
+        let packet = match read_packet(stream).await {
+            Ok(packet) => packet,
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io) if io.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                _ => return Err(e),
+            },
+        };
+        let mut r = Reader { buf: &packet };
+        let kind = r.u8().unwrap_or(0);
+        let Ok(id) = r.u32() else {
+            bail!("SFTP packet without request id");
+        };
+        let response = match session.dispatch(kind, id, &mut r).await {
+            Ok(response) => response,
+            Err(status) => {
+                let mut response = Packet::new(FXP_STATUS, id);
+                response.u32(status.code);
+                response.bytes(status.message.as_bytes());
+                response.bytes(b"en");
+                response
+            }
+        };
+        write_packet(stream, response).await?;
+    }
+}
+
+/// Serves one SFTP session.
+pub(crate) async fn handle_sftp_connection<S>(
+    mut stream: S,
+    policy: TransferPolicy,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let handle = SESSIONS.register(&service, circuit, port, "sftp", "");
+    let id = handle.id();
+    let started = Instant::now();
+    audit::record(AuditEvent::SessionStart {
+        service: &service,
+        circuit,
+        session: id,
+        port,
+        command: "sftp",
+    });
+
+    let mut session = Session::new(policy);
+    tokio::select! {
+        result = run(&mut stream, &mut session) => match result {
+            Ok(()) => debug!("SFTP session {id} ended"),
+            Err(e) => debug!("SFTP session {id} failed: {e}"),
+        },
+        () = handle.cancelled() => debug!("SFTP session {id} killed"),
+    }
+
+    audit::record(AuditEvent::SessionEnd {
+        service: &service,
+        circuit,
+        session: id,
+        duration_secs: started.elapsed().as_secs_f64(),
+        bytes_in: session.bytes_written,
+        bytes_out: session.bytes_read,
+        exit_code: None,
+        signal: None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+    use tokio::io::DuplexStream;
+
+    /// A fresh directory holding the `root` sessions are confined to.
+    fn sftp_dir(name: &str) -> PathBuf {
+        let dir = scratch_dir(&format!("sftp-{name}"));
+        std::fs::create_dir(dir.join("root")).unwrap();
+        dir
+    }
+
+    /// The client end of a session confined to one root.
+    struct Client {
+        stream: DuplexStream,
+    }
+
+    impl Client {
+        async fn start(root: &Path) -> Self {
+            let policy = TransferPolicy::new(&[root.to_path_buf()]).unwrap();
+            let (mut server, stream) = tokio::io::duplex(2 * MAX_PACKET as usize);
+            tokio::spawn(async move {
+                let _ = run(&mut server, &mut Session::new(policy)).await;
+            });
+            let mut client = Client { stream };
+            let mut init = Packet(vec![FXP_INIT]);
+            init.u32(SFTP_VERSION);
+            write_packet(&mut client.stream, init).await.unwrap();
+            let version = read_packet(&mut client.stream).await.unwrap();
+            assert_eq!(version[0], FXP_VERSION);
+            client
+        }
+
+        /// Sends one request, returning the response type and its body
+        /// after the request id.
+        async fn request(&mut self, packet: Packet) -> (u8, Vec<u8>) {
+            let id = packet.0[1..5].to_vec();
+            write_packet(&mut self.stream, packet).await.unwrap();
+            let response = read_packet(&mut self.stream).await.unwrap();
+            assert_eq!(response[1..5], id[..]);
+            (response[0], response[5..].to_vec())
+        }
+
+        /// Sends a request answered with a status, returning its code.
+        async fn status(&mut self, packet: Packet) -> u32 {
+            let (kind, body) = self.request(packet).await;
+            assert_eq!(kind, FXP_STATUS);
+            Reader { buf: &body }.u32().unwrap()
+        }
+
+        /// Sends a request answered with a handle or a status code.
+        async fn handle(&mut self, packet: Packet) -> Result<Vec<u8>, u32> {
+            match self.request(packet).await {
+                (FXP_HANDLE, body) => Ok(Reader { buf: &body }.bytes().unwrap().to_vec()),
+                (FXP_STATUS, body) => Err(Reader { buf: &body }.u32().unwrap()),
+                (kind, _) => panic!("unexpected response type {kind}"),
+            }
+        }
+
+        async fn open(
+            &mut self,
+            path: &str,
+            pflags: u32,
+            permissions: Option<u32>,
+        ) -> Result<Vec<u8>, u32> {
+            let mut packet = path_request(FXP_OPEN, path);
+            packet.u32(pflags);
+            packet.attrs(&Attrs {
+                permissions,
+                ..Attrs::default()
+            });
+            self.handle(packet).await
+        }
+
+        async fn symlink(&mut self, target: &str, link: &str) -> u32 {
+            let mut packet = path_request(FXP_SYMLINK, target);
+            packet.bytes(link.as_bytes());
+            self.status(packet).await
+        }
+    }
+
+    fn path_request(kind: u8, path: &str) -> Packet {
+        let mut packet = Packet::new(kind, 7);
+        packet.bytes(path.as_bytes());
+        packet
+    }
+
+    fn handle_request(kind: u8, handle: &[u8]) -> Packet {
+        let mut packet = Packet::new(kind, 7);
+        packet.bytes(handle);
+        packet
+    }
+
+    #[tokio::test]
+    async fn writes_reads_and_lists_files() {
+        let dir = sftp_dir("files");
+        let mut client = Client::start(&dir.join("root")).await;
+
+        let file = client
+            .open("notes.txt", FXF_WRITE | FXF_CREAT | FXF_TRUNC, None)
+            .await
+            .unwrap();
+        let mut write = handle_request(FXP_WRITE, &file);
+        write.u64(0);
+        write.bytes(b"hello");
+        assert_eq!(client.status(write).await, FX_OK);
+        assert_eq!(client.status(handle_request(FXP_CLOSE, &file)).await, FX_OK);
+        assert_eq!(std::fs::read(dir.join("root/notes.txt")).unwrap(), b"hello");
+
+        let file = client.open("notes.txt", FXF_READ, None).await.unwrap();
+        let mut read = handle_request(FXP_READ, &file);
+        read.u64(1);
+        read.u32(100);
+        let (kind, body) = client.request(read).await;
+        assert_eq!(kind, FXP_DATA);
+        assert_eq!(Reader { buf: &body }.bytes().unwrap(), b"ello");
+        let mut read = handle_request(FXP_READ, &file);
+        read.u64(5);
+        read.u32(100);
+        assert_eq!(client.status(read).await, FX_EOF);
+        assert_eq!(client.status(handle_request(FXP_CLOSE, &file)).await, FX_OK);
+
+        let listing = client.handle(path_request(FXP_OPENDIR, ".")).await.unwrap();
+        let (kind, body) = client.request(handle_request(FXP_READDIR, &listing)).await;
+        assert_eq!(kind, FXP_NAME);
+        let mut r = Reader { buf: &body };
+        let mut names = Vec::new();
+        for _ in 0..r.u32().unwrap() {
+            names.push(String::from_utf8(r.bytes().unwrap().to_vec()).unwrap());
+            r.bytes().unwrap();
+            r.attrs().unwrap();
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "notes.txt"]);
+        let readdir = handle_request(FXP_READDIR, &listing);
+        assert_eq!(client.status(readdir).await, FX_EOF);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_that_climb_out() {
+        let dir = sftp_dir("climb");
+        let mut client = Client::start(&dir.join("root")).await;
+
+        let outside = dir.join("outside.txt");
+        for path in [
+            "../outside.txt",
+            "missing/../../outside.txt",
+            outside.to_str().unwrap(),
+        ] {
+            let opened = client.open(path, FXF_WRITE | FXF_CREAT, None).await;
+            assert_eq!(opened, Err(FX_PERMISSION_DENIED), "{path} opened");
+        }
+        assert!(!outside.exists());
+
+        let mut mkdir = path_request(FXP_MKDIR, "../made");
+        mkdir.attrs(&Attrs::default());
+        assert_eq!(client.status(mkdir).await, FX_PERMISSION_DENIED);
+        assert!(!dir.join("made").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlink_escapes() {
+        let dir = sftp_dir("symlink");
+        let root = dir.join("root");
+        let mut client = Client::start(&root).await;
+
+        // Links the client makes may only point down.
+        assert_eq!(client.symlink(".", "up").await, FX_OK);
+        let escapes = ["up/up/../../outside.txt", "../outside.txt", "/etc/passwd"];
+        for target in escapes {
+            assert_eq!(client.symlink(target, "dl").await, FX_PERMISSION_DENIED);
+        }
+        assert!(std::fs::symlink_metadata(root.join("dl")).is_err());
+
+        // Dangling links planted outside the session cannot be written
+        // through, whether they are the last component or not.
+        std::os::unix::fs::symlink(dir.join("planted.txt"), root.join("planted")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing"), root.join("dangling")).unwrap();
+        for path in ["planted", "dangling/file", "up/planted"] {
+            let opened = client.open(path, FXF_WRITE | FXF_CREAT, None).await;
+            assert_eq!(opened, Err(FX_PERMISSION_DENIED), "{path} opened");
+        }
+        assert!(!dir.join("planted.txt").exists());
+        assert!(!dir.join("missing").exists());
+
+        // Links that stay inside the root still work.
+        std::fs::write(root.join("real.txt"), b"data").unwrap();
+        assert_eq!(client.symlink("real.txt", "alias").await, FX_OK);
+        assert!(client.open("alias", FXF_READ, None).await.is_ok());
+        assert!(client.open("up/alias", FXF_WRITE, None).await.is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn drops_setuid_and_setgid_from_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = sftp_dir("modes");
+        let mut client = Client::start(&dir.join("root")).await;
+        let mode = || {
+            std::fs::metadata(dir.join("root/tool"))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+
+        let file = client
+            .open("tool", FXF_WRITE | FXF_CREAT, Some(0o4755))
+            .await
+            .unwrap();
+        assert_eq!(mode() & 0o7000, 0);
+
+        let permissions = Attrs {
+            permissions: Some(0o6755),
+            ..Attrs::default()
+        };
+        let mut setstat = path_request(FXP_SETSTAT, "tool");
+        setstat.attrs(&permissions);
+        assert_eq!(client.status(setstat).await, FX_OK);
+        assert_eq!(mode() & 0o7777, 0o755);
+        let mut fsetstat = handle_request(FXP_FSETSTAT, &file);
+        fsetstat.attrs(&permissions);
+        assert_eq!(client.status(fsetstat).await, FX_OK);
+        assert_eq!(mode() & 0o7777, 0o755);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_malformed_requests_with_a_status() {
+        let dir = sftp_dir("malformed");
+        let mut client = Client::start(&dir.join("root")).await;
+
+        // A path length longer than the packet.
+        let mut open = Packet::new(FXP_OPEN, 7);
+        open.u32(100);
+        assert_eq!(client.status(open).await, FX_BAD_MESSAGE);
+        let open = path_request(FXP_OPEN, "notes.txt");
+        assert_eq!(client.status(open).await, FX_BAD_MESSAGE);
+        let close = handle_request(FXP_CLOSE, b"not a handle");
+        assert_eq!(client.status(close).await, FX_FAILURE);
+        assert_eq!(client.status(Packet::new(200, 7)).await, FX_OP_UNSUPPORTED);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_packet_lengths() {
+        for len in [0, MAX_PACKET + 1, u32::MAX] {
+            let header = len.to_be_bytes();
+            assert!(read_packet(&mut &header[..]).await.is_err(), "{len} read");
+        }
+        // Shorter than its length claims.
+        let short = [0, 0, 0, 9, FXP_INIT, 0, 0];
+        assert!(read_packet(&mut &short[..]).await.is_err());
+
+        let (mut server, mut client) = tokio::io::duplex(64);
+        client.write_all(&[0, 0, 0, 0]).await.unwrap();
+        let policy = TransferPolicy {
+            roots: Arc::from([]),
+        };
+        assert!(run(&mut server, &mut Session::new(policy)).await.is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! What shell streams carry.
+//!
+//! A shell stream starts out as raw terminal bytes in both directions, which
+//! is all `nc` or an older client needs. The server opens every shell stream
+//! with an offer to switch to frames, disguised as an APC escape sequence
+//! that terminals ignore. A client that understands it answers with a request
+//! at the very start of its input, which the server acknowledges:
+//!
+//! ```text
+//! server: ESC _ backtor-frames/1 ESC \    (first bytes of the stream)
+//! client: NUL backtor-frames/1 LF         (first bytes the client sends)
+//! server: ESC _ backtor-framed ESC \      (output before this is still raw)
+//! ```
+//!
+//! The client sends frames right after its request, the server right after
+//! its acknowledgment. A frame is a type byte, the big-endian `u16` length of
+//! its payload and the payload. Frames of unknown types are skipped, so
+//! either side can add new ones.
+//!
+//! Both sides ping each other every [`PING_INTERVAL`], so a side that hears
+//! nothing for [`PEER_TIMEOUT`] knows the circuit died, while a busy but
+//! quiet remote still answers. Pongs come back in the order of the pings.
+//!
+//! A client can ask for the screen instead of the output with
+//! [`Frame::Sync`]; the server then sends [`Frame::Screen`] updates, each
+//! followed by a ping (see [`screen_sync`](crate::screen_sync)).
+
+use anyhow::Error;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// What the server sends first on every shell stream.
+pub(crate) const OFFER: &[u8] = b"\x1b_backtor-frames/1\x1b\\";
+
+/// What a client that takes up the [`OFFER`] sends first.
+pub(crate) const REQUEST: &[u8] = b"\0backtor-frames/1\n";
+
+/// What the server sends once it has seen the [`REQUEST`].
+pub(crate) const ACK: &[u8] = b"\x1b_backtor-framed\x1b\\";
+
+/// How often each side pings the other.
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a side waits to hear anything from its peer before giving up on
+/// it.
+pub(crate) const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest payload a frame can carry.
+const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+const DATA: u8 = 0;
+const KEEPALIVE: u8 = 1;
+const PING: u8 = 2;
+const PONG: u8 = 3;
+const SYNC: u8 = 4;
+const SCREEN: u8 = 5;
+
+/// One frame of a framed shell stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// Terminal bytes: keystrokes from the client, output from the server.
+    Data(Vec<u8>),
+    /// Sent by clients to keep a quiet session from timing out.
+    Keepalive,
+    /// Asks the peer to answer with a [`Pong`](Frame::Pong) carrying the
+    /// same value, which is up to the sender (e.g. the time it was sent).
+    Ping(u64),
+    Pong(u64),
+    /// Sent by clients to be sent the screen, `rows` by `cols`, instead of
+    /// the output, and again whenever their terminal changes size.
+    Sync {
+        rows: u16,
+        cols: u16,
+    },
+    /// Terminal bytes that bring the client's screen up to date, the first
+    /// time and after a [`Sync`](Frame::Sync) by redrawing it all.
+    Screen(Vec<u8>),
+    /// A frame of a type this version does not know.
+    Unknown(u8),
+}
+
+/// Reads the next frame, or `None` if the stream ended between frames.
+pub(crate) async fn read_frame<R>(reader: &mut R) -> Result<Option<Frame>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let kind = match reader.read_u8().await {
+        Ok(kind) => kind,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut payload = vec![0; usize::from(reader.read_u16().await?)];
+    reader.read_exact(&mut payload).await?;
+    let value = <[u8; 8]>::try_from(payload.as_slice()).map(u64::from_be_bytes);
+    let size = <[u8; 4]>::try_from(payload.as_slice());
+    Ok(Some(match (kind, value, size) {
+        (DATA, _, _) => Frame::Data(payload),
+        (KEEPALIVE, _, _) => Frame::Keepalive,
+        (PING, Ok(value), _) => Frame::Ping(value),
+        (PONG, Ok(value), _) => Frame::Pong(value),
+        (SYNC, _, Ok([r0, r1, c0, c1])) => Frame::Sync {
+            rows: u16::from_be_bytes([r0, r1]),
+            cols: u16::from_be_bytes([c0, c1]),
+        },
+        (SCREEN, _, _) => Frame::Screen(payload),
+        (kind, _, _) => Frame::Unknown(kind),
+    }))
+}
+
+/// Writes `frame`, split up if its payload is too long for one, and flushes
+/// it.
+pub(crate) async fn write_frame<W>(writer: &mut W, frame: &Frame) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let value;
+    let size;
+    let (kind, payload): (u8, &[u8]) = match frame {
+        Frame::Data(data) => (DATA, data),
+        Frame::Keepalive => (KEEPALIVE, &[]),
+        Frame::Ping(v) => {
+            value = v.to_be_bytes();
+            (PING, &value)
+        }
+        Frame::Pong(v) => {
+            value = v.to_be_bytes();
+            (PONG, &value)
+        }
+        Frame::Sync { rows, cols } => {
+            size = [rows.to_be_bytes(), cols.to_be_bytes()].concat();
+            (SYNC, &size)
+        }
+        Frame::Screen(data) => (SCREEN, data),
+        Frame::Unknown(kind) => (*kind, &[]),
+    };
+    for chunk in payload.chunks(MAX_PAYLOAD) {
+        write_one(writer, kind, chunk).await?;
+    }
+    if payload.is_empty() {
+        write_one(writer, kind, payload).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn write_one<W>(writer: &mut W, kind: u8, payload: &[u8]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(3 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Reads bytes for as long as they match `expected`.
+///
+/// Returns whether all of `expected` was read, and otherwise the bytes read,
+/// which belong to the raw stream.
+///
+/// Byte-at-a-time reads are fine here: callers read through a buffer.
+pub(crate) async fn read_prefix<R>(
+    reader: &mut R,
+    expected: &[u8],
+) -> Result<Result<(), Vec<u8>>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut read = Vec::new();
+    for &byte in expected {
+        match reader.read_u8().await {
+            Ok(b) => read.push(b),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(Err(read)),
+            Err(e) => return Err(e.into()),
+        }
+        if read.last() != Some(&byte) {
+            return Ok(Err(read));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Finds [`ACK`] in the raw output that precedes it, however that output is
+/// split into reads.
+#[cfg(feature = "client")]
+#[derive(Debug, Default)]
+pub(crate) struct AckScanner {
+    /// How many bytes of [`ACK`] the output seen so far ends with.
+    matched: usize,
+}
+
+#[cfg(feature = "client")]
+impl AckScanner {
+    /// Feeds `byte` of raw output. Returns the output bytes to show, which
+    /// lag behind while they might be the start of [`ACK`], or `None` once
+    /// `byte` completed it.
+    pub(crate) fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        let mut candidate = ACK[..self.matched].to_vec();
+        candidate.push(byte);
+        if candidate == ACK {
+            self.matched = 0;
+            return None;
+        }
+        // Keep the longest tail that could still become the ACK.
+        let keep = (0..=candidate.len())
+            .rev()
+            .find(|&n| ACK.starts_with(&candidate[candidate.len() - n..]))
+            .unwrap_or(0);
+        self.matched = keep;
+        candidate.truncate(candidate.len() - keep);
+        Some(candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encode(frames: &[Frame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            write_frame(&mut bytes, frame).await.unwrap();
+        }
+        bytes
+    }
+
+    async fn decode(mut bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut bytes).await.unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let frames = [
+            Frame::Data(b"ls\r".to_vec()),
+            Frame::Data(Vec::new()),
+            Frame::Keepalive,
+            Frame::Ping(u64::MAX),
+            Frame::Pong(7),
+            Frame::Sync { rows: 24, cols: 80 },
+            Frame::Screen(b"\x1b[H\x1b[J$ ".to_vec()),
+        ];
+        assert_eq!(decode(&encode(&frames).await).await, frames);
+    }
+
+    #[tokio::test]
+    async fn long_payloads_are_split() {
+        let data: Vec<u8> = (0..MAX_PAYLOAD + 100).map(|i| i as u8).collect();
+        let bytes = encode(&[Frame::Data(data.clone())]).await;
+        assert_eq!(bytes.len(), data.len() + 2 * 3);
+        assert_eq!(
+            decode(&bytes).await,
+            [
+                Frame::Data(data[..MAX_PAYLOAD].to_vec()),
+                Frame::Data(data[MAX_PAYLOAD..].to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_frames_are_skipped() {
+        let mut bytes = vec![9, 0, 3, 1, 2, 3];
+        // A ping with the wrong payload size is no ping.
+        bytes.extend_from_slice(&[PING, 0, 1, 0]);
+        bytes.extend_from_slice(&encode(&[Frame::Pong(1)]).await);
+        assert_eq!(
+            decode(&bytes).await,
+            [Frame::Unknown(9), Frame::Unknown(PING), Frame::Pong(1)]
+        );
+        assert_eq!(encode(&[Frame::Unknown(9)]).await, [9, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        assert!(read_frame(&mut &[DATA, 0, 5, b'a'][..]).await.is_err());
+        assert!(read_frame(&mut &[DATA, 0][..]).await.is_err());
+        assert_eq!(read_frame(&mut &[][..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_prefixes() {
+        let mut input = &b"\0backtor-frames/1\nrest"[..];
+        assert_eq!(read_prefix(&mut input, REQUEST).await.unwrap(), Ok(()));
+        assert_eq!(input, b"rest");
+        let mut input = &b"\0ls"[..];
+        assert_eq!(
+            read_prefix(&mut input, REQUEST).await.unwrap(),
+            Err(b"\0l".to_vec())
+        );
+    }
+
+    /// Pushes `bytes` and returns the output shown, and whether the ACK was
+    /// found.
+    #[cfg(feature = "client")]
+    fn scan(scanner: &mut AckScanner, bytes: &[u8]) -> (Vec<u8>, bool) {
+        let mut shown = Vec::new();
+        for &byte in bytes {
+            match scanner.push(byte) {
+                Some(output) => shown.extend(output),
+                None => return (shown, true),
+            }
+        }
+        (shown, false)
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn finds_the_ack_after_raw_output() {
+        let mut scanner = AckScanner::default();
+        let input = [b"login: ".as_slice(), ACK].concat();
+        assert_eq!(scan(&mut scanner, &input), (b"login: ".to_vec(), true));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn finds_an_ack_split_across_reads() {
+        let mut scanner = AckScanner::default();
+        let (head, tail) = ACK.split_at(5);
+        assert_eq!(
+            scan(&mut scanner, &[b"$ ", head].concat()),
+            (b"$ ".to_vec(), false)
+        );
+        assert_eq!(scan(&mut scanner, tail), (Vec::new(), true));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn passes_on_near_misses() {
+        let mut scanner = AckScanner::default();
+        // Held back while it could be the ACK, then shown.
+        assert_eq!(scan(&mut scanner, b"\x1b_back"), (Vec::new(), false));
+        assert_eq!(scan(&mut scanner, b"up"), (b"\x1b_backup".to_vec(), false));
+        // An escape right before the real one.
+        let input = [b"\x1b".as_slice(), ACK].concat();
+        assert_eq!(scan(&mut scanner, &input), (b"\x1b".to_vec(), true));
+    }
+}
@@ -0,0 +1,464 @@
+//! A minimal SOCKS5 (RFC 1928) server front end.
+//!
+//! `connect -D` only needs the `CONNECT` command with the "no
+//! authentication" method, which is all a browser uses. `backtor socks`
+//! additionally accepts username/password authentication (RFC 1929), whose
+//! credentials select the stream isolation group, and Tor's `RESOLVE` and
+//! `RESOLVE_PTR` extensions.
+//!
+//! [`connect`] is the client side, used to reach bridges through an upstream
+//! proxy (see [`crate::bridges`]).
+
+use anyhow::{Error, bail};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+/// Version of the RFC 1929 username/password subnegotiation.
+const AUTH_VERSION: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+/// Tor extension: resolve a host name to an address.
+const CMD_RESOLVE: u8 = 0xf0;
+/// Tor extension: resolve an address to a host name.
+const CMD_RESOLVE_PTR: u8 = 0xf1;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes from RFC 1928 section 6.
+pub(crate) const REPLY_SUCCEEDED: u8 = 0x00;
+pub(crate) const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub(crate) const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub(crate) const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub(crate) const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// What a SOCKS client asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Command {
+    Connect,
+    Resolve,
+    ResolvePtr,
+}
+
+/// A request read from a SOCKS client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) command: Command,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// Username and password, if the client authenticated with them.
+    pub(crate) credentials: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Request {
+    /// The target as `host:port`, bracketing IPv6 literals.
+    pub(crate) fn target(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// The address carried in a reply.
+pub(crate) enum BoundAddr<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+/// Runs the SOCKS5 handshake up to and including a `CONNECT` request.
+///
+/// Unsupported methods, commands and address types are answered with the
+/// appropriate error reply before an error is returned; a successful request
+/// still has to be answered with [`reply`].
+pub(crate) async fn accept_connect<S>(stream: &mut S) -> Result<Request, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = accept_request(stream, false).await?;
+    if request.command != Command::Connect {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        bail!("unsupported SOCKS command {:?}", request.command);
+    }
+    Ok(request)
+}
+
+/// Runs the SOCKS5 handshake up to and including the request.
+///
+/// With `allow_auth`, clients offering username/password authentication
+/// are asked for their credentials, which are returned with the request.
+/// Errors are answered like in [`accept_connect`].
+pub(crate) async fn accept_request<S>(stream: &mut S, allow_auth: bool) -> Result<Request, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Greeting: VER NMETHODS METHODS...
+    if stream.read_u8().await? != VERSION {
+        bail!("not a SOCKS5 client");
+    }
+    let mut methods = vec![0u8; usize::from(stream.read_u8().await?)];
+    stream.read_exact(&mut methods).await?;
+
+    let credentials = if allow_auth && methods.contains(&METHOD_USERNAME_PASSWORD) {
+        stream
+            .write_all(&[VERSION, METHOD_USERNAME_PASSWORD])
+            .await?;
+        // Subnegotiation: VER ULEN UNAME PLEN PASSWD
+        if stream.read_u8().await? != AUTH_VERSION {
+            bail!("bad username/password subnegotiation version");
+        }
+        let mut username = vec![0u8; usize::from(stream.read_u8().await?)];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; usize::from(stream.read_u8().await?)];
+        stream.read_exact(&mut password).await?;
+        // Any credentials are accepted; they only select an isolation group.
+        stream.write_all(&[AUTH_VERSION, 0x00]).await?;
+        Some((username, password))
+    } else if methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+        None
+    } else {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        bail!("client offers no acceptable authentication method");
+    };
+
+    // Request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, atyp] = header;
+    if version != VERSION {
+        bail!("bad SOCKS version {version} in request");
+    }
+
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; usize::from(stream.read_u8().await?)];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| anyhow::anyhow!("host name is not UTF-8"))?
+        }
+        other => {
+            reply(stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            bail!("unsupported address type {other}");
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    let command = match command {
+        CMD_CONNECT => Command::Connect,
+        CMD_RESOLVE => Command::Resolve,
+        CMD_RESOLVE_PTR => Command::ResolvePtr,
+        other => {
+            reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+            bail!("unsupported SOCKS command {other}");
+        }
+    };
+
+    Ok(Request {
+        command,
+        host,
+        port,
+        credentials,
+    })
+}
+
+/// Sends a reply with the given code and an all-zero bound address.
+pub(crate) async fn reply<S>(stream: &mut S, code: u8) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    reply_with(stream, code, BoundAddr::Ip(Ipv4Addr::UNSPECIFIED.into()), 0).await
+}
+
+/// Sends a reply with the given code and bound address, which is how the
+/// answers to `RESOLVE` and `RESOLVE_PTR` are delivered.
+pub(crate) async fn reply_with<S>(
+    stream: &mut S,
+    code: u8,
+    addr: BoundAddr<'_>,
+    port: u16,
+) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut packet = vec![VERSION, code, 0x00];
+    match addr {
+        BoundAddr::Ip(IpAddr::V4(ip)) => {
+            packet.push(ATYP_IPV4);
+            packet.extend_from_slice(&ip.octets());
+        }
+        BoundAddr::Ip(IpAddr::V6(ip)) => {
+            packet.push(ATYP_IPV6);
+            packet.extend_from_slice(&ip.octets());
+        }
+        BoundAddr::Domain(name) => {
+            let name = &name.as_bytes()[..name.len().min(255)];
+            packet.push(ATYP_DOMAIN);
+            packet.push(name.len() as u8);
+            packet.extend_from_slice(name);
+        }
+    }
+    packet.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Asks the SOCKS5 proxy at the other end of `stream` to connect to
+/// `host:port`, authenticating with a username and password if given.
+/// The stream carries the connection once this returns.
+pub(crate) async fn connect<S>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match credentials {
+        Some(_) => METHOD_USERNAME_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        bail!("not a SOCKS5 proxy");
+    }
+    if choice[1] != method {
+        bail!("the proxy does not accept our authentication method");
+    }
+    if let Some((username, password)) = credentials {
+        let (username, password) = (username.as_bytes(), password.as_bytes());
+        if username.len() > 255 || password.len() > 255 {
+            bail!("proxy username or password is too long");
+        }
+        let mut packet = vec![AUTH_VERSION, username.len() as u8];
+        packet.extend_from_slice(username);
+        packet.push(password.len() as u8);
+        packet.extend_from_slice(password);
+        stream.write_all(&packet).await?;
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            bail!("the proxy rejected our username and password");
+        }
+    }
+
+    let mut packet = vec![VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            packet.push(ATYP_IPV4);
+            packet.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            packet.push(ATYP_IPV6);
+            packet.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                bail!("host name {host:?} is too long");
+            }
+            packet.push(ATYP_DOMAIN);
+            packet.push(host.len() as u8);
+            packet.extend_from_slice(host.as_bytes());
+        }
+    }
+    packet.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+
+    // Reply: VER REP RSV ATYP BND.ADDR BND.PORT
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [_, code, _, atyp] = header;
+    let skip = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => usize::from(stream.read_u8().await?),
+        other => bail!("unsupported address type {other} in proxy reply"),
+    };
+    let mut bound = vec![0u8; skip + 2];
+    stream.read_exact(&mut bound).await?;
+    if code != REPLY_SUCCEEDED {
+        bail!("the proxy could not connect to {host}:{port} (SOCKS reply {code})");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the server side of the handshake on `input`, returning its
+    /// outcome and everything it wrote back.
+    async fn serve(input: &[u8], allow_auth: bool) -> (Result<Request, Error>, Vec<u8>) {
+        let (mut ours, mut theirs) = tokio::io::duplex(4096);
+        theirs.write_all(input).await.unwrap();
+        let result = accept_request(&mut ours, allow_auth).await;
+        drop(ours);
+        let mut output = Vec::new();
+        theirs.read_to_end(&mut output).await.unwrap();
+        (result, output)
+    }
+
+    fn request(host: &str, port: u16) -> Request {
+        Request {
+            command: Command::Connect,
+            host: host.to_owned(),
+            port,
+            credentials: None,
+        }
+    }
+
+    const NO_AUTH: [u8; 3] = [VERSION, 1, METHOD_NO_AUTH];
+
+    #[tokio::test]
+    async fn reads_each_address_type() {
+        let ipv4 = [VERSION, CMD_CONNECT, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80];
+        let (result, output) = serve(&[&NO_AUTH[..], &ipv4].concat(), false).await;
+        assert_eq!(result.unwrap(), request("127.0.0.1", 80));
+        assert_eq!(output, [VERSION, METHOD_NO_AUTH]);
+
+        let mut ipv6 = vec![VERSION, CMD_CONNECT, 0, ATYP_IPV6];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&443u16.to_be_bytes());
+        let (result, _) = serve(&[&NO_AUTH[..], &ipv6].concat(), false).await;
+        let request_v6 = result.unwrap();
+        assert_eq!(request_v6, request("::1", 443));
+        assert_eq!(request_v6.target(), "[::1]:443");
+
+        let mut domain = vec![VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&22u16.to_be_bytes());
+        let (result, _) = serve(&[&NO_AUTH[..], &domain].concat(), false).await;
+        assert_eq!(result.unwrap().target(), "example.com:22");
+    }
+
+    #[tokio::test]
+    async fn reads_credentials_when_allowed() {
+        let greeting = [VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD];
+        let auth = [&[AUTH_VERSION, 5][..], b"alice", &[6], b"secret"].concat();
+        let connect = [VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80];
+
+        let input = [&greeting[..], &auth, &connect].concat();
+        let (result, output) = serve(&input, true).await;
+        let credentials = result.unwrap().credentials;
+        assert_eq!(credentials, Some((b"alice".to_vec(), b"secret".to_vec())));
+        assert_eq!(
+            output,
+            [VERSION, METHOD_USERNAME_PASSWORD, AUTH_VERSION, 0x00]
+        );
+
+        // Without auth support, the client gets no-auth instead.
+        let input = [&greeting[..], &connect].concat();
+        let (result, output) = serve(&input, false).await;
+        assert_eq!(result.unwrap().credentials, None);
+        assert_eq!(output, [VERSION, METHOD_NO_AUTH]);
+
+        let (result, output) = serve(&[VERSION, 1, METHOD_USERNAME_PASSWORD], false).await;
+        assert!(result.is_err());
+        assert_eq!(output, [VERSION, METHOD_NONE_ACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn refuses_unsupported_requests() {
+        let error_reply = |code| vec![VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+
+        let bind = [VERSION, 0x02, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80];
+        let (result, output) = serve(&[&NO_AUTH[..], &bind].concat(), false).await;
+        assert!(result.is_err());
+        assert_eq!(output[2..], error_reply(REPLY_COMMAND_NOT_SUPPORTED)[..]);
+
+        let atyp = [VERSION, CMD_CONNECT, 0, 0x05, 0, 80];
+        let (result, output) = serve(&[&NO_AUTH[..], &atyp].concat(), false).await;
+        assert!(result.is_err());
+        assert_eq!(
+            output[2..],
+            error_reply(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)[..]
+        );
+
+        let (result, output) = serve(&[4, 1, 0, 80, 127, 0, 0, 1, 0], false).await;
+        assert!(result.is_err());
+        assert!(output.is_empty());
+
+        // `accept_connect` also refuses Tor's RESOLVE extension.
+        let (mut ours, mut theirs) = tokio::io::duplex(4096);
+        let mut resolve = vec![VERSION, CMD_RESOLVE, 0, ATYP_DOMAIN, 3];
+        resolve.extend_from_slice(b"a.b\0\0");
+        theirs
+            .write_all(&[&NO_AUTH[..], &resolve].concat())
+            .await
+            .unwrap();
+        assert!(accept_connect(&mut ours).await.is_err());
+        drop(ours);
+        let mut output = Vec::new();
+        theirs.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output[2..], error_reply(REPLY_COMMAND_NOT_SUPPORTED)[..]);
+    }
+
+    #[tokio::test]
+    async fn replies_with_bound_addresses() {
+        let mut output = Vec::new();
+        reply_with(&mut output, REPLY_SUCCEEDED, BoundAddr::Domain("a.b"), 7)
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            [VERSION, 0, 0, ATYP_DOMAIN, 3, b'a', b'.', b'b', 0, 7]
+        );
+    }
+
+    #[tokio::test]
+    async fn connects_through_a_proxy() {
+        let (mut proxy, mut client) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let request = accept_request(&mut proxy, true).await.unwrap();
+            reply(&mut proxy, REPLY_SUCCEEDED).await.unwrap();
+            request
+        });
+        connect(&mut client, "example.com", 443, Some(("bob", "pw")))
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert_eq!(request.target(), "example.com:443");
+        assert_eq!(request.credentials, Some((b"bob".to_vec(), b"pw".to_vec())));
+
+        let (mut proxy, mut client) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            accept_request(&mut proxy, false).await.unwrap();
+            reply(&mut proxy, REPLY_HOST_UNREACHABLE).await.unwrap();
+        });
+        let error = connect(&mut client, "192.0.2.1", 443, None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("SOCKS reply 4"), "{error}");
+
+        // A proxy that insists on credentials we do not have.
+        let (mut proxy, mut client) = tokio::io::duplex(4096);
+        proxy
+            .write_all(&[VERSION, METHOD_NONE_ACCEPTABLE])
+            .await
+            .unwrap();
+        assert!(connect(&mut client, "192.0.2.1", 443, None).await.is_err());
+    }
+}
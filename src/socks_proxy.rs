@@ -0,0 +1,116 @@
+//! `backtor socks`: a SOCKS5 proxy in front of the embedded Tor client, so
+//! other programs can reach onion services (and the rest of the Internet)
+//! without a separate Tor daemon.
+
+use anyhow::Error;
+use arti_client::TorClient;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::socks::{self, BoundAddr, Command};
+
+type Credentials = (Vec<u8>, Vec<u8>);
+
+/// Tor clients sharing the base client's state but isolated from each other,
+/// one per set of SOCKS credentials, like Tor's `IsolateSOCKSAuth`.
+struct IsolationGroups {
+    base: TorClient<PreferredRuntime>,
+    groups: Mutex<HashMap<Credentials, TorClient<PreferredRuntime>>>,
+}
+
+impl IsolationGroups {
+    fn client_for(&self, credentials: Option<Credentials>) -> TorClient<PreferredRuntime> {
+        match credentials {
+            None => self.base.clone(),
+            Some(credentials) => self
+                .groups
+                .lock()
+                .unwrap()
+                .entry(credentials)
+                .or_insert_with(|| self.base.isolated_client())
+                .clone(),
+        }
+    }
+}
+
+/// Accepts SOCKS clients on `listener` until the process exits.
+pub(crate) async fn run_socks_proxy(
+    client: TorClient<PreferredRuntime>,
+    listener: TcpListener,
+) -> Result<(), Error> {
+    info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
+    let groups = Arc::new(IsolationGroups {
+        base: client,
+        groups: Mutex::new(HashMap::new()),
+    });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let groups = groups.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_socks_client(stream, &groups).await {
+                debug!("SOCKS client {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_socks_client(mut stream: TcpStream, groups: &IsolationGroups) -> Result<(), Error> {
+    let request = socks::accept_request(&mut stream, true).await?;
+    let client = groups.client_for(request.credentials.clone());
+
+    match request.command {
+        Command::Connect => {
+            let target = request.target();
+            let tor_stream = match client.connect((request.host.as_str(), request.port)).await {
+                Ok(tor_stream) => tor_stream,
+                Err(e) => {
+                    socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE).await?;
+                    anyhow::bail!("connect to {target} failed: {e}");
+                }
+            };
+            socks::reply(&mut stream, socks::REPLY_SUCCEEDED).await?;
+            debug!("SOCKS connection to {target} opened");
+            tokio::io::copy_bidirectional(&mut stream, &mut tor_stream.compat()).await?;
+            debug!("SOCKS connection to {target} closed");
+        }
+        Command::Resolve => match client.resolve(&request.host).await {
+            Ok(addrs) if !addrs.is_empty() => {
+                // Prefer IPv4, which every SOCKS client understands.
+                let addr = addrs
+                    .iter()
+                    .find(|addr| addr.is_ipv4())
+                    .unwrap_or(&addrs[0]);
+                socks::reply_with(&mut stream, socks::REPLY_SUCCEEDED, BoundAddr::Ip(*addr), 0)
+                    .await?;
+            }
+            result => {
+                socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE).await?;
+                let reason = result.map_or_else(|e| e.to_string(), |_| "no answer".to_owned());
+                anyhow::bail!("cannot resolve {}: {reason}", request.host);
+            }
+        },
+        Command::ResolvePtr => {
+            let Ok(addr) = request.host.parse::<IpAddr>() else {
+                socks::reply(&mut stream, socks::REPLY_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+                anyhow::bail!("RESOLVE_PTR needs an address, got {}", request.host);
+            };
+            match client.resolve_ptr(addr).await {
+                Ok(names) if !names.is_empty() => {
+                    let name = BoundAddr::Domain(&names[0]);
+                    socks::reply_with(&mut stream, socks::REPLY_SUCCEEDED, name, 0).await?;
+                }
+                result => {
+                    socks::reply(&mut stream, socks::REPLY_HOST_UNREACHABLE).await?;
+                    let reason = result.map_or_else(|e| e.to_string(), |_| "no answer".to_owned());
+                    anyhow::bail!("cannot resolve {addr}: {reason}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,319 @@
+//! Integration with systemd for `backtor serve`: readiness, status and
+//! watchdog notifications (the `sd_notify` protocol), and the example unit
+//! printed by `backtor systemd-unit`.
+//!
+//! Notifications are only sent when systemd asked for them by setting
+//! `$NOTIFY_SOCKET`; otherwise every function here does nothing.
+
+use log::debug;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::onion_server::{RUNNING_ONION_SERVICES, SESSION_REAP_TIMEOUT};
+use crate::sessions::SESSIONS;
+
+/// How often the status line is refreshed when there is no watchdog asking
+/// for more frequent pings.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The notification socket systemd passed us, if any.
+static NOTIFY_SOCKET: LazyLock<Option<(UnixDatagram, SocketAddr)>> = LazyLock::new(|| {
+    let path = std::env::var_os("NOTIFY_SOCKET")?;
+    let address = notify_address(Path::new(&path))
+        .inspect_err(|e| debug!("Ignoring NOTIFY_SOCKET {}: {e}", path.display()))
+        .ok()?;
+    let socket = UnixDatagram::unbound().ok()?;
+    Some((socket, address))
+});
+
+/// Services that still have to become reachable before we report readiness.
+static PENDING_SERVICES: AtomicUsize = AtomicUsize::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Parses `$NOTIFY_SOCKET`, which is a path or, with a leading `@`, the name
+/// of a socket in the abstract namespace.
+fn notify_address(path: &Path) -> std::io::Result<SocketAddr> {
+    use std::os::unix::ffi::OsStrExt;
+
+    match path.as_os_str().as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        _ => SocketAddr::from_pathname(path),
+    }
+}
+
+/// Sends a notification like `READY=1` to systemd.
+pub(crate) fn notify(state: &str) {
+    if let Some((socket, address)) = NOTIFY_SOCKET.as_ref()
+        && let Err(e) = socket.send_to_addr(state.as_bytes(), address)
+    {
+        debug!("sd_notify {state:?} failed: {e}");
+    }
+}
+
+/// Announces that the configuration is being reloaded; send `READY=1`
+/// when done.
+pub(crate) fn notify_reloading() {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid, writable timespec and CLOCK_MONOTONIC is
+    // always available, so this only writes to `now`.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+}
+
+/// Holds back readiness until `count` services are fully reachable.
+pub(crate) fn expect_services(count: usize) {
+    PENDING_SERVICES.store(count, Ordering::SeqCst);
+}
+
+/// Called whenever a service becomes fully reachable; reports readiness
+/// once all the services expected at startup are.
+pub(crate) fn service_reachable() {
+    if count_down(&PENDING_SERVICES, &READY) {
+        notify("READY=1");
+    }
+}
+
+/// Counts one more service as reachable; true exactly once, when the last
+/// pending service is.
+fn count_down(pending: &AtomicUsize, ready: &AtomicBool) -> bool {
+    let pending = pending
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .map_or(0, |n| n - 1);
+    pending == 0 && !ready.swap(true, Ordering::SeqCst)
+}
+
+/// Pings the watchdog, if systemd enabled it for us, and keeps the status
+/// line shown by `systemctl status` up to date. Runs until the process exits.
+pub(crate) async fn run_status_updates() {
+    if NOTIFY_SOCKET.is_none() {
+        return;
+    }
+    let watchdog = watchdog_interval();
+    let period = watchdog.map_or(STATUS_INTERVAL, |interval| {
+        (interval / 2).min(STATUS_INTERVAL)
+    });
+    let mut ticker = tokio::time::interval(period);
+    let mut last_status = String::new();
+    loop {
+        ticker.tick().await;
+        if watchdog.is_some() {
+            notify("WATCHDOG=1");
+        }
+        let status = status_line();
+        if status != last_status {
+            notify(&format!("STATUS={status}"));
+            last_status = status;
+        }
+    }
+}
+
+/// The watchdog timeout systemd expects pings within, if any.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID")
+        && pid.to_str().and_then(|pid| pid.parse().ok()) != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// E.g. `2/2 services reachable, 3 sessions: ops=abc….onion web=def….onion`.
+fn status_line() -> String {
+    let running = RUNNING_ONION_SERVICES.lock().unwrap();
+    let mut services: Vec<_> = running.iter().collect();
+    services.sort_by_key(|(name, _)| name.as_str());
+    let reachable = services
+        .iter()
+        .filter(|(_, service)| service.service.status().state().is_fully_reachable())
+        .count();
+    let mut status = format!(
+        "{reachable}/{} services reachable, {} sessions",
+        services.len(),
+        SESSIONS.list().len(),
+    );
+    for (i, (name, service)) in services.iter().enumerate() {
+        status.push_str(if i == 0 { ": " } else { " " });
+        status.push_str(&format!("{name}={}", service.address));
+    }
+    status
+}
+
+/// An example unit running `backtor serve` as a `Type=notify` service, as
+/// `user` if given and as root otherwise. The server reads
+/// `/etc/backtor/config.toml` and then `config`, if given, and drains
+/// sessions for `grace` when stopped.
+pub(crate) fn unit_file(
+    executable: &Path,
+    config: Option<&Path>,
+    user: Option<&str>,
+    grace: Duration,
+) -> String {
+    let user = match user {
+        Some(user) => format!("User={user}\n"),
+        None => "# Runs as root, which `user = ...` in the config requires. Set User=\n\
+                 # to run every shell as one unprivileged account instead.\n"
+            .to_owned(),
+    };
+    let config = match config {
+        Some(config) => format!(" --config {}", config.display()),
+        None => String::new(),
+    };
+    // Leave time to drain and reap sessions, and a little to close Tor.
+    let stop_timeout = (grace + SESSION_REAP_TIMEOUT).as_secs() + 10;
+    format!(
+        "\
+[Unit]
+Description=backtor onion shell service
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={executable}{config} serve --control-socket /run/backtor/control.sock
+ExecReload=/bin/kill -HUP $MAINPID
+{user}WorkingDirectory=/var/lib/backtor
+StateDirectory=backtor
+StateDirectoryMode=0700
+CacheDirectory=backtor
+CacheDirectoryMode=0700
+RuntimeDirectory=backtor
+RuntimeDirectoryMode=0700
+UMask=0077
+
+# Becoming reachable over Tor can take a few minutes on a cold start.
+TimeoutStartSec=5min
+WatchdogSec=1min
+Restart=on-failure
+RestartSec=10s
+
+# Only signal backtor itself on stop, so it can warn shell users and drain
+# their sessions (--shutdown-grace) before the rest of the unit is killed.
+# TimeoutStopSec must exceed the grace ({grace}s) plus {reap}s to reap sessions;
+# print the unit again after changing shutdown_grace.
+KillMode=mixed
+TimeoutStopSec={stop_timeout}s
+
+# Hardening. Shells inherit these restrictions: relax ProtectSystem and
+# ProtectHome if shell users need to administer the machine.
+ProtectSystem=full
+ProtectHome=read-only
+PrivateTmp=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+
+[Install]
+WantedBy=multi-user.target
+",
+        executable = executable.display(),
+        grace = grace.as_secs(),
+        reap = SESSION_REAP_TIMEOUT.as_secs(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn notify_address_with_an_at_is_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = notify_address(Path::new("@/org/freedesktop/systemd1/notify")).unwrap();
+        assert_eq!(
+            address.as_abstract_name(),
+            Some(&b"/org/freedesktop/systemd1/notify"[..])
+        );
+        assert_eq!(address.as_pathname(), None);
+    }
+
+    #[test]
+    fn notify_address_is_otherwise_a_path() {
+        let address = notify_address(Path::new("/run/systemd/notify")).unwrap();
+        assert_eq!(
+            address.as_pathname(),
+            Some(Path::new("/run/systemd/notify"))
+        );
+    }
+
+    #[test]
+    fn ready_once_every_expected_service_is_reachable() {
+        let pending = AtomicUsize::new(2);
+        let ready = AtomicBool::new(false);
+        assert!(!count_down(&pending, &ready));
+        assert!(count_down(&pending, &ready));
+        // Services coming back later don't report readiness again.
+        assert!(!count_down(&pending, &ready));
+        assert_eq!(pending.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn ready_at_the_first_service_when_none_were_expected() {
+        let pending = AtomicUsize::new(0);
+        let ready = AtomicBool::new(false);
+        assert!(count_down(&pending, &ready));
+        assert!(!count_down(&pending, &ready));
+    }
+
+    #[test]
+    fn unit_runs_as_root_unless_given_a_user() {
+        let grace = Duration::from_secs(10);
+        let unit = unit_file(Path::new("/usr/bin/backtor"), None, None, grace);
+        assert!(!unit.contains("\nUser="));
+        assert!(unit.contains("# Runs as root"));
+        assert!(unit.contains("\nExecStart=/usr/bin/backtor serve --control-socket"));
+
+        let unit = unit_file(Path::new("/usr/bin/backtor"), None, Some("backtor"), grace);
+        assert!(unit.contains("\nUser=backtor\n"));
+        assert!(!unit.contains("# Runs as root"));
+    }
+
+    #[test]
+    fn unit_passes_the_config_file() {
+        let unit = unit_file(
+            Path::new("/usr/bin/backtor"),
+            Some(Path::new("/etc/backtor/ops.toml")),
+            None,
+            Duration::from_secs(10),
+        );
+        assert!(unit.contains(
+            "\nExecStart=/usr/bin/backtor --config /etc/backtor/ops.toml serve --control-socket"
+        ));
+    }
+
+    #[test]
+    fn unit_stop_timeout_outlasts_the_grace() {
+        let grace = Duration::from_secs(120);
+        let unit = unit_file(Path::new("/usr/bin/backtor"), None, None, grace);
+        let timeout = unit
+            .lines()
+            .find_map(|line| line.strip_prefix("TimeoutStopSec="))
+            .and_then(|timeout| timeout.strip_suffix('s'))
+            .unwrap();
+        let timeout = Duration::from_secs(timeout.parse().unwrap());
+        assert!(timeout > grace + SESSION_REAP_TIMEOUT);
+    }
+}
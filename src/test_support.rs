@@ -0,0 +1,18 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use std::path::PathBuf;
+
+/// A fresh directory for one test, accessible only to us. `name` must be
+/// unique among all the tests.
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("backtor-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,1013 @@
+//! File transfer (`backtor cp`).
+//!
+//! A transfer uses one Tor stream to [`TRANSFER_PORT`]. Every message is a
+//! JSON object prefixed with its length as a big-endian `u32`; file contents
+//! follow their `file` entry as raw bytes.
+//!
+//! The client opens with a [`Request`] and the server answers with a
+//! [`Response`]. Then the side that owns the files (the server for `get`, the
+//! client for `put`) walks them and sends one [`Entry`] per directory or
+//! file, finishing with `end`. The receiving side answers every `file` entry
+//! with a [`FileReply`]: the offset to resume from (the size of a partial
+//! copy it already has, or 0), or a reason to skip it. After `end` the
+//! receiver sends a final [`Response`] summarising the outcome.
+
+use anyhow::{Context, Error, anyhow, bail};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{IsTerminal, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+#[cfg(feature = "client")]
+use arti_client::TorClient;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "client")]
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+#[cfg(feature = "client")]
+use tor_rtcompat::PreferredRuntime;
+
+#[cfg(feature = "server")]
+use crate::audit::{self, AuditEvent};
+#[cfg(feature = "server")]
+use crate::sessions::SESSIONS;
+
+/// The virtual port file transfers are served on.
+pub(crate) const TRANSFER_PORT: u16 = 26;
+
+/// Largest JSON message we accept.
+const MAX_MESSAGE: u32 = 64 * 1024;
+
+/// Size of the buffer used to copy file contents.
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Send `path` (recursively if it is a directory and `recursive` is set).
+    Get { path: String, recursive: bool },
+    /// Receive a tree named `name` into `path`.
+    Put {
+        path: String,
+        name: String,
+        resume: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    /// For `get`, `name` is the file name of the source.
+    Ok {
+        name: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// One item of a transferred tree. `path` is relative to the root of the
+/// transfer, using `/` separators; the root itself has an empty path.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Entry {
+    Dir {
+        path: String,
+        mode: u32,
+        mtime: u64,
+        mtime_nsec: u32,
+    },
+    File {
+        path: String,
+        mode: u32,
+        mtime: u64,
+        mtime_nsec: u32,
+        size: u64,
+    },
+    End,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+enum FileReply {
+    Offset { offset: u64 },
+    Skip { message: String },
+}
+
+async fn write_message<W, T>(writer: &mut W, message: &T) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(message)?;
+    writer.write_u32(json.len() as u32).await?;
+    writer.write_all(&json).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<R, T>(reader: &mut R) -> Result<T, Error>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = reader.read_u32().await?;
+    if len > MAX_MESSAGE {
+        bail!("transfer message too large ({len} bytes)");
+    }
+    let mut json = vec![0u8; len as usize];
+    reader.read_exact(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Joins a relative entry path onto `root`, refusing anything that could
+/// escape it.
+fn join_relative(root: &Path, relative: &str) -> Result<PathBuf, Error> {
+    if relative.is_empty() {
+        return Ok(root.to_path_buf());
+    }
+    let relative = Path::new(relative);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!("refusing unsafe path {}", relative.display());
+    }
+    Ok(root.join(relative))
+}
+
+fn mode_of(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
+fn mtime_of(metadata: &std::fs::Metadata) -> (u64, u32) {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+        .unwrap_or_default()
+}
+
+/// Applies transferred permissions and modification time to `path`.
+///
+/// Only the permission bits are applied: the peer's setuid, setgid and
+/// sticky bits are dropped, so an upload cannot plant a setuid file.
+fn apply_metadata(path: &Path, mode: u32, mtime: u64, mtime_nsec: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    let modified = UNIX_EPOCH + Duration::new(mtime, mtime_nsec);
+    std::fs::File::open(path)?.set_modified(modified)
+}
+
+/// A single-line progress display on stderr, shown only when stderr is a
+/// terminal.
+struct Progress {
+    enabled: bool,
+    name: String,
+    total: u64,
+    done: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled: enabled && std::io::stderr().is_terminal(),
+            name: String::new(),
+            total: 0,
+            done: 0,
+            started: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    fn start(&mut self, name: &str, total: u64, done: u64) {
+        self.name = name.to_owned();
+        self.total = total;
+        self.done = done;
+        self.started = Instant::now();
+        self.last_draw = None;
+        self.draw();
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.done += n;
+        if self
+            .last_draw
+            .is_none_or(|t| t.elapsed() >= Duration::from_millis(200))
+        {
+            self.draw();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.draw();
+        if self.enabled {
+            eprintln!();
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        let rate = self.done as f64 / self.started.elapsed().as_secs_f64().max(0.001);
+        let mut stderr = std::io::stderr();
+        let _ = write!(
+            stderr,
+            "\r\x1b[K{}  {percent:>3}%  {}/{}  {}/s",
+            self.name,
+            human_bytes(self.done as f64),
+            human_bytes(self.total as f64),
+            human_bytes(rate),
+        );
+        let _ = stderr.flush();
+    }
+}
+
+pub(crate) fn human_bytes(mut n: f64) -> String {
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if n < 1024.0 {
+            return format!("{n:.1} {unit}");
+        }
+        n /= 1024.0;
+    }
+    format!("{n:.1} TiB")
+}
+
+/// Walks `source` and sends it to the peer, returning the number of content
+/// bytes sent.
+async fn send_tree<S>(
+    stream: &mut S,
+    source: &Path,
+    recursive: bool,
+    progress: &mut Progress,
+) -> Result<u64, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut sent = 0;
+    let mut pending = vec![(source.to_path_buf(), String::new())];
+    while let Some((path, relative)) = pending.pop() {
+        // Follow a symlink given as the source itself, but never inside the
+        // tree, so a transfer cannot wander off through a link.
+        let metadata = if relative.is_empty() {
+            tokio::fs::metadata(&path).await
+        } else {
+            tokio::fs::symlink_metadata(&path).await
+        }
+        .with_context(|| format!("cannot stat {}", path.display()))?;
+        let (mtime, mtime_nsec) = mtime_of(&metadata);
+        let mode = mode_of(&metadata);
+
+        if metadata.is_dir() {
+            if !recursive {
+                let message = format!("{} is a directory (use -r)", path.display());
+                write_message(
+                    stream,
+                    &Entry::Error {
+                        message: message.clone(),
+                    },
+                )
+                .await?;
+                bail!(message);
+            }
+            write_message(
+                stream,
+                &Entry::Dir {
+                    path: relative.clone(),
+                    mode,
+                    mtime,
+                    mtime_nsec,
+                },
+            )
+            .await?;
+
+            let mut children = Vec::new();
+            let mut dir = tokio::fs::read_dir(&path).await?;
+            while let Some(child) = dir.next_entry().await? {
+                children.push(child.file_name());
+            }
+            children.sort();
+            for name in children.into_iter().rev() {
+                let Some(name) = name.to_str().map(str::to_owned) else {
+                    warn!("Skipping non-UTF-8 file name in {}", path.display());
+                    continue;
+                };
+                let child_relative = if relative.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{relative}/{name}")
+                };
+                pending.push((path.join(name), child_relative));
+            }
+        } else if metadata.is_file() {
+            let size = metadata.len();
+            write_message(
+                stream,
+                &Entry::File {
+                    path: relative.clone(),
+                    mode,
+                    mtime,
+                    mtime_nsec,
+                    size,
+                },
+            )
+            .await?;
+
+            let offset = match read_message::<_, FileReply>(stream).await? {
+                FileReply::Offset { offset } => offset.min(size),
+                FileReply::Skip { message } => {
+                    warn!("Skipped {}: {message}", path.display());
+                    continue;
+                }
+            };
+
+            let mut file = tokio::fs::File::open(&path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            let mut remaining = size - offset;
+            let mut buf = vec![0u8; CHUNK];
+            progress.start(&display_name(&path), size, offset);
+            while remaining > 0 {
+                let want = remaining.min(CHUNK as u64) as usize;
+                let n = file.read(&mut buf[..want]).await?;
+                if n == 0 {
+                    bail!("{} shrank while it was being sent", path.display());
+                }
+                stream.write_all(&buf[..n]).await?;
+                remaining -= n as u64;
+                sent += n as u64;
+                progress.advance(n as u64);
+            }
+            stream.flush().await?;
+            progress.finish();
+        } else {
+            warn!(
+                "Skipping {}: not a regular file or directory",
+                path.display()
+            );
+        }
+    }
+
+    write_message(stream, &Entry::End).await?;
+    Ok(sent)
+}
+
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// The name a local source is sent under. A path without a last component,
+/// like `.` or `..`, is resolved first so that `cp -r . host:dir` sends the
+/// current directory by its name.
+#[cfg(feature = "client")]
+fn source_name(path: &Path) -> Result<String, Error> {
+    if let Some(name) = path.file_name() {
+        return Ok(name.to_string_lossy().into_owned());
+    }
+    let resolved =
+        std::fs::canonicalize(path).with_context(|| format!("cannot stat {}", path.display()))?;
+    match resolved.file_name() {
+        Some(name) => Ok(name.to_string_lossy().into_owned()),
+        None => bail!(
+            "cannot copy {}: it has no name to copy it as",
+            path.display()
+        ),
+    }
+}
+
+/// Receives a tree from the peer into `destination`, returning the number
+/// of content bytes received.
+///
+/// Like `cp`, an existing directory `destination` receives the tree as
+/// `destination/name`; otherwise the tree's root is written to
+/// `destination` itself. `check` is called for every path before it is
+/// written, and entries it rejects are skipped.
+async fn receive_tree<S>(
+    stream: &mut S,
+    destination: &Path,
+    name: &str,
+    resume: bool,
+    check: &(dyn Fn(&Path) -> Result<(), Error> + Send + Sync),
+    progress: &mut Progress,
+) -> Result<u64, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let root = if tokio::fs::metadata(destination)
+        .await
+        .is_ok_and(|m| m.is_dir())
+    {
+        join_relative(destination, name)?
+    } else {
+        destination.to_path_buf()
+    };
+
+    let mut received = 0;
+    let mut errors = Vec::new();
+    // Directory metadata is applied last, since writing their contents
+    // changes their modification time.
+    let mut directories = Vec::new();
+
+    loop {
+        match read_message::<_, Entry>(stream).await? {
+            Entry::Dir {
+                path,
+                mode,
+                mtime,
+                mtime_nsec,
+            } => {
+                let result = async {
+                    let target = join_relative(&root, &path)?;
+                    check(&target)?;
+                    tokio::fs::create_dir_all(&target)
+                        .await
+                        .with_context(|| format!("cannot create {}", target.display()))?;
+                    Ok::<_, Error>(target)
+                }
+                .await;
+                match result {
+                    Ok(target) => directories.push((target, mode, mtime, mtime_nsec)),
+                    Err(e) => errors.push(e.to_string()),
+                }
+            }
+            Entry::File {
+                path,
+                mode,
+                mtime,
+                mtime_nsec,
+                size,
+            } => {
+                let opened = open_for_receive(&root, &path, size, resume, check).await;
+                let (target, mut file, offset) = match opened {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        let message = e.to_string();
+                        write_message(
+                            stream,
+                            &FileReply::Skip {
+                                message: message.clone(),
+                            },
+                        )
+                        .await?;
+                        errors.push(message);
+                        continue;
+                    }
+                };
+                write_message(stream, &FileReply::Offset { offset }).await?;
+
+                let mut remaining = size - offset;
+                let mut buf = vec![0u8; CHUNK];
+                progress.start(&display_name(&target), size, offset);
+                while remaining > 0 {
+                    let want = remaining.min(CHUNK as u64) as usize;
+                    let n = stream.read(&mut buf[..want]).await?;
+                    if n == 0 {
+                        bail!("connection closed while receiving {}", target.display());
+                    }
+                    file.write_all(&buf[..n]).await?;
+                    remaining -= n as u64;
+                    received += n as u64;
+                    progress.advance(n as u64);
+                }
+                file.flush().await?;
+                drop(file);
+                progress.finish();
+
+                if let Err(e) = apply_metadata(&target, mode, mtime, mtime_nsec) {
+                    warn!("Cannot set metadata on {}: {e}", target.display());
+                }
+            }
+            Entry::End => break,
+            Entry::Error { message } => bail!(message),
+        }
+    }
+
+    for (target, mode, mtime, mtime_nsec) in directories.into_iter().rev() {
+        if let Err(e) = apply_metadata(&target, mode, mtime, mtime_nsec) {
+            warn!("Cannot set metadata on {}: {e}", target.display());
+        }
+    }
+
+    let response = match errors.first() {
+        None => Response::Ok { name: None },
+        Some(first) if errors.len() == 1 => Response::Error {
+            message: first.clone(),
+        },
+        Some(first) => Response::Error {
+            message: format!("{first} (and {} more errors)", errors.len() - 1),
+        },
+    };
+    write_message(stream, &response).await?;
+    match response {
+        Response::Ok { .. } => Ok(received),
+        Response::Error { message } => bail!(message),
+    }
+}
+
+/// Opens the destination of a `file` entry, positioned at the offset to
+/// resume from.
+async fn open_for_receive(
+    root: &Path,
+    relative: &str,
+    size: u64,
+    resume: bool,
+    check: &(dyn Fn(&Path) -> Result<(), Error> + Send + Sync),
+) -> Result<(PathBuf, tokio::fs::File, u64), Error> {
+    let target = join_relative(root, relative)?;
+    check(&target)?;
+
+    let existing = tokio::fs::symlink_metadata(&target).await.ok();
+    if existing
+        .as_ref()
+        .is_some_and(|m| m.file_type().is_symlink())
+    {
+        bail!("refusing to write through symlink {}", target.display());
+    }
+    let offset = match existing {
+        Some(m) if resume && m.is_file() && m.len() <= size => m.len(),
+        _ => 0,
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(offset == 0)
+        .open(&target)
+        .await
+        .with_context(|| format!("cannot open {}", target.display()))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    debug!("Receiving {} from offset {offset}", target.display());
+    Ok((target, file, offset))
+}
+
+// ── Client side ─────────────────────────────────────────────────────────────
+
+/// One side of a `backtor cp` invocation: a local path or `ADDRESS:path`.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyLocation {
+    Local(PathBuf),
+    Remote { host: String, path: String },
+}
+
+#[cfg(feature = "client")]
+impl CopyLocation {
+    /// Parses a `cp` argument. An argument is remote when the part before
+    /// the first `:` looks like an onion address.
+    pub fn parse(arg: &str) -> Self {
+        if let Some((host, path)) = arg.split_once(':') {
+            let id = host.strip_suffix(".onion").unwrap_or(host);
+            if id.len() == 56
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b))
+            {
+                return CopyLocation::Remote {
+                    host: format!("{id}.onion"),
+                    path: path.to_owned(),
+                };
+            }
+        }
+        CopyLocation::Local(PathBuf::from(arg))
+    }
+}
+
+/// Copies between the local machine and a backtor server.
+///
+/// Exactly one of `source` and `destination` must be remote.
+#[cfg(feature = "client")]
+pub(crate) async fn copy(
+    client: &TorClient<PreferredRuntime>,
+    source: CopyLocation,
+    destination: CopyLocation,
+    recursive: bool,
+    resume: bool,
+) -> Result<(), Error> {
+    let (host, request, local) = match (source, destination) {
+        (CopyLocation::Local(local), CopyLocation::Remote { host, path }) => {
+            let name = source_name(&local)?;
+            (host, Request::Put { path, name, resume }, local)
+        }
+        (CopyLocation::Remote { host, path }, CopyLocation::Local(local)) => {
+            (host, Request::Get { path, recursive }, local)
+        }
+        (CopyLocation::Local(_), CopyLocation::Local(_)) => {
+            bail!("one side of the copy must be a remote ADDRESS:path")
+        }
+        (CopyLocation::Remote { .. }, CopyLocation::Remote { .. }) => {
+            bail!("copying between two remote hosts is not supported")
+        }
+    };
+
+    if let Request::Put { .. } = request {
+        let metadata = tokio::fs::metadata(&local)
+            .await
+            .with_context(|| format!("cannot stat {}", local.display()))?;
+        if metadata.is_dir() && !recursive {
+            bail!("{} is a directory (use -r)", local.display());
+        }
+    }
+
+    debug!("Connecting to {host}:{TRANSFER_PORT} for file transfer…");
+    let stream = client
+        .connect((host.as_str(), TRANSFER_PORT))
+        .await
+        .map_err(|e| anyhow!("Tor connect failed: {e}"))?;
+    let mut stream = stream.compat();
+
+    let mut progress = Progress::new(true);
+    write_message(&mut stream, &request).await?;
+    let name = match read_message::<_, Response>(&mut stream).await? {
+        Response::Ok { name } => name,
+        Response::Error { message } => bail!("server refused transfer: {message}"),
+    };
+
+    match request {
+        Request::Put { .. } => {
+            send_tree(&mut stream, &local, recursive, &mut progress).await?;
+            match read_message::<_, Response>(&mut stream).await? {
+                Response::Ok { .. } => Ok(()),
+                Response::Error { message } => bail!("upload incomplete: {message}"),
+            }
+        }
+        Request::Get { .. } => {
+            let name = name.ok_or_else(|| anyhow!("server did not name the source"))?;
+            receive_tree(
+                &mut stream,
+                &local,
+                &name,
+                resume,
+                &|_| Ok(()),
+                &mut progress,
+            )
+            .await
+            .map(|_| ())
+        }
+    }
+}
+
+// ── Server side ─────────────────────────────────────────────────────────────
+
+/// Where clients may read and write files.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TransferPolicy {
+    /// Canonical root directories; every transferred path must lie inside
+    /// one of them. Relative client paths are resolved against the first.
+    pub(crate) roots: Arc<[PathBuf]>,
+}
+
+#[cfg(feature = "server")]
+impl TransferPolicy {
+    /// Builds a policy from the configured roots, canonicalising them.
+    pub(crate) fn new(roots: &[PathBuf]) -> Result<Self, Error> {
+        let roots = roots
+            .iter()
+            .map(|root| {
+                std::fs::canonicalize(root)
+                    .with_context(|| format!("invalid transfer root {}", root.display()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            roots: roots.into(),
+        })
+    }
+
+    /// Resolves a client path, relative paths starting at the first root.
+    pub(crate) fn resolve(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            self.roots[0].join(path)
+        }
+    }
+
+    /// Checks that `path`, or the nearest ancestor of it that exists,
+    /// resolves to a location inside one of the roots.
+    ///
+    /// A symlink that cannot be resolved (dangling or looping) is refused
+    /// rather than skipped: writing through it would create its target,
+    /// wherever that is.
+    pub(crate) fn check(&self, path: &Path) -> Result<(), Error> {
+        let mut existing = path;
+        let canonical = loop {
+            match std::fs::canonicalize(existing) {
+                Ok(canonical) => break canonical,
+                Err(_) => {
+                    if std::fs::symlink_metadata(existing).is_ok_and(|m| m.is_symlink()) {
+                        bail!("refusing unresolvable symlink {}", existing.display());
+                    }
+                    existing = existing
+                        .parent()
+                        .ok_or_else(|| anyhow!("{} does not exist", path.display()))?;
+                }
+            }
+        };
+        // Any not-yet-existing tail must not climb back out again.
+        let tail = path.strip_prefix(existing).unwrap_or(Path::new(""));
+        let tail_is_safe = tail.components().all(|c| matches!(c, Component::Normal(_)));
+
+        if tail_is_safe && self.roots.iter().any(|root| canonical.starts_with(root)) {
+            Ok(())
+        } else {
+            bail!("{} is outside the transfer roots", path.display())
+        }
+    }
+}
+
+/// Serves one `backtor cp` request.
+#[cfg(feature = "server")]
+pub(crate) async fn handle_transfer_connection<S>(
+    mut stream: S,
+    policy: TransferPolicy,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let request = match read_message::<_, Request>(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Bad transfer request: {e}");
+            return;
+        }
+    };
+    let (op, resolved) = match &request {
+        Request::Get { path, .. } => ("get", policy.resolve(Path::new(path))),
+        Request::Put { path, .. } => ("put", policy.resolve(Path::new(path))),
+    };
+    let path = resolved.display().to_string();
+    let handle = SESSIONS.register(&service, circuit, port, "transfer", &format!("{op} {path}"));
+    let session = handle.id();
+    let started = Instant::now();
+
+    let transfer = async {
+        match request {
+            Request::Get { recursive, .. } => {
+                serve_get(&mut stream, &policy, &resolved, recursive).await
+            }
+            Request::Put { name, resume, .. } => {
+                serve_put(&mut stream, &policy, &resolved, &name, resume).await
+            }
+        }
+    };
+    let result = tokio::select! {
+        result = transfer => result,
+        () = handle.cancelled() => Err(anyhow!("killed from the control socket")),
+    };
+
+    let error = result.as_ref().err().map(ToString::to_string);
+    match &error {
+        None => debug!("Transfer ({op} {path}) finished"),
+        Some(e) => debug!("Transfer ({op} {path}) failed: {e}"),
+    }
+    audit::record(AuditEvent::Transfer {
+        service: &service,
+        circuit,
+        session,
+        port,
+        op,
+        path: &path,
+        bytes: result.unwrap_or(0),
+        duration_secs: started.elapsed().as_secs_f64(),
+        error: error.as_deref(),
+    });
+}
+
+#[cfg(feature = "server")]
+async fn serve_get<S>(
+    stream: &mut S,
+    policy: &TransferPolicy,
+    source: &Path,
+    recursive: bool,
+) -> Result<u64, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = policy.check(source) {
+        let message = e.to_string();
+        write_message(stream, &Response::Error { message }).await?;
+        return Err(e);
+    }
+    let name = Some(display_name(source));
+    write_message(stream, &Response::Ok { name }).await?;
+
+    let mut progress = Progress::new(false);
+    let sent = send_tree(stream, source, recursive, &mut progress).await?;
+    match read_message::<_, Response>(stream).await? {
+        Response::Ok { .. } => Ok(sent),
+        Response::Error { message } => bail!("client reported: {message}"),
+    }
+}
+
+#[cfg(feature = "server")]
+async fn serve_put<S>(
+    stream: &mut S,
+    policy: &TransferPolicy,
+    destination: &Path,
+    name: &str,
+    resume: bool,
+) -> Result<u64, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = policy.check(destination) {
+        let message = e.to_string();
+        write_message(stream, &Response::Error { message }).await?;
+        return Err(e);
+    }
+    write_message(stream, &Response::Ok { name: None }).await?;
+
+    let mut progress = Progress::new(false);
+    let check = |path: &Path| policy.check(path);
+    receive_tree(stream, destination, name, resume, &check, &mut progress).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "server")]
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn joins_only_plain_relative_paths() {
+        let root = Path::new("/srv");
+        assert_eq!(join_relative(root, "").unwrap(), root);
+        assert_eq!(join_relative(root, "a/b").unwrap(), root.join("a/b"));
+        for relative in ["..", "a/../../b", "/etc/passwd", "./a"] {
+            assert!(
+                join_relative(root, relative).is_err(),
+                "{relative:?} joined"
+            );
+        }
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn sources_are_sent_under_their_name() {
+        let cwd = std::env::current_dir().unwrap();
+        let cwd_name = cwd.file_name().unwrap().to_str().unwrap();
+        assert_eq!(source_name(Path::new("notes.txt")).unwrap(), "notes.txt");
+        assert_eq!(source_name(Path::new("photos/")).unwrap(), "photos");
+        assert_eq!(source_name(Path::new(".")).unwrap(), cwd_name);
+        assert_eq!(source_name(Path::new("src/..")).unwrap(), cwd_name);
+        assert!(source_name(Path::new("/")).is_err());
+
+        // What the server then does with the name of `cp -r . host:dir`.
+        let name = source_name(Path::new(".")).unwrap();
+        assert!(join_relative(Path::new("/srv"), &name).is_ok());
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn parses_copy_locations() {
+        let id = "a".repeat(52) + "2345";
+        assert_eq!(
+            CopyLocation::parse(&format!("{id}:notes.txt")),
+            CopyLocation::Remote {
+                host: format!("{id}.onion"),
+                path: "notes.txt".into(),
+            }
+        );
+        assert_eq!(
+            CopyLocation::parse(&format!("{id}.onion:/etc/hosts")),
+            CopyLocation::Remote {
+                host: format!("{id}.onion"),
+                path: "/etc/hosts".into(),
+            }
+        );
+        for local in [
+            "notes.txt".to_owned(),
+            "./a:b".to_owned(),
+            "host:path".to_owned(),
+            format!("{}:x", id.to_uppercase()),
+            format!("{id}8:x"),
+        ] {
+            assert_eq!(
+                CopyLocation::parse(&local),
+                CopyLocation::Local(local.clone().into())
+            );
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn policy_allows_paths_inside_the_roots() {
+        let dir = scratch_dir("inside");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let policy = TransferPolicy::new(std::slice::from_ref(&root)).unwrap();
+
+        assert_eq!(
+            policy.resolve(Path::new("sub")),
+            policy.roots[0].join("sub")
+        );
+        for path in ["", "sub", "sub/new.txt", "new/dir/file"] {
+            let path = policy.resolve(Path::new(path));
+            assert!(policy.check(&path).is_ok(), "{} refused", path.display());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn policy_refuses_paths_that_climb_out() {
+        let dir = scratch_dir("climb");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        let policy = TransferPolicy::new(std::slice::from_ref(&root)).unwrap();
+
+        for path in ["..", "../outside/file", "missing/../../outside/file"] {
+            let path = policy.resolve(Path::new(path));
+            assert!(policy.check(&path).is_err(), "{} allowed", path.display());
+        }
+        assert!(policy.check(&dir.join("outside")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(all(feature = "server", unix))]
+    #[test]
+    fn policy_refuses_symlinks_out_of_the_roots() {
+        let dir = scratch_dir("symlink");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("relative")).unwrap();
+        let policy = TransferPolicy::new(std::slice::from_ref(&root)).unwrap();
+
+        for path in ["link", "link/file", "relative/new/file"] {
+            let path = policy.resolve(Path::new(path));
+            assert!(policy.check(&path).is_err(), "{} allowed", path.display());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(all(feature = "server", unix))]
+    #[test]
+    fn policy_refuses_dangling_symlinks_out_of_the_roots() {
+        let dir = scratch_dir("dangling");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/file"), root.join("file")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/dir"), root.join("dir")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        let policy = TransferPolicy::new(std::slice::from_ref(&root)).unwrap();
+
+        for path in ["file", "dir/new", "dir/new/deeper", "loop"] {
+            let path = policy.resolve(Path::new(path));
+            assert!(policy.check(&path).is_err(), "{} allowed", path.display());
+        }
+        assert!(!dir.join("outside").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn received_modes_drop_setuid_and_setgid() {
+        use std::os::unix::fs::PermissionsExt;
+        let file = std::env::temp_dir().join(format!("backtor-{}-mode", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+
+        apply_metadata(&file, 0o6755, 0, 0).unwrap();
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+        std::fs::remove_file(file).unwrap();
+    }
+}
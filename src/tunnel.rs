@@ -0,0 +1,816 @@
+//! Port forwarding over additional Tor streams.
+//!
+//! Every forwarded connection gets its own Tor stream to [`TUNNEL_PORT`] on
+//! the server's onion address. The stream starts with a single request line
+//! from the client, answered by a single status line from the server; after
+//! that the stream carries the forwarded bytes verbatim:
+//!
+//! ```text
+//! client: CONNECT <host>:<port>\n   (used by -L and -D)
+//! server: OK\n                      (or: ERR <reason>\n)
+//! ```
+//!
+//! Remote forwards (`-R`) use a long-lived control stream on which the server
+//! announces each connection it accepted on its loopback listener. The client
+//! claims it with a fresh tunnel stream carrying the announced token:
+//!
+//! ```text
+//! client: LISTEN <port>\n            (control stream)
+//! server: OK\n
+//! server: ACCEPT <token>\n           (once per accepted connection)
+//! client: BIND <token>\n             (new tunnel stream)
+//! server: OK\n
+//! ```
+
+use anyhow::{Error, anyhow, bail};
+#[cfg(feature = "client")]
+use arti_client::{DataStream, TorClient};
+use log::{debug, error};
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+#[cfg(feature = "client")]
+use std::fmt;
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+#[cfg(feature = "client")]
+use std::str::FromStr;
+#[cfg(feature = "server")]
+use std::sync::{Arc, LazyLock, Mutex};
+#[cfg(feature = "server")]
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(feature = "client")]
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+#[cfg(feature = "client")]
+use tor_rtcompat::PreferredRuntime;
+
+#[cfg(feature = "server")]
+use crate::audit::{self, AuditEvent};
+#[cfg(feature = "server")]
+use crate::egress::{self, EgressRule};
+#[cfg(feature = "server")]
+use crate::sessions::SESSIONS;
+#[cfg(feature = "client")]
+use crate::socks;
+
+/// The virtual port tunnel streams are opened to (right next to the default
+/// shell port).
+pub(crate) const TUNNEL_PORT: u16 = 24;
+
+/// Longest request or status line we are willing to read.
+const MAX_LINE: usize = 1024;
+
+/// Reads one `\n`-terminated line without consuming anything past it.
+///
+/// Byte-at-a-time reads are fine here: the line is short and Tor streams are
+/// buffered internally.
+pub(crate) async fn read_line<R>(reader: &mut R) -> Result<String, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    loop {
+        let byte = reader.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE {
+            bail!("tunnel header line too long");
+        }
+        line.push(byte);
+    }
+    String::from_utf8(line).map_err(|_| anyhow!("tunnel header is not valid UTF-8"))
+}
+
+/// Writes one `\n`-terminated line and flushes it.
+pub(crate) async fn write_line<W>(writer: &mut W, line: &str) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(format!("{line}\n").as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Splits a forwarding spec on `:`, keeping bracketed IPv6 addresses intact
+/// and stripping their brackets.
+#[cfg(feature = "client")]
+fn split_spec(spec: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = spec;
+    loop {
+        if let Some(inner) = rest.strip_prefix('[')
+            && let Some(end) = inner.find(']')
+        {
+            parts.push(&inner[..end]);
+            rest = &inner[end + 1..];
+            match rest.strip_prefix(':') {
+                Some(tail) => rest = tail,
+                None => {
+                    if !rest.is_empty() {
+                        parts.push(rest);
+                    }
+                    return parts;
+                }
+            }
+            continue;
+        }
+        match rest.split_once(':') {
+            Some((head, tail)) => {
+                parts.push(head);
+                rest = tail;
+            }
+            None => {
+                parts.push(rest);
+                return parts;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port {port:?}"))
+}
+
+/// Formats `host:port`, bracketing IPv6 literals.
+#[cfg(feature = "client")]
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// A `-L [bind_address:]port:host:hostport` local forward: the client listens
+/// on `bind_address:port` and the server dials `host:hostport`.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalForward {
+    pub bind_address: String,
+    pub port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+#[cfg(feature = "client")]
+impl LocalForward {
+    /// The local address the client listens on.
+    pub fn listen_addr(&self) -> String {
+        join_host_port(&self.bind_address, self.port)
+    }
+
+    /// The `host:port` the server connects to.
+    pub fn target(&self) -> String {
+        join_host_port(&self.host, self.host_port)
+    }
+}
+
+#[cfg(feature = "client")]
+impl FromStr for LocalForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bind_address, port, host, host_port) = match split_spec(s).as_slice() {
+            [port, host, host_port] => ("127.0.0.1", *port, *host, *host_port),
+            [bind, port, host, host_port] => (*bind, *port, *host, *host_port),
+            _ => {
+                return Err(format!(
+                    "expected [bind_address:]port:host:hostport, got {s:?}"
+                ));
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {s:?}"));
+        }
+
+        Ok(LocalForward {
+            bind_address: bind_address.to_owned(),
+            port: parse_port(port)?,
+            host: host.to_owned(),
+            host_port: parse_port(host_port)?,
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+impl fmt::Display for LocalForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.listen_addr(), self.target())
+    }
+}
+
+/// A `-D [bind_address:]port` dynamic forward: the client runs a SOCKS5
+/// proxy on `bind_address:port` whose connections the server dials.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicForward {
+    pub bind_address: String,
+    pub port: u16,
+}
+
+#[cfg(feature = "client")]
+impl DynamicForward {
+    /// The local address the SOCKS proxy listens on.
+    pub fn listen_addr(&self) -> String {
+        join_host_port(&self.bind_address, self.port)
+    }
+}
+
+#[cfg(feature = "client")]
+impl FromStr for DynamicForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bind_address, port) = match split_spec(s)[..] {
+            [port] => ("127.0.0.1", port),
+            [bind, port] => (bind, port),
+            _ => return Err(format!("expected [bind_address:]port, got {s:?}")),
+        };
+
+        Ok(DynamicForward {
+            bind_address: bind_address.to_owned(),
+            port: parse_port(port)?,
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+impl fmt::Display for DynamicForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SOCKS5 proxy on {}", self.listen_addr())
+    }
+}
+
+/// A `-R remoteport:host:hostport` remote forward: the server listens on its
+/// loopback interface at `remoteport` and the client dials `host:hostport`.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteForward {
+    pub remote_port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+#[cfg(feature = "client")]
+impl RemoteForward {
+    /// The `host:port` the client connects to.
+    pub fn target(&self) -> String {
+        join_host_port(&self.host, self.host_port)
+    }
+}
+
+#[cfg(feature = "client")]
+impl FromStr for RemoteForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [remote_port, host, host_port] = split_spec(s)[..] else {
+            return Err(format!("expected remoteport:host:hostport, got {s:?}"));
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {s:?}"));
+        }
+
+        let remote_port = parse_port(remote_port)?;
+        if remote_port == 0 {
+            return Err(format!("remote port must not be 0 in {s:?}"));
+        }
+
+        Ok(RemoteForward {
+            remote_port,
+            host: host.to_owned(),
+            host_port: parse_port(host_port)?,
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+impl fmt::Display for RemoteForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote port {} -> {}", self.remote_port, self.target())
+    }
+}
+
+/// Reads a status line and turns `ERR <reason>` into an error.
+#[cfg(feature = "client")]
+async fn expect_ok<R>(reader: &mut R, what: &str) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let status = read_line(reader).await?;
+    match status.strip_prefix("ERR ") {
+        None if status == "OK" => Ok(()),
+        Some(reason) => bail!("server refused {what}: {reason}"),
+        None => bail!("unexpected tunnel reply {status:?}"),
+    }
+}
+
+// ── Client side ─────────────────────────────────────────────────────────────
+
+/// Opens a new stream to the server's tunnel port and sends `request`,
+/// returning the stream once the server has confirmed.
+#[cfg(feature = "client")]
+async fn open_tunnel(
+    client: &TorClient<PreferredRuntime>,
+    onion_host: &str,
+    request: &str,
+) -> Result<Compat<DataStream>, Error> {
+    let stream = client
+        .connect((onion_host, TUNNEL_PORT))
+        .await
+        .map_err(|e| anyhow!("Tor connect to tunnel port failed: {e}"))?;
+    let mut stream = stream.compat();
+
+    write_line(&mut stream, request).await?;
+    expect_ok(&mut stream, request).await?;
+    Ok(stream)
+}
+
+/// Listens on the forward's local address and tunnels every accepted
+/// connection through `onion_host`.
+///
+/// Runs until the task is aborted; failures of individual connections are
+/// logged and do not stop the listener.
+#[cfg(feature = "client")]
+pub(crate) async fn run_local_forward(
+    client: TorClient<PreferredRuntime>,
+    onion_host: String,
+    forward: LocalForward,
+    listener: tokio::net::TcpListener,
+) {
+    loop {
+        let (mut local, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Local forward {forward}: accept failed: {e}");
+                continue;
+            }
+        };
+        debug!("Local forward {forward}: connection from {peer}");
+
+        let client = client.clone();
+        let onion_host = onion_host.clone();
+        let target = forward.target();
+        tokio::spawn(async move {
+            let request = format!("CONNECT {target}");
+            match open_tunnel(&client, &onion_host, &request).await {
+                Ok(mut remote) => {
+                    let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+                    debug!("Local forward to {target} closed");
+                }
+                Err(e) => error!("Local forward to {target} failed: {e}"),
+            }
+        });
+    }
+}
+
+/// Runs a SOCKS5 proxy on `listener` and asks the server to connect each
+/// requested destination from its side of the network.
+///
+/// Runs until the task is aborted.
+#[cfg(feature = "client")]
+pub(crate) async fn run_dynamic_forward(
+    client: TorClient<PreferredRuntime>,
+    onion_host: String,
+    listener: tokio::net::TcpListener,
+) {
+    loop {
+        let (mut local, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Dynamic forward: accept failed: {e}");
+                continue;
+            }
+        };
+        debug!("Dynamic forward: connection from {peer}");
+
+        let client = client.clone();
+        let onion_host = onion_host.clone();
+        tokio::spawn(async move {
+            let target = match socks::accept_connect(&mut local).await {
+                Ok(request) => request.target(),
+                Err(e) => {
+                    debug!("Dynamic forward: bad SOCKS request from {peer}: {e}");
+                    return;
+                }
+            };
+            let request = format!("CONNECT {target}");
+            match open_tunnel(&client, &onion_host, &request).await {
+                Ok(mut remote) => {
+                    if socks::reply(&mut local, socks::REPLY_SUCCEEDED)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+                    debug!("Dynamic forward to {target} closed");
+                }
+                Err(e) => {
+                    debug!("Dynamic forward to {target} failed: {e}");
+                    let _ = socks::reply(&mut local, socks::REPLY_GENERAL_FAILURE).await;
+                }
+            }
+        });
+    }
+}
+
+/// Asks the server to listen for a remote forward and returns the control
+/// stream once it has done so.
+#[cfg(feature = "client")]
+pub(crate) async fn request_remote_forward(
+    client: &TorClient<PreferredRuntime>,
+    onion_host: &str,
+    forward: &RemoteForward,
+) -> Result<Compat<DataStream>, Error> {
+    open_tunnel(
+        client,
+        onion_host,
+        &format!("LISTEN {}", forward.remote_port),
+    )
+    .await
+}
+
+/// Serves a remote forward: for every `ACCEPT` announced on `control`, claims
+/// the connection with a new tunnel stream and splices it to the forward's
+/// local target.
+///
+/// Returns when the server closes the control stream.
+#[cfg(feature = "client")]
+pub(crate) async fn run_remote_forward(
+    client: TorClient<PreferredRuntime>,
+    onion_host: String,
+    forward: RemoteForward,
+    mut control: Compat<DataStream>,
+) {
+    loop {
+        let token = match read_line(&mut control).await {
+            Ok(line) => match line.strip_prefix("ACCEPT ") {
+                Some(token) => token.to_owned(),
+                None => {
+                    error!("Remote forward {forward}: unexpected message {line:?}");
+                    continue;
+                }
+            },
+            Err(e) => {
+                debug!("Remote forward {forward}: control stream closed: {e}");
+                return;
+            }
+        };
+
+        let client = client.clone();
+        let onion_host = onion_host.clone();
+        let target = forward.target();
+        tokio::spawn(async move {
+            let mut remote = match open_tunnel(&client, &onion_host, &format!("BIND {token}")).await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Remote forward to {target} failed: {e}");
+                    return;
+                }
+            };
+            match TcpStream::connect(target.as_str()).await {
+                Ok(mut local) => {
+                    let _ = tokio::io::copy_bidirectional(&mut remote, &mut local).await;
+                    debug!("Remote forward to {target} closed");
+                }
+                Err(e) => error!("Remote forward to {target} failed: {e}"),
+            }
+        });
+    }
+}
+
+// ── Server side ─────────────────────────────────────────────────────────────
+
+/// Which tunnel requests the server honours.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TunnelPolicy {
+    /// `CONNECT`: destinations the server may dial for a client (`-L`, `-D`).
+    /// Empty means no `CONNECT` is allowed at all.
+    pub(crate) egress: Arc<[EgressRule]>,
+    /// `LISTEN`/`BIND`: listen on a server loopback port (`-R`).
+    pub(crate) allow_listen: bool,
+}
+
+/// How long an announced remote-forward connection waits for its `BIND`.
+#[cfg(feature = "server")]
+const BIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections accepted on a remote-forward listener, waiting for the client
+/// to claim them, keyed by their unguessable token.
+#[cfg(feature = "server")]
+static PENDING_BINDS: LazyLock<Mutex<HashMap<String, (TcpStream, String)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Reads the request line of a tunnel stream and serves it according to
+/// `policy`.
+///
+/// Only reachable when the operator enabled some kind of forwarding, because
+/// the tunnel port is otherwise absent from the service's port map.
+#[cfg(feature = "server")]
+pub(crate) async fn handle_tunnel_connection<S>(
+    mut stream: S,
+    policy: TunnelPolicy,
+    service: Arc<str>,
+    circuit: u64,
+    port: u16,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let request = match read_line(&mut stream).await {
+        Ok(line) => line,
+        Err(e) => {
+            debug!("Bad tunnel request: {e}");
+            return;
+        }
+    };
+
+    let (verb, arg) = request.split_once(' ').unwrap_or((request.as_str(), ""));
+    match verb {
+        "CONNECT" if !policy.egress.is_empty() => {
+            serve_connect(stream, arg, &policy.egress, &service, circuit, port).await;
+        }
+        "LISTEN" if policy.allow_listen => {
+            serve_listen(stream, arg, &service, circuit, port).await;
+        }
+        "BIND" if policy.allow_listen => {
+            serve_bind(stream, arg, &service, circuit, port).await;
+        }
+        "CONNECT" | "LISTEN" | "BIND" => {
+            debug!("Refusing tunnel request {verb}: not allowed by policy");
+            let _ = write_line(&mut stream, "ERR not allowed by server policy").await;
+        }
+        _ => {
+            let _ = write_line(&mut stream, "ERR unknown request").await;
+        }
+    }
+}
+
+/// Connects to `target` if the egress policy allows any of the addresses it
+/// resolves to, then splices the stream to it.
+#[cfg(feature = "server")]
+async fn serve_connect<S>(
+    mut stream: S,
+    target: &str,
+    egress: &[EgressRule],
+    service: &str,
+    circuit: u64,
+    port: u16,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let addrs: Vec<SocketAddr> = match tokio::net::lookup_host(target).await {
+        Ok(addrs) => addrs
+            .filter(|addr| egress::is_allowed(egress, addr))
+            .collect(),
+        Err(e) => {
+            debug!("Tunnel connect: cannot resolve {target}: {e}");
+            let _ = write_line(&mut stream, &format!("ERR {e}")).await;
+            return;
+        }
+    };
+    if addrs.is_empty() {
+        debug!("Refusing tunnel connect to {target}: not allowed by egress policy");
+        audit::record(AuditEvent::StreamRejected {
+            service,
+            circuit,
+            port: Some(port),
+            reason: &format!("egress to {target} not allowed"),
+        });
+        let _ = write_line(&mut stream, "ERR destination not allowed by server policy").await;
+        return;
+    }
+
+    match TcpStream::connect(&addrs[..]).await {
+        Ok(local) => {
+            if write_line(&mut stream, "OK").await.is_err() {
+                return;
+            }
+            crate::forward::splice(stream, local, service, circuit, port, target).await;
+        }
+        Err(e) => {
+            error!("Tunnel connect to {target} failed: {e}");
+            let _ = write_line(&mut stream, &format!("ERR {e}")).await;
+        }
+    }
+}
+
+/// Listens on a server loopback port for a remote forward and announces
+/// every connection on `control`, until the client closes it or the session
+/// is ended.
+#[cfg(feature = "server")]
+async fn serve_listen<S>(mut control: S, remote_port: &str, service: &str, circuit: u64, port: u16)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Port 0 would listen on a port the client never learns.
+    let Some(remote_port) = remote_port.parse::<u16>().ok().filter(|&p| p != 0) else {
+        let _ = write_line(&mut control, "ERR invalid port").await;
+        return;
+    };
+    let listener = match tokio::net::TcpListener::bind(("127.0.0.1", remote_port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Remote forward: cannot listen on 127.0.0.1:{remote_port}: {e}");
+            let _ = write_line(&mut control, &format!("ERR {e}")).await;
+            return;
+        }
+    };
+    if write_line(&mut control, "OK").await.is_err() {
+        return;
+    }
+    debug!("Remote forward listening on 127.0.0.1:{remote_port}");
+
+    // A session of its own, so it can be listed, killed and drained; the
+    // listener goes with it.
+    let address = format!("127.0.0.1:{remote_port}");
+    let handle = SESSIONS.register(service, circuit, port, "listen", &address);
+    let session = handle.id();
+    let started = Instant::now();
+    audit::record(AuditEvent::ListenStart {
+        service,
+        circuit,
+        session,
+        port,
+        address: &address,
+    });
+    let label = format!("remote forward {address}");
+    let (mut control_read, mut control_write) = tokio::io::split(control);
+    let mut scratch = [0u8; 64];
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (local, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Remote forward on port {remote_port}: accept failed: {e}");
+                        continue;
+                    }
+                };
+                debug!("Remote forward on port {remote_port}: connection from {peer}");
+                audit::record(AuditEvent::ListenAccept {
+                    service,
+                    circuit,
+                    session,
+                    peer: &peer.to_string(),
+                });
+
+                let token = format!("{:032x}", rand::random::<u128>());
+                PENDING_BINDS
+                    .lock()
+                    .unwrap()
+                    .insert(token.clone(), (local, label.clone()));
+                if write_line(&mut control_write, &format!("ACCEPT {token}")).await.is_err() {
+                    PENDING_BINDS.lock().unwrap().remove(&token);
+                    break;
+                }
+
+                // Drop the connection if the client never claims it.
+                tokio::spawn(async move {
+                    tokio::time::sleep(BIND_TIMEOUT).await;
+                    if PENDING_BINDS.lock().unwrap().remove(&token).is_some() {
+                        debug!("Remote forward connection {token} was never bound");
+                    }
+                });
+            }
+            // The client sends nothing after LISTEN; any read completing means
+            // the control stream is gone.
+            _ = control_read.read(&mut scratch) => break,
+            () = handle.cancelled() => {
+                debug!("Remote forward session {session} killed");
+                break;
+            }
+            // Connections already forwarded are sessions of their own and
+            // get the grace period; new ones are not taken.
+            _ = handle.draining() => break,
+        }
+    }
+    audit::record(AuditEvent::SessionEnd {
+        service,
+        circuit,
+        session,
+        duration_secs: started.elapsed().as_secs_f64(),
+        bytes_in: 0,
+        bytes_out: 0,
+        exit_code: None,
+        signal: None,
+    });
+    debug!("Remote forward on port {remote_port} closed");
+}
+
+#[cfg(feature = "server")]
+async fn serve_bind<S>(mut stream: S, token: &str, service: &str, circuit: u64, port: u16)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let pending = PENDING_BINDS.lock().unwrap().remove(token);
+    let Some((local, label)) = pending else {
+        let _ = write_line(&mut stream, "ERR unknown token").await;
+        return;
+    };
+    if write_line(&mut stream, "OK").await.is_err() {
+        return;
+    }
+    crate::forward::splice(stream, local, service, circuit, port, &label).await;
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_specs_on_colons() {
+        assert_eq!(
+            split_spec("8080:example.com:80"),
+            ["8080", "example.com", "80"]
+        );
+        assert_eq!(split_spec("1080"), ["1080"]);
+        assert_eq!(split_spec("a::b"), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn keeps_bracketed_ipv6_addresses_together() {
+        assert_eq!(
+            split_spec("[::1]:8080:[fd00::2]:80"),
+            ["::1", "8080", "fd00::2", "80"]
+        );
+        assert_eq!(split_spec("8080:[::1]"), ["8080", "::1"]);
+        assert_eq!(split_spec("[::1]x"), ["::1", "x"]);
+    }
+
+    #[test]
+    fn parses_local_forwards() {
+        let forward: LocalForward = "8080:example.com:80".parse().unwrap();
+        assert_eq!(
+            forward,
+            LocalForward {
+                bind_address: "127.0.0.1".into(),
+                port: 8080,
+                host: "example.com".into(),
+                host_port: 80,
+            }
+        );
+        assert_eq!(forward.to_string(), "127.0.0.1:8080 -> example.com:80");
+
+        let forward: LocalForward = "[::1]:8080:[fd00::2]:80".parse().unwrap();
+        assert_eq!(forward.listen_addr(), "[::1]:8080");
+        assert_eq!(forward.target(), "[fd00::2]:80");
+    }
+
+    #[test]
+    fn rejects_malformed_local_forwards() {
+        for s in [
+            "8080",
+            "8080:host",
+            "8080::80",
+            "x:host:80",
+            "8080:host:70000",
+            "a:b:c:d:e",
+        ] {
+            assert!(s.parse::<LocalForward>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn parses_remote_forwards() {
+        let forward: RemoteForward = "9000:[::1]:22".parse().unwrap();
+        assert_eq!(
+            forward,
+            RemoteForward {
+                remote_port: 9000,
+                host: "::1".into(),
+                host_port: 22,
+            }
+        );
+        assert_eq!(forward.to_string(), "remote port 9000 -> [::1]:22");
+    }
+
+    #[test]
+    fn rejects_malformed_remote_forwards() {
+        for s in [
+            "9000:host",
+            "9000::22",
+            "0:host:22",
+            "127.0.0.1:9000:host:22",
+        ] {
+            assert!(s.parse::<RemoteForward>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn parses_dynamic_forwards() {
+        let forward: DynamicForward = "1080".parse().unwrap();
+        assert_eq!(forward.listen_addr(), "127.0.0.1:1080");
+        let forward: DynamicForward = "[::]:1080".parse().unwrap();
+        assert_eq!(forward.to_string(), "SOCKS5 proxy on [::]:1080");
+        for s in ["", "x", "1080:1081:1082", "0.0.0.0:65536"] {
+            assert!(s.parse::<DynamicForward>().is_err(), "{s:?} parsed");
+        }
+    }
+}
@@ -1,6 +1,30 @@
+#[cfg(feature = "server")]
 use sha3::{Digest, Sha3_256};
+#[cfg(feature = "server")]
 use tor_llcrypto::pk::ed25519::ExpandedKeypair;
 
+/// The virtual port the shell service listens on unless configured otherwise
+/// (telnet-like).
+pub(crate) const DEFAULT_SHELL_PORT: u16 = 23;
+
+/// Splits an `address[:port]` argument into host and port, using
+/// `default_port` when no port is given.
+///
+/// Onion addresses never contain a colon, so the last `:` separates the port.
+#[cfg(feature = "client")]
+pub(crate) fn split_host_port(address: &str, default_port: u16) -> anyhow::Result<(&str, u16)> {
+    match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid port {port:?} in {address:?}"))?;
+            Ok((host, port))
+        }
+        None => Ok((address, default_port)),
+    }
+}
+
+#[cfg(feature = "server")]
 pub(crate) fn keypair_from_sk(secret_key: [u8; 32]) -> ExpandedKeypair {
     let sk = secret_key as ed25519_dalek::SecretKey;
     let esk = ed25519_dalek::hazmat::ExpandedSecretKey::from(&sk);
@@ -10,6 +34,7 @@ pub(crate) fn keypair_from_sk(secret_key: [u8; 32]) -> ExpandedKeypair {
     ExpandedKeypair::from_secret_key_bytes(bytes).expect("error converting to ExpandedKeypair")
 }
 
+#[cfg(feature = "server")]
 #[must_use]
 pub fn get_onion_address(public_key: &[u8]) -> String {
     let pub_key = <[u8; 32]>::try_from(public_key).expect("could not convert to [u8; 32]");
@@ -29,4 +54,22 @@ pub fn get_onion_address(public_key: &[u8]) -> String {
     buf[34] = 3;
 
     base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &buf).to_ascii_lowercase()
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_host_and_port() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        assert_eq!(split_host_port(onion, 23).unwrap(), (onion, 23));
+        assert_eq!(
+            split_host_port(&format!("{onion}:2222"), 23).unwrap(),
+            (onion, 2222)
+        );
+        for bad in ["host:", "host:ssh", "host:65536", "host:-1"] {
+            assert!(split_host_port(bad, 23).is_err(), "{bad:?} accepted");
+        }
+    }
+}